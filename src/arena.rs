@@ -0,0 +1,271 @@
+// 用数组存放的语法树: 节点之间用 ExprId 引用, 不再每个节点一次 Box 分配.
+//
+// 子节点总是先于父节点分配, 所以 id 从小到大就是一个自底向上的顺序, 遍历 ids() 即可
+// 完成需要先算子节点的分析. 类型, 位置等附加信息放在以 id 为下标的 SideTable 里.
+use std::ops::Index;
+
+use crate::ast::{BinaryOp, Expr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ExprId(u32);
+
+impl ExprId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+// 和 Expr 一一对应, 只是子节点换成了 id
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Number(f64),
+    Str(String),
+    Var(String),
+    Bool(bool),
+    UnaryOp { op: BinaryOp, expr: ExprId },
+    BinaryOp { left: ExprId, op: BinaryOp, right: ExprId },
+    List(Vec<ExprId>),
+    Compare { first: ExprId, rest: Vec<(BinaryOp, ExprId)> },
+    Call { name: String, args: Vec<ExprId> },
+    Let { name: String, value: ExprId, body: ExprId },
+    Error,
+}
+
+impl Node {
+    // 直接子节点, 按求值顺序
+    pub fn children(&self) -> Vec<ExprId> {
+        let mut children = Vec::new();
+        self.for_each_child(|child| children.push(child));
+        children
+    }
+
+    // 和 children() 顺序相同, 但不分配
+    pub fn for_each_child(&self, mut f: impl FnMut(ExprId)) {
+        match self {
+            Node::Number(_) | Node::Str(_) | Node::Var(_) | Node::Bool(_) | Node::Error => {}
+            Node::UnaryOp { expr, .. } => f(*expr),
+            Node::BinaryOp { left, right, .. } => {
+                f(*left);
+                f(*right);
+            }
+            Node::List(items) | Node::Call { args: items, .. } => items.iter().copied().for_each(f),
+            Node::Compare { first, rest } => {
+                f(*first);
+                rest.iter().for_each(|(_, e)| f(*e));
+            }
+            Node::Let { value, body, .. } => {
+                f(*value);
+                f(*body);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Arena {
+    nodes: Vec<Node>,
+}
+
+impl Arena {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // 把 Expr 整棵树放进来, 返回根节点
+    pub fn from_expr(expr: &Expr) -> (Self, ExprId) {
+        let mut arena = Self::new();
+        let root = arena.insert(expr);
+        (arena, root)
+    }
+
+    // 子节点必须已经在这个 arena 里
+    pub fn alloc(&mut self, node: Node) -> ExprId {
+        let len = self.nodes.len();
+        node.for_each_child(|child| assert!(child.index() < len, "子节点 {child:?} 不在这个 arena 里"));
+        let id = ExprId(u32::try_from(self.nodes.len()).expect("节点太多"));
+        self.nodes.push(node);
+        id
+    }
+
+    pub fn insert(&mut self, expr: &Expr) -> ExprId {
+        let node = match expr {
+            Expr::Number(n) => Node::Number(*n),
+            Expr::Str(s) => Node::Str(s.clone()),
+            Expr::Var(name) => Node::Var(name.clone()),
+            Expr::Bool(b) => Node::Bool(*b),
+            Expr::UnaryOp { op, expr } => Node::UnaryOp { op: *op, expr: self.insert(expr) },
+            Expr::BinaryOp { left, op, right } => {
+                let left = self.insert(left);
+                Node::BinaryOp { left, op: *op, right: self.insert(right) }
+            }
+            Expr::List(items) => Node::List(items.iter().map(|item| self.insert(item)).collect()),
+            Expr::Compare { first, rest } => {
+                let first = self.insert(first);
+                Node::Compare { first, rest: rest.iter().map(|(op, e)| (*op, self.insert(e))).collect() }
+            }
+            Expr::Call { name, args } => Node::Call { name: name.clone(), args: args.iter().map(|a| self.insert(a)).collect() },
+            Expr::Let { name, value, body } => {
+                let value = self.insert(value);
+                Node::Let { name: name.clone(), value, body: self.insert(body) }
+            }
+            Expr::Error => Node::Error,
+        };
+        self.alloc(node)
+    }
+
+    // 还原成 Box 形式的语法树
+    pub fn to_expr(&self, id: ExprId) -> Expr {
+        let boxed = |id: ExprId| Box::new(self.to_expr(id));
+        match &self[id] {
+            Node::Number(n) => Expr::Number(*n),
+            Node::Str(s) => Expr::Str(s.clone()),
+            Node::Var(name) => Expr::Var(name.clone()),
+            Node::Bool(b) => Expr::Bool(*b),
+            Node::UnaryOp { op, expr } => Expr::UnaryOp { op: *op, expr: boxed(*expr) },
+            Node::BinaryOp { left, op, right } => Expr::BinaryOp { left: boxed(*left), op: *op, right: boxed(*right) },
+            Node::List(items) => Expr::List(items.iter().map(|id| self.to_expr(*id)).collect()),
+            Node::Compare { first, rest } => Expr::Compare {
+                first: boxed(*first),
+                rest: rest.iter().map(|(op, id)| (*op, self.to_expr(*id))).collect(),
+            },
+            Node::Call { name, args } => Expr::Call { name: name.clone(), args: args.iter().map(|id| self.to_expr(*id)).collect() },
+            Node::Let { name, value, body } => Expr::Let { name: name.clone(), value: boxed(*value), body: boxed(*body) },
+            Node::Error => Expr::Error,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // 所有节点, 子节点在前
+    pub fn ids(&self) -> impl Iterator<Item = ExprId> + use<> {
+        (0..self.nodes.len() as u32).map(ExprId)
+    }
+}
+
+impl Index<ExprId> for Arena {
+    type Output = Node;
+
+    fn index(&self, id: ExprId) -> &Node {
+        &self.nodes[id.index()]
+    }
+}
+
+// 按 id 存放的附加信息, 没有设置的节点为 None
+#[derive(Debug, Clone)]
+pub struct SideTable<T> {
+    values: Vec<Option<T>>,
+}
+
+impl<T> Default for SideTable<T> {
+    fn default() -> Self {
+        Self { values: Vec::new() }
+    }
+}
+
+impl<T> SideTable<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, id: ExprId, value: T) -> Option<T> {
+        if id.index() >= self.values.len() {
+            self.values.resize_with(id.index() + 1, || None);
+        }
+        self.values[id.index()].replace(value)
+    }
+
+    pub fn get(&self, id: ExprId) -> Option<&T> {
+        self.values.get(id.index()).and_then(Option::as_ref)
+    }
+
+    pub fn remove(&mut self, id: ExprId) -> Option<T> {
+        self.values.get_mut(id.index()).and_then(Option::take)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Rng(0xa7e4a);
+        for _ in 0..500 {
+            let expr = random_expr(&mut rng, 5);
+            let (arena, root) = Arena::from_expr(&expr);
+            assert_eq!(arena.to_expr(root), expr);
+        }
+        let expr = Expr::Let { name: "$0".to_string(), value: Box::new(parse("a + 1")), body: Box::new(Expr::Var("$0".to_string())) };
+        let (arena, root) = Arena::from_expr(&expr);
+        assert_eq!(arena.to_expr(root), expr);
+    }
+
+    #[test]
+    fn test_children_before_parents() {
+        let (arena, root) = Arena::from_expr(&parse("f(x, 1) * -y < 2 <= [z]"));
+        assert_eq!(arena.len(), 10);
+        assert_eq!(root, arena.ids().last().unwrap());
+        for id in arena.ids() {
+            assert!(arena[id].children().iter().all(|child| *child < id));
+        }
+        let Node::Compare { first, .. } = &arena[root] else { panic!("{:?}", arena[root]) };
+        assert_eq!(arena.to_expr(*first).to_string(), "(f(x, 1) * (-y))");
+    }
+
+    #[test]
+    fn test_side_table() {
+        // 自底向上算出每个节点的深度和可以直接算出的数值
+        let (arena, root) = Arena::from_expr(&parse("(1 + 2) * 3 - x"));
+        let mut depth = SideTable::new();
+        let mut constant = SideTable::new();
+        for id in arena.ids() {
+            let children = arena[id].children();
+            depth.insert(id, 1 + children.iter().map(|c| depth.get(*c).copied().unwrap_or(0)).max().unwrap_or(0));
+            let value = match &arena[id] {
+                Node::Number(n) => Some(*n),
+                Node::BinaryOp { left, op, right } => match (constant.get(*left), constant.get(*right)) {
+                    (Some(a), Some(b)) => match op {
+                        BinaryOp::Add => Some(a + b),
+                        BinaryOp::Mul => Some(a * b),
+                        _ => None,
+                    },
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(value) = value {
+                constant.insert(id, value);
+            }
+        }
+        assert_eq!(depth.get(root), Some(&4));
+        assert_eq!(constant.get(root), None);
+        let Node::BinaryOp { left, .. } = &arena[root] else { unreachable!() };
+        assert_eq!(constant.get(*left), Some(&9.0));
+        assert_eq!(constant.remove(*left), Some(9.0));
+        assert_eq!(constant.get(*left), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "不在这个 arena 里")]
+    fn test_alloc_checks_children() {
+        let mut arena = Arena::new();
+        let x = arena.alloc(Node::Var("x".to_string()));
+        arena.alloc(Node::UnaryOp { op: BinaryOp::Sub, expr: ExprId(x.0 + 1) });
+    }
+}
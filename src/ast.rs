@@ -5,31 +5,126 @@ use std::convert::TryFrom;
 
 use crate::lexer::Token;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Expr {
     Number(f64),
-    // Bool(bool),
+    Str(String),
+    Var(String),
+    Bool(bool),
     UnaryOp { op: BinaryOp, expr: Box<Expr> },
-    BinaryOp { left: Box<Expr>, op: BinaryOp, right: Box<Expr> }
+    BinaryOp { left: Box<Expr>, op: BinaryOp, right: Box<Expr> },
+    List(Vec<Expr>), // [a, b, c]
+    // 连续比较 a < b <= c, 等价于 a < b && b <= c, 但 b 只求值一次
+    Compare { first: Box<Expr>, rest: Vec<(BinaryOp, Expr)> },
+    Call { name: String, args: Vec<Expr> },
+    // 优化时生成的内部绑定, 先求 value 再在 body 中以 name 引用; 语法上不能直接写
+    Let { name: String, value: Box<Expr>, body: Box<Expr> },
+    // 出错恢复时代替解析失败的部分, 不能求值
+    Error,
 }
 
 impl fmt::Display for Expr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Expr::Number(n) => write!(f, "{}", n),
-            // Expr::Bool(b)=> write!(f, "{b}"),
+            Expr::Str(s) => write!(f, "{:?}", s),
+            Expr::Var(name) => write!(f, "{}", name),
+            Expr::Bool(b)=> write!(f, "{b}"),
             Expr::UnaryOp { op, expr } => write!(f, "({}{})", op, expr),
             Expr::BinaryOp { left, op, right } => write!(f, "({} {} {})", left, op, right),
+            Expr::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Expr::Compare { first, rest } => {
+                write!(f, "({}", first)?;
+                for (op, expr) in rest {
+                    write!(f, " {} {}", op, expr)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Call { name, args } => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            }
+            Expr::Let { name, value, body } => write!(f, "(let {} = {} in {})", name, value, body),
+            Expr::Error => write!(f, "<错误>"),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+impl Expr {
+    // 优先级, 越大结合越紧; 用于按需加括号的输出
+    pub fn precedence(&self) -> u8 {
+        match self {
+            Expr::Number(n) if n.is_sign_negative() => UNARY_PRECEDENCE,
+            Expr::UnaryOp { .. } => UNARY_PRECEDENCE,
+            Expr::BinaryOp { op, .. } => op.precedence(),
+            Expr::Compare { rest, .. } => rest[0].0.precedence(),
+            _ => ATOM_PRECEDENCE,
+        }
+    }
+
+    // 把变量 name 替换成 value
+    pub fn substitute(&self, name: &str, value: &Expr) -> Expr {
+        Substitute { name, value }.fold_expr(self.clone())
+    }
+
+    // 展开所有 let 绑定, 得到不含内部变量的表达式
+    pub fn inline_lets(&self) -> Expr {
+        InlineLets.fold_expr(self.clone())
+    }
+}
+
+struct Substitute<'a> {
+    name: &'a str,
+    value: &'a Expr,
+}
+
+impl Folder for Substitute<'_> {
+    fn fold_var(&mut self, name: String) -> Expr {
+        if name == self.name { self.value.clone() } else { Expr::Var(name) }
+    }
+
+    // 内层同名绑定会遮住外层
+    fn fold_let(&mut self, name: String, value: Expr, body: Expr) -> Expr {
+        let value = self.fold_expr(value);
+        let body = if name == self.name { body } else { self.fold_expr(body) };
+        Expr::Let { name, value: Box::new(value), body: Box::new(body) }
+    }
+}
+
+struct InlineLets;
+
+impl Folder for InlineLets {
+    fn fold_let(&mut self, name: String, value: Expr, body: Expr) -> Expr {
+        let value = self.fold_expr(value);
+        self.fold_expr(body).substitute(&name, &value)
+    }
+}
+
+pub const UNARY_PRECEDENCE: u8 = 7;
+pub const ATOM_PRECEDENCE: u8 = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum BinaryOp {
     Add,
     Sub,
     Mul,
     Div,
+    Pow,   // ^
     Eq,    // ==
     Neq,   // !=
     Gt,    // >
@@ -39,6 +134,10 @@ pub enum BinaryOp {
     And,   // &&
     Or,    // ||
     Not,   // !
+    Range,          // ..
+    RangeInclusive, // ..=
+    In,    // in
+    NotIn, // not in
 }
 
 impl fmt::Display for BinaryOp {
@@ -48,6 +147,7 @@ impl fmt::Display for BinaryOp {
             BinaryOp::Sub => "-",
             BinaryOp::Mul => "*",
             BinaryOp::Div => "/",
+            BinaryOp::Pow => "^",
             BinaryOp::Eq  => "==",
             BinaryOp::Neq => "!=",
             BinaryOp::Gt  => ">",
@@ -57,12 +157,41 @@ impl fmt::Display for BinaryOp {
             BinaryOp::And => "&&",
             BinaryOp::Or  => "||",
             BinaryOp::Not => "!",
+            BinaryOp::Range => "..",
+            BinaryOp::RangeInclusive => "..=",
+            BinaryOp::In  => "in",
+            BinaryOp::NotIn => "not in",
         };
         write!(f, "{}", symbol)
     }
 }
 
 
+impl BinaryOp {
+    // 所有运算符, 序列化时按符号或下标查找; 只能在末尾追加, 否则已经存下来的二进制数据会对不上
+    pub const ALL: [BinaryOp; 18] = [
+        BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Pow,
+        BinaryOp::Eq, BinaryOp::Neq, BinaryOp::Gt, BinaryOp::Gte, BinaryOp::Lt, BinaryOp::Lte,
+        BinaryOp::And, BinaryOp::Or, BinaryOp::Not,
+        BinaryOp::Range, BinaryOp::RangeInclusive, BinaryOp::In, BinaryOp::NotIn,
+    ];
+
+    // 和语法分析的层次一致: || < && < 比较 < 范围 < 加减 < 乘除 < 单目 < 乘方
+    pub fn precedence(&self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq | BinaryOp::Neq | BinaryOp::Gt | BinaryOp::Gte | BinaryOp::Lt | BinaryOp::Lte
+            | BinaryOp::In | BinaryOp::NotIn => 3,
+            BinaryOp::Range | BinaryOp::RangeInclusive => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div => 6,
+            BinaryOp::Not => UNARY_PRECEDENCE,
+            BinaryOp::Pow => 8,
+        }
+    }
+}
+
 impl TryFrom<&Token> for BinaryOp {
     type Error = anyhow::Error;
 
@@ -72,6 +201,7 @@ impl TryFrom<&Token> for BinaryOp {
             Token::Minus => Ok(BinaryOp::Sub),
             Token::Star => Ok(BinaryOp::Mul),
             Token::Slash => Ok(BinaryOp::Div),
+            Token::Caret => Ok(BinaryOp::Pow),
             Token::Equal => Ok(BinaryOp::Eq),
             Token::NotEqual => Ok(BinaryOp::Neq),
             Token::Greater => Ok(BinaryOp::Gt),
@@ -81,8 +211,250 @@ impl TryFrom<&Token> for BinaryOp {
             Token::And => Ok(BinaryOp::And),
             Token::Or => Ok(BinaryOp::Or),
             Token::Not => Ok(BinaryOp::Not),
+            Token::DotDot => Ok(BinaryOp::Range),
+            Token::DotDotEq => Ok(BinaryOp::RangeInclusive),
+            Token::In => Ok(BinaryOp::In),
+            Token::NotIn => Ok(BinaryOp::NotIn),
             // Token::Number(n) => anyhow::bail!("错误的符号: {n}"),
-            _ => Err(anyhow::bail!("未匹配的token")),
+            _ => anyhow::bail!("未匹配的token"),
+        }
+    }
+}
+// 只读遍历. 每种节点对应一个 visit 方法, 默认实现继续访问子节点, 只需重写关心的节点:
+//
+//   struct Vars(Vec<String>);
+//   impl Visitor<'_> for Vars {
+//       fn visit_var(&mut self, name: &str) { self.0.push(name.to_string()) }
+//   }
+//
+// 重写 visit_expr 可以在进入每个节点时做事, 再调用 walk_expr 继续默认的遍历
+pub trait Visitor<'a> {
+    fn visit_expr(&mut self, expr: &'a Expr) {
+        walk_expr(self, expr)
+    }
+
+    fn visit_number(&mut self, _value: f64) {}
+
+    fn visit_str(&mut self, _value: &'a str) {}
+
+    fn visit_var(&mut self, _name: &'a str) {}
+
+    fn visit_bool(&mut self, _value: bool) {}
+
+    fn visit_unary(&mut self, _op: BinaryOp, expr: &'a Expr) {
+        self.visit_expr(expr);
+    }
+
+    fn visit_binary(&mut self, left: &'a Expr, _op: BinaryOp, right: &'a Expr) {
+        self.visit_expr(left);
+        self.visit_expr(right);
+    }
+
+    fn visit_list(&mut self, items: &'a [Expr]) {
+        for item in items {
+            self.visit_expr(item);
+        }
+    }
+
+    fn visit_compare(&mut self, first: &'a Expr, rest: &'a [(BinaryOp, Expr)]) {
+        self.visit_expr(first);
+        for (_, expr) in rest {
+            self.visit_expr(expr);
+        }
+    }
+
+    fn visit_call(&mut self, _name: &'a str, args: &'a [Expr]) {
+        for arg in args {
+            self.visit_expr(arg);
         }
     }
-}
\ No newline at end of file
+
+    fn visit_let(&mut self, _name: &'a str, value: &'a Expr, body: &'a Expr) {
+        self.visit_expr(value);
+        self.visit_expr(body);
+    }
+
+    fn visit_error(&mut self) {}
+}
+
+// 按节点类型分派到对应的 visit 方法
+pub fn walk_expr<'a, V: Visitor<'a> + ?Sized>(visitor: &mut V, expr: &'a Expr) {
+    match expr {
+        Expr::Number(n) => visitor.visit_number(*n),
+        Expr::Str(s) => visitor.visit_str(s),
+        Expr::Var(name) => visitor.visit_var(name),
+        Expr::Bool(b) => visitor.visit_bool(*b),
+        Expr::UnaryOp { op, expr } => visitor.visit_unary(*op, expr),
+        Expr::BinaryOp { left, op, right } => visitor.visit_binary(left, *op, right),
+        Expr::List(items) => visitor.visit_list(items),
+        Expr::Compare { first, rest } => visitor.visit_compare(first, rest),
+        Expr::Call { name, args } => visitor.visit_call(name, args),
+        Expr::Let { name, value, body } => visitor.visit_let(name, value, body),
+        Expr::Error => visitor.visit_error(),
+    }
+}
+
+// 变换: 拿走旧树, 返回新树. 默认实现先变换子节点再原样重建, 子节点按求值顺序处理
+pub trait Folder {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        walk_fold(self, expr)
+    }
+
+    fn fold_number(&mut self, value: f64) -> Expr {
+        Expr::Number(value)
+    }
+
+    fn fold_str(&mut self, value: String) -> Expr {
+        Expr::Str(value)
+    }
+
+    fn fold_var(&mut self, name: String) -> Expr {
+        Expr::Var(name)
+    }
+
+    fn fold_bool(&mut self, value: bool) -> Expr {
+        Expr::Bool(value)
+    }
+
+    fn fold_unary(&mut self, op: BinaryOp, expr: Expr) -> Expr {
+        Expr::UnaryOp { op, expr: Box::new(self.fold_expr(expr)) }
+    }
+
+    fn fold_binary(&mut self, left: Expr, op: BinaryOp, right: Expr) -> Expr {
+        let left = self.fold_expr(left);
+        Expr::BinaryOp { left: Box::new(left), op, right: Box::new(self.fold_expr(right)) }
+    }
+
+    fn fold_list(&mut self, items: Vec<Expr>) -> Expr {
+        Expr::List(items.into_iter().map(|item| self.fold_expr(item)).collect())
+    }
+
+    fn fold_compare(&mut self, first: Expr, rest: Vec<(BinaryOp, Expr)>) -> Expr {
+        let first = self.fold_expr(first);
+        Expr::Compare {
+            first: Box::new(first),
+            rest: rest.into_iter().map(|(op, expr)| (op, self.fold_expr(expr))).collect(),
+        }
+    }
+
+    fn fold_call(&mut self, name: String, args: Vec<Expr>) -> Expr {
+        Expr::Call { name, args: args.into_iter().map(|arg| self.fold_expr(arg)).collect() }
+    }
+
+    fn fold_let(&mut self, name: String, value: Expr, body: Expr) -> Expr {
+        let value = self.fold_expr(value);
+        Expr::Let { name, value: Box::new(value), body: Box::new(self.fold_expr(body)) }
+    }
+
+    fn fold_error(&mut self) -> Expr {
+        Expr::Error
+    }
+}
+
+// 按节点类型分派到对应的 fold 方法
+pub fn walk_fold<F: Folder + ?Sized>(folder: &mut F, expr: Expr) -> Expr {
+    match expr {
+        Expr::Number(n) => folder.fold_number(n),
+        Expr::Str(s) => folder.fold_str(s),
+        Expr::Var(name) => folder.fold_var(name),
+        Expr::Bool(b) => folder.fold_bool(b),
+        Expr::UnaryOp { op, expr } => folder.fold_unary(op, *expr),
+        Expr::BinaryOp { left, op, right } => folder.fold_binary(*left, op, *right),
+        Expr::List(items) => folder.fold_list(items),
+        Expr::Compare { first, rest } => folder.fold_compare(*first, rest),
+        Expr::Call { name, args } => folder.fold_call(name, args),
+        Expr::Let { name, value, body } => folder.fold_let(name, *value, *body),
+        Expr::Error => folder.fold_error(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[derive(Default)]
+    struct Vars<'a>(Vec<&'a str>);
+
+    impl<'a> Visitor<'a> for Vars<'a> {
+        fn visit_var(&mut self, name: &'a str) {
+            if !self.0.contains(&name) {
+                self.0.push(name);
+            }
+        }
+    }
+
+    struct Rename;
+
+    impl Folder for Rename {
+        fn fold_var(&mut self, name: String) -> Expr {
+            Expr::Var(name.to_uppercase())
+        }
+
+        fn fold_call(&mut self, name: String, args: Vec<Expr>) -> Expr {
+            Expr::Call { name: format!("my_{name}"), args: args.into_iter().map(|a| self.fold_expr(a)).collect() }
+        }
+    }
+
+    // 只折叠两边都是数字的加法和乘法
+    struct FoldConstants;
+
+    impl Folder for FoldConstants {
+        fn fold_binary(&mut self, left: Expr, op: BinaryOp, right: Expr) -> Expr {
+            match (self.fold_expr(left), op, self.fold_expr(right)) {
+                (Expr::Number(a), BinaryOp::Add, Expr::Number(b)) => Expr::Number(a + b),
+                (Expr::Number(a), BinaryOp::Mul, Expr::Number(b)) => Expr::Number(a * b),
+                (left, op, right) => Expr::BinaryOp { left: Box::new(left), op, right: Box::new(right) },
+            }
+        }
+    }
+
+    #[test]
+    fn test_visitor_collects_variables() {
+        let expr = parse("x + max(y, [z, x]) > 0 < y && !(w in 1..n)");
+        let mut vars = Vars::default();
+        vars.visit_expr(&expr);
+        assert_eq!(vars.0, ["x", "y", "z", "w", "n"]);
+    }
+
+    #[test]
+    fn test_visitor_enter_every_node() {
+        struct Count(usize);
+        impl Visitor<'_> for Count {
+            fn visit_expr(&mut self, expr: &Expr) {
+                self.0 += 1;
+                walk_expr(self, expr);
+            }
+        }
+        let mut count = Count(0);
+        count.visit_expr(&parse("-a * f(1, \"s\") <= [true]"));
+        // <=, *, -a, a, f, 1, "s", [..], true
+        assert_eq!(count.0, 9);
+    }
+
+    #[test]
+    fn test_folder_rename_and_fold() {
+        let expr = parse("abs(x) + y * 2 in [a, 1 + 2 * 3]");
+        assert_eq!(Rename.fold_expr(expr.clone()).to_string(), "((my_abs(X) + (Y * 2)) in [A, (1 + (2 * 3))])");
+        assert_eq!(FoldConstants.fold_expr(expr).to_string(), "((abs(x) + (y * 2)) in [a, 7])");
+    }
+
+    #[test]
+    fn test_substitute_and_inline_lets() {
+        let value = parse("a + 1");
+        assert_eq!(parse("x * x - y").substitute("x", &value).to_string(), "(((a + 1) * (a + 1)) - y)");
+        // 内层同名的 let 遮住外层
+        let shadowed = Expr::Let {
+            name: "x".to_string(),
+            value: Box::new(parse("x + 1")),
+            body: Box::new(parse("x * 2")),
+        };
+        assert_eq!(shadowed.substitute("x", &Expr::Number(5.0)).to_string(), "(let x = (5 + 1) in (x * 2))");
+        assert_eq!(shadowed.inline_lets().to_string(), "((x + 1) * 2)");
+    }
+}
@@ -0,0 +1,492 @@
+// 按列批量求值: 一次对整列数据计算每个语法树节点, 而不是逐行调用 eval
+//
+// 每个节点的结果是一整列, 加上一个有效位掩码. 某一行求值出错 (除以零, 类型不对, 未定义的变量)
+// 时这一行为 null, 其他行不受影响; 结果和逐行调用 eval_with 完全一致, 出错的行对应 null.
+// 不依赖列数据的子表达式只计算一次. && 和 || 的右边, 以及连续比较后面的操作数, 只在没有被短路的
+// 行上计算, 所以不纯的函数和逐行求值时一样, 不会在被短路的行上被调用.
+use std::{borrow::Cow, cmp::Ordering, collections::HashMap};
+
+use crate::{
+    ast::{BinaryOp, Expr},
+    eval::{self, Context, EvalPolicy, Value},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Column {
+    Number(Vec<f64>),
+    Bool(Vec<bool>),
+    Values(Vec<Value>), // 类型不一致或非数字的结果
+}
+
+// 无效行 (valid[i] == false) 在 column 里的值没有意义
+#[derive(Debug, Clone, PartialEq)]
+pub struct Batch {
+    pub column: Column,
+    pub valid: Vec<bool>,
+}
+
+impl Batch {
+    pub fn len(&self) -> usize {
+        self.valid.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.valid.is_empty()
+    }
+
+    pub fn get(&self, row: usize) -> Option<Value> {
+        if !self.valid[row] {
+            return None;
+        }
+        Some(match &self.column {
+            Column::Number(v) => Value::Number(v[row]),
+            Column::Bool(v) => Value::Bool(v[row]),
+            Column::Values(v) => v[row].clone(),
+        })
+    }
+
+    pub fn to_values(&self) -> Vec<Option<Value>> {
+        (0..self.len()).map(|row| self.get(row)).collect()
+    }
+}
+
+pub fn eval_batch(expr: &Expr, columns: &HashMap<String, &[f64]>) -> anyhow::Result<Batch> {
+    eval_batch_with(expr, columns, &Context::default(), &EvalPolicy::default())
+}
+
+// ctx 提供列以外的标量变量和函数
+pub fn eval_batch_with(
+    expr: &Expr,
+    columns: &HashMap<String, &[f64]>,
+    ctx: &Context,
+    policy: &EvalPolicy,
+) -> anyhow::Result<Batch> {
+    let mut lens = columns.values().map(|c| c.len());
+    let rows = lens.next().unwrap_or(0);
+    if lens.any(|n| n != rows) {
+        anyhow::bail!("列的长度不一致");
+    }
+    let mut evaluator = BatchEval { columns, ctx, policy, rows, active: vec![true; rows], scope: Vec::new() };
+    let result = evaluator.eval(expr);
+    let column = match result.data {
+        Data::Number(v) => Column::Number(v.into_owned()),
+        Data::Bool(v) => Column::Bool(v),
+        Data::Values(v) => Column::Values(v),
+        Data::Scalar(Value::Number(n)) => Column::Number(vec![n; rows]),
+        Data::Scalar(Value::Bool(b)) => Column::Bool(vec![b; rows]),
+        Data::Scalar(v) => Column::Values(vec![v; rows]),
+    };
+    Ok(Batch { column, valid: result.valid })
+}
+
+#[derive(Clone)]
+enum Data<'a> {
+    Scalar(Value), // 所有行都相同
+    Number(Cow<'a, [f64]>), // 直接引用输入列时不复制
+    Bool(Vec<bool>),
+    Values(Vec<Value>),
+}
+
+#[derive(Clone)]
+struct Col<'a> {
+    data: Data<'a>,
+    valid: Vec<bool>,
+}
+
+// 按数字读取一列, bool 按 0/1 转换
+enum Numbers<'a> {
+    Scalar(f64),
+    Column(Cow<'a, [f64]>),
+}
+
+impl Numbers<'_> {
+    fn get(&self, row: usize) -> f64 {
+        match self {
+            Numbers::Scalar(n) => *n,
+            Numbers::Column(v) => v[row],
+        }
+    }
+}
+
+impl Col<'_> {
+    fn value(&self, row: usize) -> Value {
+        match &self.data {
+            Data::Scalar(v) => v.clone(),
+            Data::Number(v) => Value::Number(v[row]),
+            Data::Bool(v) => Value::Bool(v[row]),
+            Data::Values(v) => v[row].clone(),
+        }
+    }
+
+    fn numbers(&self) -> Option<Numbers<'_>> {
+        Some(match &self.data {
+            Data::Scalar(Value::Number(n)) => Numbers::Scalar(*n),
+            Data::Scalar(Value::Bool(b)) => Numbers::Scalar(*b as u8 as f64),
+            Data::Number(v) => Numbers::Column(Cow::Borrowed(v)),
+            Data::Bool(v) => Numbers::Column(Cow::Owned(v.iter().map(|b| *b as u8 as f64).collect())),
+            _ => return None,
+        })
+    }
+
+    fn is_scalar(&self) -> bool {
+        matches!(self.data, Data::Scalar(_))
+    }
+}
+
+struct BatchEval<'a> {
+    columns: &'a HashMap<String, &'a [f64]>,
+    ctx: &'a Context,
+    policy: &'a EvalPolicy,
+    rows: usize,
+    // 需要计算的行; 被 && || 或连续比较短路的行为 false, 逐行计算 (包括调用函数) 时跳过
+    active: Vec<bool>,
+    scope: Vec<(String, Col<'a>)>,
+}
+
+impl<'a> BatchEval<'a> {
+    fn scalar(&self, value: anyhow::Result<Value>) -> Col<'a> {
+        match value {
+            Ok(v) => Col { data: Data::Scalar(v), valid: vec![true; self.rows] },
+            Err(_) => self.null(),
+        }
+    }
+
+    // 所有行都出错; 不用标量表示, 避免走标量的快速路径
+    fn null(&self) -> Col<'a> {
+        Col { data: Data::Bool(vec![false; self.rows]), valid: vec![false; self.rows] }
+    }
+
+    // 逐行计算, 再尽量收窄成数字列或 bool 列; 输入都是标量时只算一次
+    fn per_row(&self, mut valid: Vec<bool>, scalar: bool, f: impl Fn(usize) -> anyhow::Result<Value>) -> Col<'a> {
+        if scalar {
+            return self.scalar(f(0));
+        }
+        let mut values = Vec::with_capacity(self.rows);
+        for (row, ok) in valid.iter_mut().enumerate() {
+            let value = if *ok && self.active[row] { f(row).ok() } else { None };
+            *ok = value.is_some();
+            values.push(value.unwrap_or(Value::Bool(false)));
+        }
+        let live = || values.iter().zip(&valid).filter(|(_, ok)| **ok).map(|(v, _)| v);
+        let data = if live().all(|v| matches!(v, Value::Number(_))) {
+            Data::Number(values.iter().map(|v| if let Value::Number(n) = v { *n } else { 0.0 }).collect::<Vec<_>>().into())
+        } else if live().all(|v| matches!(v, Value::Bool(_))) {
+            Data::Bool(values.iter().map(|v| matches!(v, Value::Bool(true))).collect())
+        } else {
+            Data::Values(values)
+        };
+        Col { data, valid }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Col<'a> {
+        match expr {
+            Expr::Number(n) => self.scalar(Ok(Value::Number(*n))),
+            Expr::Error => self.scalar(Err(anyhow::anyhow!("表达式有语法错误"))),
+            Expr::Str(s) => self.scalar(Ok(Value::Str(s.clone()))),
+            Expr::Bool(b) => self.scalar(Ok(Value::Bool(*b))),
+            Expr::Var(name) => {
+                if let Some((_, col)) = self.scope.iter().rev().find(|(s, _)| s == name) {
+                    return col.clone();
+                }
+                match self.columns.get(name) {
+                    Some(column) => Col { data: Data::Number(Cow::Borrowed(column)), valid: vec![true; self.rows] },
+                    None => self.scalar(self.ctx.lookup(name).cloned()),
+                }
+            }
+            Expr::List(items) => {
+                let items: Vec<Col> = items.iter().map(|e| self.eval(e)).collect();
+                let valid = and_masks(self.rows, items.iter().map(|c| &c.valid));
+                let scalar = items.iter().all(Col::is_scalar);
+                self.per_row(valid, scalar, |row| Ok(Value::List(items.iter().map(|c| c.value(row)).collect())))
+            }
+            Expr::UnaryOp { op, expr } => {
+                let col = self.eval(expr);
+                self.unary(*op, col)
+            }
+            Expr::BinaryOp { left, op: op @ (BinaryOp::And | BinaryOp::Or), right } => {
+                let left = self.eval(left);
+                self.logic(*op, left, right)
+            }
+            Expr::BinaryOp { left, op, right } => {
+                let (l, r) = (self.eval(left), self.eval(right));
+                self.binary(*op, &l, &r)
+            }
+            Expr::Compare { first, rest } => self.chain(first, rest),
+            Expr::Call { name, args } => {
+                let Ok(f) = self.ctx.functions().lookup(name) else { return self.null() };
+                let args: Vec<Col> = args.iter().map(|e| self.eval(e)).collect();
+                let valid = and_masks(self.rows, args.iter().map(|c| &c.valid));
+                // 不纯的函数每行都要调用一次
+                let scalar = args.iter().all(Col::is_scalar) && f.pure;
+                self.per_row(valid, scalar, |row| {
+                    let values: Vec<Value> = args.iter().map(|c| c.value(row)).collect();
+                    eval::call(f, &values, self.policy)
+                })
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.eval(value);
+                self.scope.push((name.clone(), value));
+                let result = self.eval(body);
+                self.scope.pop();
+                result
+            }
+        }
+    }
+
+    fn unary(&self, op: BinaryOp, col: Col<'a>) -> Col<'a> {
+        let data = match (&col.data, op) {
+            (Data::Scalar(v), _) => return self.scalar(eval::unary(&op, v.clone())),
+            (Data::Number(v), BinaryOp::Sub) => Data::Number(v.iter().map(|n| -n).collect()),
+            (Data::Number(v), BinaryOp::Not) => Data::Bool(v.iter().map(|n| *n == 0.0).collect()),
+            (Data::Bool(v), BinaryOp::Sub | BinaryOp::Not) => Data::Bool(v.iter().map(|b| !b).collect()),
+            (Data::Number(_) | Data::Bool(_), BinaryOp::Add) => return col,
+            _ => return self.per_row(col.valid.clone(), false, |row| eval::unary(&op, col.value(row))),
+        };
+        Col { data, valid: col.valid }
+    }
+
+    fn binary(&self, op: BinaryOp, l: &Col, r: &Col) -> Col<'a> {
+        if let (Data::Scalar(a), Data::Scalar(b)) = (&l.data, &r.data) {
+            return self.scalar(eval::binary(&op, a.clone(), b.clone(), self.policy));
+        }
+        let mut valid = and_masks(self.rows, [&l.valid, &r.valid]);
+        if let (Some(a), Some(b)) = (l.numbers(), r.numbers()) {
+            let float_eq = self.policy.float_eq;
+            let compare = |f: fn(Option<Ordering>) -> bool| {
+                let v = (0..self.rows).map(|i| f(compare_numbers(a.get(i), b.get(i), float_eq))).collect();
+                Data::Bool(v)
+            };
+            let data = match op {
+                BinaryOp::Eq => compare(|o| o == Some(Ordering::Equal)),
+                BinaryOp::Neq => compare(|o| o != Some(Ordering::Equal)),
+                BinaryOp::Lt => compare(|o| o == Some(Ordering::Less)),
+                BinaryOp::Lte => compare(|o| matches!(o, Some(Ordering::Less | Ordering::Equal))),
+                BinaryOp::Gt => compare(|o| o == Some(Ordering::Greater)),
+                BinaryOp::Gte => compare(|o| matches!(o, Some(Ordering::Greater | Ordering::Equal))),
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow => {
+                    Data::Number(self.arithmetic(op, &a, &b, &mut valid).into())
+                }
+                _ => return self.per_row(valid, false, |row| eval::binary(&op, l.value(row), r.value(row), self.policy)),
+            };
+            return Col { data, valid };
+        }
+        self.per_row(valid, false, |row| eval::binary(&op, l.value(row), r.value(row), self.policy))
+    }
+
+    fn arithmetic(&self, op: BinaryOp, a: &Numbers, b: &Numbers, valid: &mut [bool]) -> Vec<f64> {
+        let f: fn(f64, f64) -> f64 = match op {
+            BinaryOp::Add => |x, y| x + y,
+            BinaryOp::Sub => |x, y| x - y,
+            BinaryOp::Mul => |x, y| x * y,
+            BinaryOp::Div => |x, y| x / y,
+            _ => f64::powf,
+        };
+        let div_error = op == BinaryOp::Div && self.policy.div_by_zero == eval::DivByZero::Error;
+        let finite_error = self.policy.non_finite == eval::NonFinite::Error;
+        let mut out = Vec::with_capacity(self.rows);
+        for (row, ok) in valid.iter_mut().enumerate() {
+            let (x, y) = (a.get(row), b.get(row));
+            let n = f(x, y);
+            if (div_error && y == 0.0) || (finite_error && !n.is_finite()) {
+                *ok = false;
+            }
+            out.push(n);
+        }
+        out
+    }
+
+    fn logic(&mut self, op: BinaryOp, left: Col, right: &Expr) -> Col<'a> {
+        // 左边已经决定结果的行: && 左边为假, || 左边为真
+        let decided = op == BinaryOp::Or;
+        let mut valid = left.valid.clone();
+        let mut out = vec![decided; self.rows];
+        let mut pending = vec![false; self.rows];
+        for row in 0..self.rows {
+            if valid[row] && self.active[row] {
+                match left.value(row).truthy() {
+                    Ok(t) if t == decided => {}
+                    Ok(_) => pending[row] = true,
+                    Err(_) => valid[row] = false,
+                }
+            }
+        }
+        if pending.iter().any(|p| *p) {
+            let outer = std::mem::replace(&mut self.active, pending.clone());
+            let right = self.eval(right);
+            self.active = outer;
+            for row in (0..self.rows).filter(|row| pending[*row]) {
+                match right.valid[row].then(|| right.value(row).truthy()) {
+                    Some(Ok(t)) => out[row] = t,
+                    _ => valid[row] = false,
+                }
+            }
+        }
+        Col { data: Data::Bool(out), valid }
+    }
+
+    fn chain(&mut self, first: &Expr, rest: &[(BinaryOp, Expr)]) -> Col<'a> {
+        let mut l = self.eval(first);
+        let mut valid = l.valid.clone();
+        let mut out = vec![true; self.rows];
+        for (op, expr) in rest {
+            // 和求值器一样, 已经为假的行不再比较后面的操作数
+            let live: Vec<bool> = (0..self.rows).map(|row| valid[row] && out[row] && self.active[row]).collect();
+            if !live.contains(&true) {
+                break;
+            }
+            let outer = std::mem::replace(&mut self.active, live);
+            let r = self.eval(expr);
+            self.active = outer;
+            let step = self.binary(*op, &l, &r);
+            for row in 0..self.rows {
+                if !(valid[row] && out[row]) {
+                    continue;
+                }
+                match (step.valid[row], step.value(row)) {
+                    (true, Value::Bool(b)) => out[row] = b,
+                    _ => valid[row] = false,
+                }
+            }
+            l = r;
+        }
+        Col { data: Data::Bool(out), valid }
+    }
+}
+
+// 和 Value::compare 对数字的规则一致
+fn compare_numbers(a: f64, b: f64, float_eq: eval::FloatEq) -> Option<Ordering> {
+    if float_eq.approx_eq(a, b) {
+        return Some(Ordering::Equal);
+    }
+    a.partial_cmp(&b)
+}
+
+fn and_masks<'a>(rows: usize, masks: impl IntoIterator<Item = &'a Vec<bool>>) -> Vec<bool> {
+    let mut valid = vec![true; rows];
+    for mask in masks {
+        for (v, m) in valid.iter_mut().zip(mask) {
+            *v &= m;
+        }
+    }
+    valid
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::eval_with,
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_numeric_columns() {
+        let x = [1.0, 2.0, 3.0, 4.0];
+        let y = [10.0, 0.0, 30.0, 40.0];
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &y[..])]);
+        let batch = eval_batch(&parse("x * 2 + y / x"), &columns).unwrap();
+        assert_eq!(batch.column, Column::Number(vec![12.0, 4.0, 16.0, 18.0]));
+        assert_eq!(batch.valid, [true; 4]);
+
+        // 除以零的行为 null
+        let batch = eval_batch(&parse("x / y"), &columns).unwrap();
+        assert_eq!(batch.to_values(), [Some(Value::Number(0.1)), None, Some(Value::Number(0.1)), Some(Value::Number(0.1))]);
+
+        let batch = eval_batch(&parse("1 < x <= 3 && sqrt(y) > 0"), &columns).unwrap();
+        assert_eq!(batch.column, Column::Bool(vec![false, false, true, false]));
+    }
+
+    #[test]
+    fn test_short_circuit_rows() {
+        let x = [0.0, 2.0, -1.0];
+        let columns = HashMap::from([("x".to_string(), &x[..])]);
+        let batch = eval_batch(&parse("x != 0 && 4 / x > 1"), &columns).unwrap();
+        assert_eq!(batch.to_values(), [Some(Value::Bool(false)), Some(Value::Bool(true)), Some(Value::Bool(false))]);
+
+        // 没有行需要右边时右边的错误不影响结果
+        let batch = eval_batch(&parse("x > 10 && nope"), &columns).unwrap();
+        assert_eq!(batch.valid, [true; 3]);
+    }
+
+    #[test]
+    fn test_impure_calls_only_on_live_rows() {
+        let x = [0.0, 2.0, -1.0, 3.0];
+        let columns = HashMap::from([("x".to_string(), &x[..])]);
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut ctx = Context::new();
+        let seen = calls.clone();
+        ctx.register_fn("log", false, move |args| {
+            seen.lock().unwrap().push(args[0].clone());
+            Ok(Value::Bool(true))
+        });
+        let policy = EvalPolicy::default();
+
+        let batch = eval_batch_with(&parse("x > 0 && log(x) || log(-x)"), &columns, &ctx, &policy).unwrap();
+        assert_eq!(batch.column, Column::Bool(vec![true; 4]));
+        // 每行只调用一次: 左边为真的行调用 log(x), 其余行调用 log(-x)
+        assert_eq!(*calls.lock().unwrap(), [Value::Number(2.0), Value::Number(3.0), Value::Number(-0.0), Value::Number(1.0)]);
+
+        calls.lock().unwrap().clear();
+        eval_batch_with(&parse("0 < x < 3 == log(x)"), &columns, &ctx, &policy).unwrap();
+        assert_eq!(*calls.lock().unwrap(), [Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn test_scalars_and_errors() {
+        let x = [1.0, 5.0];
+        let columns = HashMap::from([("x".to_string(), &x[..])]);
+        let mut ctx = Context::new();
+        ctx.set("name", "a");
+        let batch = eval_batch_with(&parse("name == \"a\" && x in [1, 2]"), &columns, &ctx, &EvalPolicy::default());
+        assert_eq!(batch.unwrap().column, Column::Bool(vec![true, false]));
+
+        let batch = eval_batch(&parse("x + \"a\""), &columns).unwrap();
+        assert_eq!(batch.to_values(), [None, None]);
+
+        let y = [1.0];
+        let columns = HashMap::from([("x".to_string(), &x[..]), ("y".to_string(), &y[..])]);
+        assert!(eval_batch(&parse("x + y"), &columns).is_err());
+    }
+
+    // 每一行的结果都要和逐行求值一致, 出错的行对应 null
+    #[test]
+    fn test_differential_random() {
+        let xs = [2.0, 0.0, -1.0, 0.5, 3.0, f64::NAN];
+        let columns = HashMap::from([("x".to_string(), &xs[..])]);
+        let mut ctx = Context::new();
+        ctx.set("s", "a");
+        let policies = [
+            EvalPolicy::default(),
+            EvalPolicy {
+                non_finite: eval::NonFinite::Error,
+                div_by_zero: eval::DivByZero::Infinity,
+                float_eq: eval::FloatEq::Abs(0.5),
+                ..Default::default()
+            },
+        ];
+        let mut rng = Rng(0xA0761D6478BD642F);
+        for i in 0..4000 {
+            let policy = &policies[i % 2];
+            let depth = 1 + rng.below(4) as u32;
+            let expr = random_expr(&mut rng, depth);
+            let batch = eval_batch_with(&expr, &columns, &ctx, policy).unwrap();
+            for (row, x) in xs.iter().enumerate() {
+                let mut row_ctx = ctx.clone();
+                row_ctx.set("x", *x);
+                let expected = eval_with(&expr, &row_ctx, policy).ok();
+                assert_eq!(
+                    format!("{:?}", batch.get(row)),
+                    format!("{expected:?}"),
+                    "{expr} at x = {x}"
+                );
+            }
+        }
+    }
+}
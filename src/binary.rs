@@ -0,0 +1,416 @@
+// 语法树和值的紧凑二进制格式 (feature = "binary")
+//
+// 开头是魔数 "EXPR", 1 字节版本号, 1 字节内容类型 (0 表达式, 1 值), 后面是内容本身.
+//
+// 表达式是 1 字节标签加字段:
+//   0 number   f64 (8 字节小端)
+//   1 str      字符串
+//   2 var      字符串
+//   3 bool     1 字节, 0 或 1
+//   4 unary    运算符, 表达式
+//   5 binary   运算符, 左表达式, 右表达式
+//   6 list     个数, 表达式...
+//   7 compare  表达式, 个数, (运算符, 表达式)...
+//   8 call     字符串, 个数, 表达式...
+//   9 let      字符串, 表达式, 表达式
+//  10 error    没有字段, 出错恢复时的占位
+// 值:
+//   0 number   f64
+//   1 bool     1 字节
+//   2 str      字符串
+//   3 range    f64, f64, 1 字节 (是否包含终点)
+//   4 list     个数, 值...
+//
+// 个数和字符串长度是 LEB128 变长整数, 字符串是 UTF-8, 运算符是在 BinaryOp::ALL 中的下标.
+// 表达式和值最多嵌套 MAX_DEPTH 层, 避免恶意数据递归太深把栈用完.
+use anyhow::{Context, Result};
+
+use crate::{ast::{BinaryOp, Expr}, eval::Value};
+
+pub const VERSION: u8 = 1;
+pub const MAX_DEPTH: usize = 512;
+const MAGIC: &[u8; 4] = b"EXPR";
+const KIND_EXPR: u8 = 0;
+const KIND_VALUE: u8 = 1;
+
+pub fn expr_to_bytes(expr: &Expr) -> Vec<u8> {
+    let mut out = header(KIND_EXPR);
+    write_expr(&mut out, expr);
+    out
+}
+
+pub fn expr_from_bytes(bytes: &[u8]) -> Result<Expr> {
+    let mut reader = Reader::open(bytes, KIND_EXPR)?;
+    let expr = reader.expr()?;
+    reader.finish()?;
+    Ok(expr)
+}
+
+pub fn value_to_bytes(value: &Value) -> Vec<u8> {
+    let mut out = header(KIND_VALUE);
+    write_value(&mut out, value);
+    out
+}
+
+pub fn value_from_bytes(bytes: &[u8]) -> Result<Value> {
+    let mut reader = Reader::open(bytes, KIND_VALUE)?;
+    let value = reader.value()?;
+    reader.finish()?;
+    Ok(value)
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend([VERSION, kind]);
+    out
+}
+
+fn write_len(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_len(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_op(out: &mut Vec<u8>, op: BinaryOp) {
+    out.push(BinaryOp::ALL.iter().position(|o| *o == op).expect("ALL 包含所有运算符") as u8);
+}
+
+fn write_expr(out: &mut Vec<u8>, expr: &Expr) {
+    match expr {
+        Expr::Number(n) => {
+            out.push(0);
+            out.extend(n.to_le_bytes());
+        }
+        Expr::Str(s) => {
+            out.push(1);
+            write_str(out, s);
+        }
+        Expr::Var(name) => {
+            out.push(2);
+            write_str(out, name);
+        }
+        Expr::Bool(b) => out.extend([3, *b as u8]),
+        Expr::UnaryOp { op, expr } => {
+            out.push(4);
+            write_op(out, *op);
+            write_expr(out, expr);
+        }
+        Expr::BinaryOp { left, op, right } => {
+            out.push(5);
+            write_op(out, *op);
+            write_expr(out, left);
+            write_expr(out, right);
+        }
+        Expr::List(items) => {
+            out.push(6);
+            write_len(out, items.len());
+            items.iter().for_each(|item| write_expr(out, item));
+        }
+        Expr::Compare { first, rest } => {
+            out.push(7);
+            write_expr(out, first);
+            write_len(out, rest.len());
+            for (op, expr) in rest {
+                write_op(out, *op);
+                write_expr(out, expr);
+            }
+        }
+        Expr::Call { name, args } => {
+            out.push(8);
+            write_str(out, name);
+            write_len(out, args.len());
+            args.iter().for_each(|arg| write_expr(out, arg));
+        }
+        Expr::Let { name, value, body } => {
+            out.push(9);
+            write_str(out, name);
+            write_expr(out, value);
+            write_expr(out, body);
+        }
+        Expr::Error => out.push(10),
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Number(n) => {
+            out.push(0);
+            out.extend(n.to_le_bytes());
+        }
+        Value::Bool(b) => out.extend([1, *b as u8]),
+        Value::Str(s) => {
+            out.push(2);
+            write_str(out, s);
+        }
+        Value::Range { start, end, inclusive } => {
+            out.push(3);
+            out.extend(start.to_le_bytes());
+            out.extend(end.to_le_bytes());
+            out.push(*inclusive as u8);
+        }
+        Value::List(items) => {
+            out.push(4);
+            write_len(out, items.len());
+            items.iter().for_each(|item| write_value(out, item));
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    depth: usize, // 当前所在的表达式或值的层数
+}
+
+impl<'a> Reader<'a> {
+    fn open(bytes: &'a [u8], kind: u8) -> Result<Self> {
+        if !bytes.starts_with(MAGIC) {
+            anyhow::bail!("不是表达式的二进制数据");
+        }
+        let mut reader = Reader { bytes, pos: MAGIC.len(), depth: 0 };
+        let version = reader.byte()?;
+        if version != VERSION {
+            anyhow::bail!("不支持的版本: {version}, 当前版本是 {VERSION}");
+        }
+        match reader.byte()? {
+            k if k == kind => Ok(reader),
+            KIND_EXPR => anyhow::bail!("数据是表达式, 不是值"),
+            KIND_VALUE => anyhow::bail!("数据是值, 不是表达式"),
+            k => anyhow::bail!("未知的内容类型: {k}"),
+        }
+    }
+
+    fn finish(&self) -> Result<()> {
+        if self.pos != self.bytes.len() {
+            anyhow::bail!("末尾有 {} 字节多余的数据", self.bytes.len() - self.pos);
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes.get(self.pos..self.pos.saturating_add(n)).context("数据不完整")?;
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool> {
+        match self.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => anyhow::bail!("无效的布尔值: {b}"),
+        }
+    }
+
+    fn number(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let mut n: usize = 0;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as usize).checked_shl(shift).context("长度太大")?;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        anyhow::bail!("长度太大")
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec()).context("字符串不是有效的 UTF-8")
+    }
+
+    fn op(&mut self) -> Result<BinaryOp> {
+        let index = self.byte()?;
+        BinaryOp::ALL.get(index as usize).copied().with_context(|| format!("未知的运算符: {index}"))
+    }
+
+    // 个数来自数据本身, 不能用来预先分配, 否则错误的数据会申请巨大的内存
+    fn many<T>(&mut self, mut item: impl FnMut(&mut Self) -> Result<T>) -> Result<Vec<T>> {
+        let count = self.len()?;
+        let mut items = Vec::new();
+        for _ in 0..count {
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    // 出错时整个读取失败, 所以只在成功返回时减一
+    fn enter(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            anyhow::bail!("嵌套超过 {MAX_DEPTH} 层");
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.enter()?;
+        let expr = match self.byte()? {
+            0 => Expr::Number(self.number()?),
+            1 => Expr::Str(self.str()?),
+            2 => Expr::Var(self.str()?),
+            3 => Expr::Bool(self.bool()?),
+            4 => {
+                let op = self.op()?;
+                if !matches!(op, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Not) {
+                    anyhow::bail!("不是单目运算符: {op}");
+                }
+                Expr::UnaryOp { op, expr: Box::new(self.expr()?) }
+            }
+            5 => {
+                let op = self.op()?;
+                if op == BinaryOp::Not {
+                    anyhow::bail!("不是双目运算符: {op}");
+                }
+                let left = Box::new(self.expr()?);
+                Expr::BinaryOp { left, op, right: Box::new(self.expr()?) }
+            }
+            6 => Expr::List(self.many(Self::expr)?),
+            7 => {
+                let first = Box::new(self.expr()?);
+                let rest = self.many(|r| Ok((r.op()?, r.expr()?)))?;
+                if rest.is_empty() {
+                    anyhow::bail!("连续比较至少要有一个运算符");
+                }
+                Expr::Compare { first, rest }
+            }
+            8 => {
+                let name = self.str()?;
+                Expr::Call { name, args: self.many(Self::expr)? }
+            }
+            9 => {
+                let name = self.str()?;
+                let value = Box::new(self.expr()?);
+                Expr::Let { name, value, body: Box::new(self.expr()?) }
+            }
+            10 => Expr::Error,
+            tag => anyhow::bail!("未知的表达式标签: {tag}"),
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.enter()?;
+        let value = match self.byte()? {
+            0 => Value::Number(self.number()?),
+            1 => Value::Bool(self.bool()?),
+            2 => Value::Str(self.str()?),
+            3 => Value::Range { start: self.number()?, end: self.number()?, inclusive: self.bool()? },
+            4 => Value::List(self.many(Self::value)?),
+            tag => anyhow::bail!("未知的值标签: {tag}"),
+        };
+        self.depth -= 1;
+        Ok(value)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_expr_format() {
+        let bytes = expr_to_bytes(&parse("-x in [true]"));
+        assert_eq!(bytes, [b'E', b'X', b'P', b'R', 1, 0, 5, 16, 4, 1, 2, 1, b'x', 6, 1, 3, 1]);
+    }
+
+    #[test]
+    fn test_expr_round_trip() {
+        let mut rng = Rng(0xb1_4a4);
+        for _ in 0..500 {
+            let expr = random_expr(&mut rng, 5);
+            assert_eq!(expr_from_bytes(&expr_to_bytes(&expr)).unwrap(), expr);
+        }
+        let long = "长".repeat(100);
+        let expr = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(Expr::Number(f64::NEG_INFINITY)),
+            body: Box::new(Expr::Call { name: long.clone(), args: vec![Expr::Str(long)] }),
+        };
+        assert_eq!(expr_from_bytes(&expr_to_bytes(&expr)).unwrap(), expr);
+        let expr = Expr::List(vec![Expr::Number(1.0), Expr::Error]);
+        assert_eq!(expr_from_bytes(&expr_to_bytes(&expr)).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let values = [
+            Value::Number(-0.5),
+            Value::Bool(true),
+            Value::Str("a\"b".to_string()),
+            Value::Range { start: 1.0, end: 10.0, inclusive: false },
+            Value::List(vec![Value::Number(f64::INFINITY), Value::List(vec![])]),
+        ];
+        for value in values {
+            assert_eq!(value_from_bytes(&value_to_bytes(&value)).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |bytes: &[u8]| expr_from_bytes(bytes).unwrap_err().to_string();
+        let bytes = expr_to_bytes(&parse("f(x, 1)"));
+        assert_eq!(error(&bytes[..bytes.len() - 1]), "数据不完整");
+        assert_eq!(error(&[bytes.as_slice(), &[0]].concat()), "末尾有 1 字节多余的数据");
+        assert_eq!(error(b"JSON"), "不是表达式的二进制数据");
+        assert_eq!(error(b"EXPR\x02\x00"), "不支持的版本: 2, 当前版本是 1");
+        assert_eq!(error(b"EXPR\x01\x01\x00"), "数据是值, 不是表达式");
+        assert_eq!(error(b"EXPR\x01\x00\x0b"), "未知的表达式标签: 11");
+        assert_eq!(error(b"EXPR\x01\x00\x04\x02\x03\x01"), "不是单目运算符: *");
+        assert_eq!(error(b"EXPR\x01\x00\x03\x02"), "无效的布尔值: 2");
+        assert_eq!(error(b"EXPR\x01\x00\x02\x02\xff\xfe"), "字符串不是有效的 UTF-8");
+        // 巨大的个数不会预先分配内存
+        assert_eq!(error(b"EXPR\x01\x00\x06\xff\xff\xff\xff\x0f"), "数据不完整");
+        assert_eq!(error(b"EXPR\x01\x00\x06\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01"), "长度太大");
+    }
+
+    #[test]
+    fn test_max_depth() {
+        // 一长串单目负号 -(-(-(... x)))
+        let nested = |depth: usize| {
+            let mut bytes = header(KIND_EXPR);
+            for _ in 0..depth {
+                bytes.extend([4, 1]);
+            }
+            bytes.extend([2, 1, b'x']);
+            bytes
+        };
+        assert!(expr_from_bytes(&nested(MAX_DEPTH - 1)).is_ok());
+        assert_eq!(expr_from_bytes(&nested(MAX_DEPTH)).unwrap_err().to_string(), format!("嵌套超过 {MAX_DEPTH} 层"));
+        assert_eq!(expr_from_bytes(&nested(200_000)).unwrap_err().to_string(), format!("嵌套超过 {MAX_DEPTH} 层"));
+
+        let mut bytes = header(KIND_VALUE);
+        bytes.extend([4, 1].repeat(200_000));
+        assert_eq!(value_from_bytes(&bytes).unwrap_err().to_string(), format!("嵌套超过 {MAX_DEPTH} 层"));
+    }
+}
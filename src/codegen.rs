@@ -0,0 +1,501 @@
+// 代码生成: 把表达式翻译成 Rust, C 或 JavaScript 的函数
+//
+// 生成的代码和默认的 EvalPolicy 下的求值结果一致:
+//   bool 参与算术和比较时按 0/1 转换, && 和 || 用 "数字大于 0 为真" 的规则 (expr_truthy)
+//   除以零是错误: Rust 返回 Err, C 设置 *err, JavaScript 抛出异常 (expr_div)
+//   连续比较的每个操作数只计算一次, 并且和求值器一样短路
+// 只支持数字和 bool: 字符串, 列表, 范围和 in 没有对应的代码. 每个变量都是函数的一个 f64 参数.
+// 生成的函数依赖 prelude(target) 中的辅助函数, 同一个文件里只需要放一次.
+use std::fmt::Display;
+
+use crate::ast::{BinaryOp, Expr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    Rust,
+    C,
+    JavaScript,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    Number,
+    Bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Generated {
+    pub code: String,
+    pub params: Vec<String>, // 参数按在表达式中第一次出现的顺序
+    pub returns: Type,
+}
+
+impl Display for Generated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.code)
+    }
+}
+
+pub fn prelude(target: Target) -> &'static str {
+    match target {
+        Target::Rust => RUST_PRELUDE,
+        Target::C => C_PRELUDE,
+        Target::JavaScript => JS_PRELUDE,
+    }
+}
+
+const RUST_PRELUDE: &str = r#"#[allow(dead_code)]
+fn expr_div(a: f64, b: f64) -> Result<f64, &'static str> {
+    if b == 0.0 { Err("除以零错误") } else { Ok(a / b) }
+}
+
+#[allow(dead_code)]
+fn expr_truthy(x: f64) -> bool {
+    x > 0.0
+}
+"#;
+
+const C_PRELUDE: &str = r#"#include <math.h>
+
+static double expr_div(double a, double b, int *err) {
+    if (b == 0.0) {
+        *err = 1;
+        return NAN;
+    }
+    return a / b;
+}
+
+static int expr_truthy(double x) {
+    return x > 0.0;
+}
+"#;
+
+// Math.round 对 .5 向上取整, Math.min 遇到 NaN 返回 NaN, 都和 Rust 不同
+const JS_PRELUDE: &str = r#"function expr_div(a, b) {
+    if (b === 0) throw new Error("除以零错误");
+    return a / b;
+}
+
+function expr_truthy(x) {
+    return x > 0;
+}
+
+function expr_round(x) {
+    return Math.sign(x) * Math.round(Math.abs(x));
+}
+
+function expr_min(a, b) {
+    return Number.isNaN(a) ? b : Number.isNaN(b) ? a : Math.min(a, b);
+}
+
+function expr_max(a, b) {
+    return Number.isNaN(a) ? b : Number.isNaN(b) ? a : Math.max(a, b);
+}
+"#;
+
+pub fn generate(expr: &Expr, target: Target, name: &str) -> anyhow::Result<Generated> {
+    let mut codegen = Codegen { target, params: Vec::new(), scope: Vec::new(), temps: Vec::new() };
+    let (body, returns) = codegen.expr(expr)?;
+    let code = match target {
+        Target::Rust => {
+            let params: Vec<String> = codegen.params.iter().map(|p| format!("{p}: f64")).collect();
+            let ty = if returns == Type::Bool { "bool" } else { "f64" };
+            format!("pub fn {name}({}) -> Result<{ty}, &'static str> {{\n    Ok({body})\n}}\n", params.join(", "))
+        }
+        Target::C => {
+            let mut params: Vec<String> = codegen.params.iter().map(|p| format!("double {p}")).collect();
+            params.push("int *err".to_string());
+            let mut decls = String::new();
+            for (temp, ty) in &codegen.temps {
+                decls += &format!("    {} {temp};\n", if *ty == Type::Bool { "int" } else { "double" });
+            }
+            let ty = if returns == Type::Bool { "int" } else { "double" };
+            format!("{ty} {name}({}) {{\n{decls}    *err = 0;\n    return {body};\n}}\n", params.join(", "))
+        }
+        Target::JavaScript => {
+            let temps: Vec<&str> = codegen.temps.iter().map(|(t, _)| t.as_str()).collect();
+            let decls = if temps.is_empty() { String::new() } else { format!("    let {};\n", temps.join(", ")) };
+            format!("function {name}({}) {{\n{decls}    return {body};\n}}\n", codegen.params.join(", "))
+        }
+    };
+    Ok(Generated { code, params: codegen.params, returns })
+}
+
+struct Codegen {
+    target: Target,
+    params: Vec<String>,
+    scope: Vec<(String, String, Type)>, // let 绑定: 原名, 生成的名字, 类型
+    temps: Vec<(String, Type)>,         // C 和 JavaScript 需要提前声明的临时变量
+}
+
+impl Codegen {
+    fn temp(&mut self, prefix: &str, ty: Type) -> String {
+        let name = format!("{prefix}{}", self.temps.len());
+        self.temps.push((name.clone(), ty));
+        name
+    }
+
+    fn expr(&mut self, expr: &Expr) -> anyhow::Result<(String, Type)> {
+        let t = self.target;
+        Ok(match expr {
+            Expr::Number(n) => (self.number(*n), Type::Number),
+            Expr::Error => anyhow::bail!("表达式有语法错误"),
+            Expr::Bool(b) => (if t == Target::C { (*b as u8).to_string() } else { b.to_string() }, Type::Bool),
+            Expr::Var(name) => {
+                if let Some((_, temp, ty)) = self.scope.iter().rev().find(|(n, _, _)| n == name) {
+                    return Ok((temp.clone(), *ty));
+                }
+                if !self.params.contains(name) {
+                    self.params.push(name.clone());
+                }
+                (name.clone(), Type::Number)
+            }
+            Expr::UnaryOp { op, expr: inner } => {
+                let value = self.expr(inner)?;
+                match (op, value.1) {
+                    (BinaryOp::Add, _) => value,
+                    (BinaryOp::Sub | BinaryOp::Not, Type::Bool) => (format!("(!{})", value.0), Type::Bool),
+                    (BinaryOp::Sub, Type::Number) => (format!("(-{})", value.0), Type::Number),
+                    (BinaryOp::Not, Type::Number) => (format!("({} == 0.0)", value.0), Type::Bool),
+                    _ => anyhow::bail!("无法生成代码: {expr}"),
+                }
+            }
+            Expr::BinaryOp { left, op, right } => {
+                let (l, r) = (self.expr(left)?, self.expr(right)?);
+                match op {
+                    BinaryOp::And | BinaryOp::Or => {
+                        let op = if *op == BinaryOp::And { "&&" } else { "||" };
+                        (format!("({} {op} {})", self.truthy(l), self.truthy(r)), Type::Bool)
+                    }
+                    BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul => {
+                        (format!("({} {op} {})", self.num(l), self.num(r)), Type::Number)
+                    }
+                    BinaryOp::Div => {
+                        let (a, b) = (self.num(l), self.num(r));
+                        let code = match t {
+                            Target::Rust => format!("expr_div({a}, {b})?"),
+                            Target::C => format!("expr_div({a}, {b}, err)"),
+                            Target::JavaScript => format!("expr_div({a}, {b})"),
+                        };
+                        (code, Type::Number)
+                    }
+                    BinaryOp::Pow => (self.function("pow", &[self.num(l), self.num(r)])?, Type::Number),
+                    BinaryOp::Eq | BinaryOp::Neq | BinaryOp::Gt | BinaryOp::Gte | BinaryOp::Lt | BinaryOp::Lte => {
+                        (format!("({} {} {})", self.num(l), comparison(*op), self.num(r)), Type::Bool)
+                    }
+                    _ => anyhow::bail!("无法生成代码: {expr}"),
+                }
+            }
+            Expr::Compare { first, rest } => (self.chain(first, rest)?, Type::Bool),
+            Expr::Call { name, args } => {
+                let args =
+                    args.iter().map(|a| self.expr(a).map(|v| self.num(v))).collect::<anyhow::Result<Vec<_>>>()?;
+                (self.function(name, &args)?, Type::Number)
+            }
+            Expr::Let { name, value, body } => {
+                let (value, ty) = self.expr(value)?;
+                let temp = self.temp("_v", ty);
+                self.scope.push((name.clone(), temp.clone(), ty));
+                let body = self.expr(body);
+                self.scope.pop();
+                let (body, ty) = body?;
+                let code = match t {
+                    Target::Rust => format!("{{ let {temp} = {value}; {body} }}"),
+                    _ => format!("(({temp} = {value}), {body})"),
+                };
+                (code, ty)
+            }
+            Expr::Str(_) | Expr::List(_) => anyhow::bail!("无法生成代码: {expr}"),
+        })
+    }
+
+    // a < b < c: 每个操作数存进临时变量, 前一个比较为假时后面的操作数不再计算
+    fn chain(&mut self, first: &Expr, rest: &[(BinaryOp, Expr)]) -> anyhow::Result<String> {
+        let first = self.expr(first)?;
+        let mut prev = self.temp("_c", Type::Number);
+        let mut steps = vec![vec![(prev.clone(), self.num(first))]];
+        let mut tests = Vec::new();
+        for (i, (op, e)) in rest.iter().enumerate() {
+            let value = self.expr(e)?;
+            let temp = self.temp("_c", Type::Number);
+            if i > 0 {
+                steps.push(Vec::new());
+            }
+            steps.last_mut().unwrap().push((temp.clone(), self.num(value)));
+            tests.push(format!("({prev} {} {temp})", comparison(*op)));
+            prev = temp;
+        }
+        // 从最后一段往前拼接
+        let mut code = String::new();
+        for (step, test) in steps.iter().zip(&tests).rev() {
+            let test = if code.is_empty() { test.clone() } else { format!("{test} && {code}") };
+            code = match self.target {
+                Target::Rust => {
+                    let lets: String = step.iter().map(|(t, v)| format!("let {t} = {v}; ")).collect();
+                    format!("{{ {lets}{test} }}")
+                }
+                _ => {
+                    let sets: Vec<String> = step.iter().map(|(t, v)| format!("({t} = {v})")).collect();
+                    format!("({}, {test})", sets.join(", "))
+                }
+            };
+        }
+        Ok(code)
+    }
+
+    fn function(&self, name: &str, args: &[String]) -> anyhow::Result<String> {
+        let expected = if matches!(name, "min" | "max" | "pow") { 2 } else { 1 };
+        if args.len() != expected {
+            anyhow::bail!("函数 {name} 需要 {expected} 个参数, 实际是 {} 个", args.len());
+        }
+        let known = ["abs", "sqrt", "exp", "ln", "log10", "sin", "cos", "tan", "floor", "ceil", "round", "min", "max", "pow"];
+        if !known.contains(&name) {
+            anyhow::bail!("无法生成代码: 函数 {name}");
+        }
+        let f = match (self.target, name) {
+            (Target::Rust, "pow") => "f64::powf".to_string(),
+            (Target::Rust, _) => format!("f64::{name}"),
+            (Target::C, "abs") => "fabs".to_string(),
+            (Target::C, "ln") => "log".to_string(),
+            (Target::C, "min" | "max") => format!("f{name}"),
+            (Target::C, _) => name.to_string(),
+            (Target::JavaScript, "ln") => "Math.log".to_string(),
+            (Target::JavaScript, "round" | "min" | "max") => format!("expr_{name}"),
+            (Target::JavaScript, _) => format!("Math.{name}"),
+        };
+        Ok(format!("{f}({})", args.join(", ")))
+    }
+
+    fn number(&self, n: f64) -> String {
+        let code = match (self.target, n) {
+            (Target::Rust, n) if n.is_nan() => "f64::NAN".to_string(),
+            (Target::Rust, n) if n.is_infinite() => "f64::INFINITY".to_string(),
+            (Target::C, n) if n.is_nan() => "NAN".to_string(),
+            (Target::C, n) if n.is_infinite() => "INFINITY".to_string(),
+            (Target::JavaScript, n) if n.is_nan() => "NaN".to_string(),
+            (Target::JavaScript, n) if n.is_infinite() => "Infinity".to_string(),
+            (_, n) => format!("{:?}", n.abs()),
+        };
+        if n.is_sign_negative() && !n.is_nan() { format!("(-{code})") } else { code }
+    }
+
+    // bool 按 0/1 转换成数字
+    fn num(&self, (code, ty): (String, Type)) -> String {
+        match (ty, self.target) {
+            (Type::Number, _) => code,
+            (Type::Bool, Target::Rust) => format!("(if {code} {{ 1.0 }} else {{ 0.0 }})"),
+            (Type::Bool, Target::C) => format!("((double){code})"),
+            (Type::Bool, Target::JavaScript) => format!("({code} ? 1 : 0)"),
+        }
+    }
+
+    fn truthy(&self, (code, ty): (String, Type)) -> String {
+        match ty {
+            Type::Bool => code,
+            Type::Number => format!("expr_truthy({code})"),
+        }
+    }
+}
+
+fn comparison(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Eq => "==",
+        BinaryOp::Neq => "!=",
+        BinaryOp::Gt => ">",
+        BinaryOp::Gte => ">=",
+        BinaryOp::Lt => "<",
+        _ => "<=",
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process::Command};
+
+    use super::*;
+    use crate::{
+        eval::{eval_with, Context, EvalPolicy, Value},
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_generate() {
+        let expr = parse("x * 2 > 1 && y / x < 3");
+        let rust = generate(&expr, Target::Rust, "check").unwrap();
+        assert_eq!(rust.params, ["x", "y"]);
+        assert_eq!(rust.returns, Type::Bool);
+        assert_eq!(
+            rust.code,
+            "pub fn check(x: f64, y: f64) -> Result<bool, &'static str> {\n    Ok((((x * 2.0) > 1.0) && (expr_div(y, x)? < 3.0)))\n}\n"
+        );
+        let js = generate(&parse("1 < x < 2"), Target::JavaScript, "f").unwrap();
+        assert_eq!(js.code, "function f(x) {\n    let _c0, _c1, _c2;\n    return ((_c0 = 1.0), (_c1 = x), (_c0 < _c1) && ((_c2 = 2.0), (_c1 < _c2)));\n}\n");
+        let c = generate(&parse("-(x > 1) + abs(x)"), Target::C, "f").unwrap();
+        assert_eq!(c.code, "double f(double x, int *err) {\n    *err = 0;\n    return (((double)(!(x > 1.0))) + fabs(x));\n}\n");
+
+        assert!(generate(&parse("x in [1, 2]"), Target::C, "f").is_err());
+        assert!(generate(&parse("foo(x)"), Target::C, "f").is_err());
+        assert!(generate(&parse("\"a\" == x"), Target::Rust, "f").is_err());
+    }
+
+    // 测试用的表达式: 固定的例子加上能生成代码的随机表达式
+    fn samples() -> Vec<Expr> {
+        let mut exprs: Vec<Expr> = [
+            "x ^ 2 + 2 * x * s - 1 / (s - 1)",
+            "sqrt(abs(x)) + ln(s + 3) - log10(s + 3) * exp(x / 4)",
+            "sin(x) * cos(s) + tan(x / 10) + floor(x / 3) - ceil(s / 2) + round(x / 2)",
+            "min(x, s) + max(x, s) + pow(2, s)",
+            "x > 0 && s > 0 || !(x == s)",
+            "-1 <= x < s + 1 != false",
+            "(x > 1) * 10 + -(s > 1) + (x && s)",
+        ]
+        .iter()
+        .map(|s| parse(s))
+        .collect();
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        while exprs.len() < 300 {
+            let depth = 1 + rng.below(4) as u32;
+            let expr = random_expr(&mut rng, depth);
+            if generate(&expr, Target::Rust, "f").is_ok() {
+                exprs.push(expr);
+            }
+        }
+        exprs
+    }
+
+    const INPUTS: [[f64; 3]; 5] = [[2.0, 1.0, 0.0], [0.0, 2.5, -1.0], [-1.5, 0.0, 3.0], [1.0, -2.0, 1.0], [3.0, 3.0, 0.5]];
+
+    fn args(generated: &Generated, input: &[f64; 3]) -> Vec<String> {
+        let value = |p: &str| input[["x", "s", "undefined"].iter().position(|n| *n == p).unwrap()];
+        generated.params.iter().map(|p| format!("{:?}", value(p))).collect()
+    }
+
+    // 每个表达式在每组输入下的期望输出, 出错为 None
+    fn expected(exprs: &[Expr]) -> Vec<Option<Value>> {
+        let mut out = Vec::new();
+        for expr in exprs {
+            for [x, s, u] in INPUTS {
+                let mut ctx = Context::new();
+                ctx.set("x", x).set("s", s).set("undefined", u);
+                out.push(eval_with(expr, &ctx, &EvalPolicy::default()).ok());
+            }
+        }
+        out
+    }
+
+    fn check_output(exprs: &[Expr], output: &str) {
+        let lines: Vec<&str> = output.lines().collect();
+        let expected = expected(exprs);
+        assert_eq!(lines.len(), expected.len());
+        for (i, (line, expected)) in lines.iter().zip(expected).enumerate() {
+            let expr = &exprs[i / INPUTS.len()];
+            let ok = match (*line, &expected) {
+                ("err", None) => true,
+                ("true", Some(Value::Bool(true))) | ("false", Some(Value::Bool(false))) => true,
+                (line, Some(Value::Number(n))) if line.to_lowercase().contains("nan") => n.is_nan(),
+                (line, Some(Value::Number(n))) => {
+                    let v: f64 = line.parse().unwrap();
+                    v == *n || (v - n).abs() <= 1e-12 * n.abs().max(1.0)
+                }
+                _ => false,
+            };
+            assert!(ok, "{expr} 输入 {:?}: 生成的代码输出 {line}, 期望 {expected:?}", INPUTS[i % INPUTS.len()]);
+        }
+    }
+
+    // 往返测试需要本机装有 rustc / cc / node, 默认忽略, 用 `cargo test -- --ignored` 运行
+    fn run(program: &str, args: &[&str]) -> String {
+        let output = Command::new(program)
+            .args(args)
+            .output()
+            .unwrap_or_else(|e| panic!("无法运行 {program}: {e}"));
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("expr_codegen_{}_{name}", std::process::id()))
+    }
+
+    #[test]
+    #[ignore = "需要 rustc"]
+    fn test_round_trip_rust() {
+        let exprs = samples();
+        let mut source = prelude(Target::Rust).to_string();
+        let mut main = String::from("fn main() {\n");
+        for (i, expr) in exprs.iter().enumerate() {
+            let generated = generate(expr, Target::Rust, &format!("f{i}")).unwrap();
+            source += &generated.code;
+            for input in &INPUTS {
+                let args = args(&generated, input).join(", ");
+                main += &format!("    match f{i}({args}) {{ Ok(v) => println!(\"{{v:?}}\"), Err(_) => println!(\"err\") }}\n");
+            }
+        }
+        source += &main;
+        source += "}\n";
+        let (src, bin) = (temp_path("rs.rs"), temp_path("rs"));
+        std::fs::write(&src, source).unwrap();
+        run("rustc", &["-O", "-A", "warnings", "-o", bin.to_str().unwrap(), src.to_str().unwrap()]);
+        check_output(&exprs, &run(bin.to_str().unwrap(), &[]));
+        let _ = (std::fs::remove_file(src), std::fs::remove_file(bin));
+    }
+
+    #[test]
+    #[ignore = "需要 cc"]
+    fn test_round_trip_c() {
+        let exprs = samples();
+        let mut source = format!("#include <stdio.h>\n{}", prelude(Target::C));
+        let mut main = String::from("int main(void) {\n    int err;\n");
+        for (i, expr) in exprs.iter().enumerate() {
+            let generated = generate(expr, Target::C, &format!("f{i}")).unwrap();
+            source += &generated.code;
+            for input in &INPUTS {
+                let mut args = args(&generated, input);
+                args.push("&err".to_string());
+                let print = match generated.returns {
+                    Type::Bool => "puts(v ? \"true\" : \"false\")",
+                    Type::Number => "printf(\"%.17g\\n\", v)",
+                };
+                let ty = if generated.returns == Type::Bool { "int" } else { "double" };
+                main += &format!(
+                    "    {{ {ty} v = f{i}({}); if (err) puts(\"err\"); else {print}; }}\n",
+                    args.join(", ")
+                );
+            }
+        }
+        source += &main;
+        source += "    return 0;\n}\n";
+        let (src, bin) = (temp_path("c.c"), temp_path("c"));
+        std::fs::write(&src, source).unwrap();
+        run("cc", &["-O2", "-o", bin.to_str().unwrap(), src.to_str().unwrap(), "-lm"]);
+        check_output(&exprs, &run(bin.to_str().unwrap(), &[]));
+        let _ = (std::fs::remove_file(src), std::fs::remove_file(bin));
+    }
+
+    #[test]
+    #[ignore = "需要 node"]
+    fn test_round_trip_javascript() {
+        let exprs = samples();
+        let mut source = prelude(Target::JavaScript).to_string();
+        source += "function show(f) {\n    try {\n        console.log(String(f()));\n    } catch (e) {\n        console.log(\"err\");\n    }\n}\n";
+        for (i, expr) in exprs.iter().enumerate() {
+            let generated = generate(expr, Target::JavaScript, &format!("f{i}")).unwrap();
+            source += &generated.code;
+            for input in &INPUTS {
+                source += &format!("show(() => f{i}({}));\n", args(&generated, input).join(", "));
+            }
+        }
+        let src = temp_path("js.js");
+        std::fs::write(&src, source).unwrap();
+        let output = run("node", &[src.to_str().unwrap()]);
+        let _ = std::fs::remove_file(&src);
+        check_output(&exprs, &output);
+    }
+}
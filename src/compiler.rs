@@ -0,0 +1,273 @@
+// 把语法树编译成字节码
+use std::fmt;
+
+use crate::{ast::{BinaryOp, Expr}, eval::Value, functions::{Function, Functions}};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Const(u32),           // 压入常量池中的值
+    Load(u32),            // 压入变量槽位对应的变量值
+    LoadLocal(u32),       // 压入内部绑定 (let) 的值
+    StoreLocal(u32),      // 弹出 1 个值存入内部绑定
+    Call(u32, u32),       // 弹出 n 个参数调用函数表中的函数, 压入返回值
+    Unary(BinaryOp),      // 弹出 1 个值, 压入结果
+    Binary(BinaryOp),     // 弹出 2 个值, 压入结果 (不包括 && 和 ||)
+    MakeList(u32),        // 弹出 n 个值, 压入列表
+    Truthy,               // 弹出 1 个值, 压入它的真值
+    CompareKeep(BinaryOp),// 弹出 a b, 压入 b 和 a op b, 用于连续比较
+    Jump(u32),            // 跳转到指定位置
+    JumpIfFalse(u32),     // 弹出 bool, 为 false 时跳转
+    JumpIfFalseOrPop(u32),// 栈顶为 false 时保留并跳转, 否则弹出
+    JumpIfTrueOrPop(u32), // 栈顶为 true 时保留并跳转, 否则弹出
+    Pop,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<Value>,
+    pub names: Vec<String>, // 变量槽位 -> 变量名
+    pub functions: Vec<(String, Function)>, // 编译时解析好的函数
+    pub locals: usize, // 内部绑定需要的槽位数
+}
+
+impl Chunk {
+    // 变量名在编译时解析成槽位, 同名变量共用一个槽位
+    fn slot(&mut self, name: &str) -> u32 {
+        if let Some(i) = self.names.iter().position(|n| n == name) {
+            return i as u32;
+        }
+        self.names.push(name.to_string());
+        (self.names.len() - 1) as u32
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        // 数字常量去重, 避免同一个数字反复进常量池
+        if let Some(i) = self.constants.iter().position(|c| matches!((c, &value), (Value::Number(a), Value::Number(b)) if a.to_bits() == b.to_bits())) {
+            return i as u32;
+        }
+        self.constants.push(value);
+        (self.constants.len() - 1) as u32
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    // 回填跳转目标为当前位置
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[at] {
+            Op::Jump(t) | Op::JumpIfFalse(t) | Op::JumpIfFalseOrPop(t) | Op::JumpIfTrueOrPop(t) => *t = target,
+            op => unreachable!("不是跳转指令: {op:?}"),
+        }
+    }
+}
+
+impl fmt::Display for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, op) in self.code.iter().enumerate() {
+            match op {
+                Op::Const(c) => writeln!(f, "{i:04} Const {}", self.constants[*c as usize])?,
+                Op::Load(slot) => writeln!(f, "{i:04} Load {}", self.names[*slot as usize])?,
+                Op::Call(func, argc) => writeln!(f, "{i:04} Call {}/{argc}", self.functions[*func as usize].0)?,
+                _ => writeln!(f, "{i:04} {op:?}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// 函数在编译时从 functions 中解析, 找不到时报错
+pub fn compile(expr: &Expr, functions: &Functions) -> anyhow::Result<Chunk> {
+    let mut compiler = Compiler { chunk: Chunk::default(), functions, scope: Vec::new() };
+    compiler.expr(expr)?;
+    Ok(compiler.chunk)
+}
+
+struct Compiler<'a> {
+    chunk: Chunk,
+    functions: &'a Functions,
+    scope: Vec<String>, // 当前可见的内部绑定, 下标就是槽位
+}
+
+impl Compiler<'_> {
+    fn function(&mut self, name: &str) -> anyhow::Result<u32> {
+        if let Some(i) = self.chunk.functions.iter().position(|(n, _)| n == name) {
+            return Ok(i as u32);
+        }
+        let f = self.functions.lookup(name)?.clone();
+        self.chunk.functions.push((name.to_string(), f));
+        Ok((self.chunk.functions.len() - 1) as u32)
+    }
+
+    fn constant(&mut self, value: Value) {
+        let c = self.chunk.constant(value);
+        self.chunk.emit(Op::Const(c));
+    }
+
+    fn expr(&mut self, expr: &Expr) -> anyhow::Result<()> {
+        match expr {
+            Expr::Number(n) => self.constant(Value::Number(*n)),
+            Expr::Error => anyhow::bail!("表达式有语法错误"),
+            Expr::Str(s) => self.constant(Value::Str(s.clone())),
+            Expr::Bool(b) => self.constant(Value::Bool(*b)),
+            Expr::Var(name) => {
+                if let Some(local) = self.scope.iter().rposition(|n| n == name) {
+                    self.chunk.emit(Op::LoadLocal(local as u32));
+                } else {
+                    let slot = self.chunk.slot(name);
+                    self.chunk.emit(Op::Load(slot));
+                }
+            }
+            Expr::List(items) => {
+                for item in items {
+                    self.expr(item)?;
+                }
+                self.chunk.emit(Op::MakeList(items.len() as u32));
+            }
+            Expr::UnaryOp { op, expr } => {
+                self.expr(expr)?;
+                self.chunk.emit(Op::Unary(*op));
+            }
+            Expr::BinaryOp { left, op: op @ (BinaryOp::And | BinaryOp::Or), right } => {
+                // left; Truthy; JumpIf..OrPop end; right; Truthy; end:
+                self.expr(left)?;
+                self.chunk.emit(Op::Truthy);
+                let jump = if *op == BinaryOp::And {
+                    self.chunk.emit(Op::JumpIfFalseOrPop(0))
+                } else {
+                    self.chunk.emit(Op::JumpIfTrueOrPop(0))
+                };
+                self.expr(right)?;
+                self.chunk.emit(Op::Truthy);
+                self.chunk.patch(jump);
+            }
+            Expr::BinaryOp { left, op, right } => {
+                self.expr(left)?;
+                self.expr(right)?;
+                self.chunk.emit(Op::Binary(*op));
+            }
+            Expr::Compare { first, rest } => {
+                // 中间的操作数留在栈上给下一次比较用, 任何一次为 false 都跳到 fail
+                self.expr(first)?;
+                let mut fails = Vec::new();
+                let (last, init) = rest.split_last().ok_or_else(|| anyhow::anyhow!("空的连续比较"))?;
+                for (op, expr) in init {
+                    self.expr(expr)?;
+                    self.chunk.emit(Op::CompareKeep(*op));
+                    fails.push(self.chunk.emit(Op::JumpIfFalse(0)));
+                }
+                self.expr(&last.1)?;
+                self.chunk.emit(Op::Binary(last.0));
+                let end = self.chunk.emit(Op::Jump(0));
+                for at in fails {
+                    self.chunk.patch(at);
+                }
+                self.chunk.emit(Op::Pop);
+                self.constant(Value::Bool(false));
+                self.chunk.patch(end);
+            }
+            Expr::Call { name, args } => {
+                let func = self.function(name)?;
+                for arg in args {
+                    self.expr(arg)?;
+                }
+                self.chunk.emit(Op::Call(func, args.len() as u32));
+            }
+            Expr::Let { name, value, body } => {
+                self.expr(value)?;
+                let local = self.scope.len();
+                self.scope.push(name.clone());
+                self.chunk.locals = self.chunk.locals.max(self.scope.len());
+                self.chunk.emit(Op::StoreLocal(local as u32));
+                self.expr(body)?;
+                self.scope.pop();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn compile_str(input: &str) -> Chunk {
+        compile(&Parser::new(tokenize(input).unwrap()).parse_program().unwrap(), &Functions::default()).unwrap()
+    }
+
+    #[test]
+    fn test_compile_arithmetic() {
+        let chunk = compile_str("1 + 2 * 1");
+        assert_eq!(chunk.code, vec![
+            Op::Const(0),
+            Op::Const(1),
+            Op::Const(0),
+            Op::Binary(BinaryOp::Mul),
+            Op::Binary(BinaryOp::Add),
+        ]);
+        assert_eq!(chunk.constants, vec![Value::Number(1.0), Value::Number(2.0)]);
+    }
+
+    #[test]
+    fn test_compile_variables() {
+        let chunk = compile_str("x * y + x");
+        assert_eq!(chunk.code, vec![
+            Op::Load(0),
+            Op::Load(1),
+            Op::Binary(BinaryOp::Mul),
+            Op::Load(0),
+            Op::Binary(BinaryOp::Add),
+        ]);
+        assert_eq!(chunk.names, vec!["x", "y"]);
+    }
+
+    #[test]
+    fn test_compile_short_circuit() {
+        let chunk = compile_str("1 && 0");
+        assert_eq!(chunk.code, vec![
+            Op::Const(0),
+            Op::Truthy,
+            Op::JumpIfFalseOrPop(5),
+            Op::Const(1),
+            Op::Truthy,
+        ]);
+    }
+
+    #[test]
+    fn test_compile_chain() {
+        let chunk = compile_str("1 < 2 < 3");
+        assert_eq!(chunk.code, vec![
+            Op::Const(0),
+            Op::Const(1),
+            Op::CompareKeep(BinaryOp::Lt),
+            Op::JumpIfFalse(7),
+            Op::Const(2),
+            Op::Binary(BinaryOp::Lt),
+            Op::Jump(9),
+            Op::Pop,
+            Op::Const(3),
+        ]);
+    }
+
+    #[test]
+    fn test_compile_call_and_let() {
+        let chunk = compile_str("max(x, 1) + max(2, x)");
+        assert_eq!(chunk.code[2], Op::Call(0, 2));
+        assert_eq!(chunk.code[5], Op::Call(0, 2));
+        assert_eq!(chunk.functions.len(), 1);
+        assert!(compile(&Expr::Call { name: "nope".to_string(), args: vec![] }, &Functions::default()).is_err());
+
+        let expr = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(Expr::Var("x".to_string())),
+            body: Box::new(Expr::Var("$0".to_string())),
+        };
+        let chunk = compile(&expr, &Functions::default()).unwrap();
+        assert_eq!(chunk.code, vec![Op::Load(0), Op::StoreLocal(0), Op::LoadLocal(0)]);
+        assert_eq!(chunk.locals, 1);
+    }
+}
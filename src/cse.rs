@@ -0,0 +1,374 @@
+// 公共子表达式消除
+//
+// 结构相同的纯子表达式只求值一次: (a + b) * (a + b) > (a + b)
+// 会变成 let $0 = (a + b) in (($0 * $0) > $0), 编译后 $0 存在虚拟机的内部槽位里.
+//
+// && 和 || 的右边, 以及连续比较第二个之后的操作数, 不一定会被求值. 提前计算它们可能
+// 引入原本不会发生的错误 (x != 0 && 1 / x > 1 / x), 所以只有当子表达式在绑定位置
+// 一定会被求值时才提取, 否则到这些分支内部再单独处理.
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+};
+
+use crate::{
+    ast::{walk_expr, walk_fold, BinaryOp, Expr, Folder, Visitor},
+    functions::Functions,
+};
+
+#[derive(Debug, Clone)]
+pub struct Eliminated {
+    pub expr: Expr,
+    pub changes: Vec<String>,
+}
+
+// 内部绑定的名字以 $ 开头, 不会和用户变量冲突
+pub fn eliminate(expr: &Expr, functions: &Functions) -> Eliminated {
+    let mut cse = Cse { functions, next: 0, changes: Vec::new() };
+    let expr = cse.region(expr.clone());
+    Eliminated { expr, changes: cse.changes }
+}
+
+// 纯表达式: 没有调用不纯的函数
+pub fn is_pure(expr: &Expr, functions: &Functions) -> bool {
+    match expr {
+        Expr::Call { name, args } => functions.is_pure(name) && args.iter().all(|a| is_pure(a, functions)),
+        _ => children(expr).into_iter().all(|e| is_pure(e, functions)),
+    }
+}
+
+struct Cse<'a> {
+    functions: &'a Functions,
+    next: usize,
+    changes: Vec<String>,
+}
+
+// 区域里的一个节点, 按先序排列, 所以一棵子树是 nodes 里连续的一段
+struct Node<'e> {
+    expr: &'e Expr,
+    hash: u64,
+    size: usize,
+    // 最近的一个 "不一定求值" 的分支起点 (先序下标), None 表示这个节点一定会被求值
+    barrier: Option<usize>,
+    // 可以提取: 不是叶子, 纯, 没有引用内部绑定
+    candidate: bool,
+}
+
+// analyze 对一棵子树的汇总
+struct Summary {
+    hash: u64,
+    size: usize,
+    pure: bool,
+    binding: bool,
+}
+
+// 结构相同的一组子表达式
+#[derive(Clone, Copy)]
+struct Group {
+    first: usize,
+    total: usize,
+    unconditional: usize,
+}
+
+impl Cse<'_> {
+    // 处理一个一定会被完整求值的区域
+    fn region(&mut self, body: Expr) -> Expr {
+        let (binding_of, sizes, values) = {
+            let mut nodes = Vec::new();
+            self.analyze(&body, None, &mut nodes);
+            let (binding_of, firsts) = select(&nodes);
+            let values: Vec<String> = firsts.iter().map(|&i| nodes[i].expr.to_string()).collect();
+            (binding_of, nodes.iter().map(|n| n.size).collect(), values)
+        };
+        let count = values.len();
+        let names: Vec<String> = (self.next..self.next + count).map(|i| format!("${i}")).collect();
+        self.next += count;
+        for (name, value) in names.iter().zip(values) {
+            self.changes.push(format!("{name} = {value}"));
+        }
+
+        let mut replacer = Replacer { index: 0, binding_of, sizes, names, values: vec![None; count] };
+        let mut body = replacer.replace(body);
+        let bindings: Vec<(String, Expr)> = replacer
+            .names
+            .into_iter()
+            .zip(replacer.values)
+            .map(|(name, value)| (name, value.expect("被替换的子表达式")))
+            .collect();
+
+        body = self.nested(body);
+        // 后提取的绑定可能被先提取的绑定引用, 所以放在外层
+        for (name, value) in bindings {
+            let value = self.nested(value);
+            body = Expr::Let { name, value: Box::new(value), body: Box::new(body) };
+        }
+        body
+    }
+
+    // 在不一定会被求值的分支内部单独做消除
+    fn nested(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::BinaryOp { left, op: op @ (BinaryOp::And | BinaryOp::Or), right } => {
+                Expr::BinaryOp { left: Box::new(self.nested(*left)), op, right: Box::new(self.region(*right)) }
+            }
+            Expr::Compare { first, rest } => Expr::Compare {
+                first: Box::new(self.nested(*first)),
+                rest: rest
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, (op, e))| (op, if i == 0 { self.nested(e) } else { self.region(e) }))
+                    .collect(),
+            },
+            expr => map_children(expr, &mut |e| self.nested(e)),
+        }
+    }
+
+    // 一次遍历算出每个节点的结构哈希, 大小, 是否纯, 是否一定会被求值
+    fn analyze<'e>(&self, expr: &'e Expr, barrier: Option<usize>, nodes: &mut Vec<Node<'e>>) -> Summary {
+        let index = nodes.len();
+        nodes.push(Node { expr, hash: 0, size: 1, barrier, candidate: false });
+
+        let mut hasher = DefaultHasher::new();
+        std::mem::discriminant(expr).hash(&mut hasher);
+        match expr {
+            Expr::Number(n) => n.to_bits().hash(&mut hasher),
+            Expr::Str(s) | Expr::Var(s) => s.hash(&mut hasher),
+            Expr::Bool(b) => b.hash(&mut hasher),
+            Expr::UnaryOp { op, .. } | Expr::BinaryOp { op, .. } => op.hash(&mut hasher),
+            Expr::Compare { rest, .. } => rest.iter().for_each(|(op, _)| op.hash(&mut hasher)),
+            Expr::Call { name, .. } | Expr::Let { name, .. } => name.hash(&mut hasher),
+            Expr::List(_) | Expr::Error => {}
+        }
+        let mut summary = Summary {
+            hash: 0,
+            size: 1,
+            pure: !matches!(expr, Expr::Call { name, .. } if !self.functions.is_pure(name)),
+            binding: matches!(expr, Expr::Var(name) if name.starts_with('$')),
+        };
+        // && 和 || 的右边, 连续比较第二个之后的操作数不一定会被求值
+        for (i, child) in children(expr).into_iter().enumerate() {
+            let conditional = match expr {
+                Expr::BinaryOp { op: BinaryOp::And | BinaryOp::Or, .. } => i == 1,
+                Expr::Compare { .. } => i >= 2,
+                _ => false,
+            };
+            let child_barrier = if conditional { Some(nodes.len()) } else { barrier };
+            let child = self.analyze(child, child_barrier, nodes);
+            child.hash.hash(&mut hasher);
+            summary.size += child.size;
+            summary.pure &= child.pure;
+            summary.binding |= child.binding;
+        }
+        summary.hash = hasher.finish();
+
+        let node = &mut nodes[index];
+        node.hash = summary.hash;
+        node.size = summary.size;
+        node.candidate = is_candidate(expr) && summary.pure && !summary.binding;
+        summary
+    }
+}
+
+// 选出要提取的子表达式, 返回每个节点对应的绑定下标, 以及每个绑定第一次出现的节点.
+// 从大到小处理, 提取一组以后直接调整它里面各组的计数, 不再重新统计整棵树
+fn select(nodes: &[Node]) -> (Vec<Option<usize>>, Vec<usize>) {
+    let mut groups: Vec<Group> = Vec::new();
+    let mut by_hash: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut group_of = vec![None; nodes.len()];
+    for (i, node) in nodes.iter().enumerate().filter(|(_, n)| n.candidate) {
+        let same = by_hash.entry(node.hash).or_default();
+        let g = match same.iter().find(|&&g| nodes[groups[g].first].expr == node.expr) {
+            Some(&g) => g,
+            None => {
+                groups.push(Group { first: i, total: 0, unconditional: 0 });
+                same.push(groups.len() - 1);
+                groups.len() - 1
+            }
+        };
+        group_of[i] = Some(g);
+        groups[g].total += 1;
+        groups[g].unconditional += node.barrier.is_none() as usize;
+    }
+
+    // 先提取最大的子表达式, 相同大小时按出现顺序, 保证结果稳定
+    let mut order: Vec<usize> = (0..groups.len()).collect();
+    order.sort_by_key(|&g| (Reverse(nodes[groups[g].first].size), groups[g].first));
+    let mut binding = vec![None; groups.len()];
+    let mut firsts = Vec::new();
+    for g in order {
+        let Group { first, total, unconditional } = groups[g];
+        if total < 2 || unconditional == 0 {
+            continue;
+        }
+        binding[g] = Some(firsts.len());
+        firsts.push(first);
+        // 所有出现都换成同一个绑定: 里面的子表达式只剩绑定值里的一份,
+        // 绑定值一定会被求值, 所以相对它一定求值的出现也就一定会被求值
+        for j in first + 1..first + nodes[first].size {
+            let Some(h) = group_of[j] else { continue };
+            groups[h].total -= total - 1;
+            if nodes[j].barrier.is_none_or(|b| b <= first) {
+                groups[h].unconditional = groups[h].unconditional + 1 - unconditional;
+            }
+        }
+    }
+    (group_of.into_iter().map(|g| g.and_then(|g| binding[g])).collect(), firsts)
+}
+
+// 按先序把选中的子表达式换成绑定变量, 每组第一次出现的地方作为绑定的值
+struct Replacer {
+    index: usize,
+    binding_of: Vec<Option<usize>>,
+    sizes: Vec<usize>,
+    names: Vec<String>,
+    values: Vec<Option<Expr>>,
+}
+
+impl Replacer {
+    fn replace(&mut self, expr: Expr) -> Expr {
+        let i = self.index;
+        match self.binding_of[i] {
+            // 已经有值了, 整棵子树跳过
+            Some(b) if self.values[b].is_some() => {
+                self.index += self.sizes[i];
+                Expr::Var(self.names[b].clone())
+            }
+            Some(b) => {
+                self.index += 1;
+                let value = map_children(expr, &mut |e| self.replace(e));
+                self.values[b] = Some(value);
+                Expr::Var(self.names[b].clone())
+            }
+            None => {
+                self.index += 1;
+                map_children(expr, &mut |e| self.replace(e))
+            }
+        }
+    }
+}
+
+// 叶子节点不值得提取; 引用了内部绑定的表达式也不提取, 避免绑定顺序出错 (由调用者检查)
+fn is_candidate(expr: &Expr) -> bool {
+    !matches!(expr, Expr::Number(_) | Expr::Str(_) | Expr::Bool(_) | Expr::Var(_) | Expr::Let { .. } | Expr::Error)
+}
+
+// 直接子节点, 不再往下
+fn children(expr: &Expr) -> Vec<&Expr> {
+    struct Children<'a>(Vec<&'a Expr>);
+
+    impl<'a> Visitor<'a> for Children<'a> {
+        fn visit_expr(&mut self, expr: &'a Expr) {
+            self.0.push(expr);
+        }
+    }
+
+    let mut children = Children(Vec::new());
+    walk_expr(&mut children, expr);
+    children.0
+}
+
+// 用 f 替换每个直接子节点
+fn map_children(expr: Expr, f: &mut impl FnMut(Expr) -> Expr) -> Expr {
+    struct MapChildren<F>(F);
+
+    impl<F: FnMut(Expr) -> Expr> Folder for MapChildren<F> {
+        fn fold_expr(&mut self, expr: Expr) -> Expr {
+            (self.0)(expr)
+        }
+    }
+
+    walk_fold(&mut MapChildren(f), expr)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::{eval_with, Context, EvalPolicy, Value},
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn eliminate_str(input: &str, functions: &Functions) -> Eliminated {
+        let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        eliminate(&expr, functions)
+    }
+
+    #[test]
+    fn test_eliminate_repeated() {
+        let result = eliminate_str("(a + b) * (a + b) > (a + b)", &Functions::default());
+        assert_eq!(result.expr.to_string(), "(let $0 = (a + b) in (($0 * $0) > $0))");
+        assert_eq!(result.changes, vec!["$0 = (a + b)"]);
+    }
+
+    #[test]
+    fn test_eliminate_largest_first() {
+        let result = eliminate_str("sqrt(a * b + 1) - sqrt(a * b + 1) * (a * b)", &Functions::default());
+        assert_eq!(
+            result.expr.to_string(),
+            "(let $1 = (a * b) in (let $0 = sqrt(($1 + 1)) in ($0 - ($0 * $1))))"
+        );
+    }
+
+    #[test]
+    fn test_impure_not_eliminated() {
+        let mut functions = Functions::default();
+        functions.register("rand", false, |_| Ok(Value::Number(4.0)));
+        let result = eliminate_str("rand() + 1 == rand() + 1", &functions);
+        assert_eq!(result.expr.to_string(), "((rand() + 1) == (rand() + 1))");
+        assert!(result.changes.is_empty());
+    }
+
+    #[test]
+    fn test_conditional_branches() {
+        // 1 / x 只在右边出现, 不能提到 && 外面
+        let result = eliminate_str("x != 0 && 1 / x > 1 / x - 1", &Functions::default());
+        assert_eq!(result.expr.to_string(), "((x != 0) && (let $0 = (1 / x) in ($0 > ($0 - 1))))");
+
+        // 左边一定会求值, 右边可以复用
+        let result = eliminate_str("x * 2 > 1 && x * 2 < 5", &Functions::default());
+        assert_eq!(result.expr.to_string(), "(let $0 = (x * 2) in (($0 > 1) && ($0 < 5)))");
+    }
+
+    #[test]
+    fn test_large_input() {
+        // 每个重复的子表达式只统计一次, 几千个节点也很快
+        let source = vec!["(x * y + 1) * (x * y)"; 500].join(" + ");
+        let result = eliminate_str(&source, &Functions::default());
+        assert_eq!(result.changes, vec!["$0 = (((x * y) + 1) * (x * y))", "$1 = (x * y)"]);
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0).set("y", 3.0);
+        assert_eq!(eval_with(&result.expr, &ctx, &EvalPolicy::default()).unwrap(), Value::Number(500.0 * 42.0));
+    }
+
+    // 消除前后的求值结果必须完全一致
+    #[test]
+    fn test_differential_random() {
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0).set("s", "a");
+        let policy = EvalPolicy::default();
+        let mut rng = Rng(0xD1B54A32D192ED03);
+        for _ in 0..3000 {
+            // 用同一个子表达式拼出有重复的表达式
+            let depth = 1 + rng.below(3) as u32;
+            let common = random_expr(&mut rng, depth);
+            let other = random_expr(&mut rng, depth);
+            let ops = [BinaryOp::Add, BinaryOp::Mul, BinaryOp::And, BinaryOp::Or, BinaryOp::Lt];
+            let mut op = || ops[rng.below(ops.len() as u64) as usize];
+            let inner = Expr::BinaryOp { left: Box::new(common.clone()), op: op(), right: Box::new(other) };
+            let expr = Expr::BinaryOp { left: Box::new(inner), op: op(), right: Box::new(common) };
+
+            let eliminated = eliminate(&expr, ctx.functions()).expr;
+            let before = eval_with(&expr, &ctx, &policy);
+            let after = eval_with(&eliminated, &ctx, &policy);
+            match (&before, &after) {
+                (Ok(a), Ok(b)) => assert_eq!(format!("{a:?}"), format!("{b:?}"), "{expr} => {eliminated}"),
+                (Err(_), Err(_)) => {}
+                _ => panic!("结果不一致: {expr} => {eliminated}\n  {before:?}\n  {after:?}"),
+            }
+        }
+    }
+}
@@ -0,0 +1,243 @@
+// 符号求导
+//
+// 求导按实数上的数学规则进行, 生成的表达式在构造时就做 0 * e => 0 这类化简,
+// 这些化简假设变量取有限实数值 (optimize 不会做, 因为 NaN * 0 不是 0).
+// floor/ceil/round 的导数按几乎处处为 0 处理; min/max/比较/bool 运算没有导数.
+use crate::{
+    ast::{BinaryOp, Expr},
+    eval::EvalPolicy,
+    optimize::optimize,
+};
+
+pub fn diff(expr: &Expr, var: &str) -> anyhow::Result<Expr> {
+    let derivative = derive(expr, var)?;
+    Ok(optimize(&derivative, &EvalPolicy::default()).expr)
+}
+
+fn derive(expr: &Expr, var: &str) -> anyhow::Result<Expr> {
+    Ok(match expr {
+        Expr::Number(_) => num(0.0),
+        Expr::Var(name) => num(if name == var { 1.0 } else { 0.0 }),
+        Expr::UnaryOp { op: BinaryOp::Sub, expr } => neg(derive(expr, var)?),
+        Expr::UnaryOp { op: BinaryOp::Add, expr } => derive(expr, var)?,
+        Expr::BinaryOp { left: u, op, right: v } => match op {
+            BinaryOp::Add => add(derive(u, var)?, derive(v, var)?),
+            BinaryOp::Sub => sub(derive(u, var)?, derive(v, var)?),
+            // (uv)' = u'v + uv'
+            BinaryOp::Mul => add(mul(derive(u, var)?, (**v).clone()), mul((**u).clone(), derive(v, var)?)),
+            // (u/v)' = (u'v - uv') / v^2
+            BinaryOp::Div => div(
+                sub(mul(derive(u, var)?, (**v).clone()), mul((**u).clone(), derive(v, var)?)),
+                pow((**v).clone(), num(2.0)),
+            ),
+            BinaryOp::Pow => derive_pow(u, v, var)?,
+            _ => anyhow::bail!("无法求导: {expr}"),
+        },
+        Expr::Call { name, args } => derive_call(name, args, var)?,
+        Expr::Let { name, value, body } => derive(&body.substitute(name, value), var)?,
+        _ => anyhow::bail!("无法求导: {expr}"),
+    })
+}
+
+fn derive_pow(u: &Expr, v: &Expr, var: &str) -> anyhow::Result<Expr> {
+    let (du, dv) = (derive(u, var)?, derive(v, var)?);
+    Ok(if is_zero(&dv) {
+        // (u^c)' = c * u^(c - 1) * u'
+        mul(mul(v.clone(), pow(u.clone(), sub(v.clone(), num(1.0)))), du)
+    } else if is_zero(&du) {
+        // (a^v)' = a^v * ln(a) * v'
+        mul(mul(pow(u.clone(), v.clone()), call("ln", vec![u.clone()])), dv)
+    } else {
+        // (u^v)' = u^v * (v' * ln(u) + v * u' / u)
+        mul(
+            pow(u.clone(), v.clone()),
+            add(mul(dv, call("ln", vec![u.clone()])), div(mul(v.clone(), du), u.clone())),
+        )
+    })
+}
+
+fn derive_call(name: &str, args: &[Expr], var: &str) -> anyhow::Result<Expr> {
+    if name == "pow" {
+        let [u, v] = args else { anyhow::bail!("函数 pow 需要 2 个参数") };
+        return derive_pow(u, v, var);
+    }
+    // 导数要看哪一边更大, 语法树里没有条件表达式, 写不出来; dual.rs 在具体的点上求值, 可以求
+    if let "min" | "max" = name {
+        anyhow::bail!("无法求导: 函数 {name} 的导数分段, 请用 dual 在具体的点上求值");
+    }
+    let [u] = args else { anyhow::bail!("无法求导: {name} 的参数个数不对") };
+    let du = derive(u, var)?;
+    let u = u.clone();
+    // 链式法则: f(u)' = f'(u) * u'
+    let outer = match name {
+        "abs" => div(u.clone(), call("abs", vec![u])),
+        "sqrt" => div(num(1.0), mul(num(2.0), call("sqrt", vec![u]))),
+        "exp" => call("exp", vec![u]),
+        "ln" => div(num(1.0), u),
+        "log10" => div(num(1.0), mul(u, call("ln", vec![num(10.0)]))),
+        "sin" => call("cos", vec![u]),
+        "cos" => neg(call("sin", vec![u])),
+        "tan" => div(num(1.0), pow(call("cos", vec![u]), num(2.0))),
+        "floor" | "ceil" | "round" => num(0.0),
+        _ => anyhow::bail!("无法求导: 函数 {name}"),
+    };
+    Ok(mul(outer, du))
+}
+
+fn num(n: f64) -> Expr {
+    Expr::Number(n)
+}
+
+fn is_zero(e: &Expr) -> bool {
+    matches!(e, Expr::Number(n) if *n == 0.0)
+}
+
+fn is_one(e: &Expr) -> bool {
+    matches!(e, Expr::Number(n) if *n == 1.0)
+}
+
+fn binary(left: Expr, op: BinaryOp, right: Expr) -> Expr {
+    Expr::BinaryOp { left: Box::new(left), op, right: Box::new(right) }
+}
+
+fn call(name: &str, args: Vec<Expr>) -> Expr {
+    Expr::Call { name: name.to_string(), args }
+}
+
+fn neg(e: Expr) -> Expr {
+    match e {
+        Expr::Number(n) => num(-n),
+        Expr::UnaryOp { op: BinaryOp::Sub, expr } => *expr,
+        e => Expr::UnaryOp { op: BinaryOp::Sub, expr: Box::new(e) },
+    }
+}
+
+fn add(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, b) if is_zero(&a) => b,
+        (a, b) if is_zero(&b) => a,
+        (Expr::Number(x), Expr::Number(y)) => num(x + y),
+        (a, Expr::UnaryOp { op: BinaryOp::Sub, expr }) => sub(a, *expr),
+        (a, b) => binary(a, BinaryOp::Add, b),
+    }
+}
+
+fn sub(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, b) if is_zero(&b) => a,
+        (a, b) if is_zero(&a) => neg(b),
+        (Expr::Number(x), Expr::Number(y)) => num(x - y),
+        (a, b) => binary(a, BinaryOp::Sub, b),
+    }
+}
+
+fn mul(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, b) if is_zero(&a) || is_zero(&b) => num(0.0),
+        (a, b) if is_one(&a) => b,
+        (a, b) if is_one(&b) => a,
+        (Expr::Number(x), Expr::Number(y)) => num(x * y),
+        (Expr::UnaryOp { op: BinaryOp::Sub, expr }, b) => neg(mul(*expr, b)),
+        (a, Expr::UnaryOp { op: BinaryOp::Sub, expr }) => neg(mul(a, *expr)),
+        // 常数放在前面: x * 2 => 2 * x
+        (a, b @ Expr::Number(_)) => binary(b, BinaryOp::Mul, a),
+        (a, b) => binary(a, BinaryOp::Mul, b),
+    }
+}
+
+fn div(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (a, _) if is_zero(&a) => num(0.0),
+        (a, b) if is_one(&b) => a,
+        (a, b) => binary(a, BinaryOp::Div, b),
+    }
+}
+
+fn pow(a: Expr, b: Expr) -> Expr {
+    match (a, b) {
+        (_, b) if is_zero(&b) => num(1.0),
+        (a, b) if is_one(&b) => a,
+        (Expr::Number(x), Expr::Number(y)) => num(x.powf(y)),
+        (a, b) => binary(a, BinaryOp::Pow, b),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::{eval_with, Context, Value},
+        lexer::tokenize,
+        parser::Parser,
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    fn diff_str(input: &str) -> String {
+        diff(&parse(input), "x").unwrap().to_string()
+    }
+
+    #[test]
+    fn test_polynomial() {
+        assert_eq!(diff_str("x ^ 3 + 2 * x + 7"), "((3 * (x ^ 2)) + 2)");
+        assert_eq!(diff_str("x * x"), "(x + x)");
+        assert_eq!(diff_str("y * x"), "y");
+        assert_eq!(diff_str("5 - x"), "-1");
+    }
+
+    #[test]
+    fn test_quotient_and_power() {
+        assert_eq!(diff_str("1 / x"), "(-1 / (x ^ 2))");
+        assert_eq!(diff_str("2 ^ x"), "((2 ^ x) * ln(2))");
+        assert_eq!(diff_str("x ^ x"), "((x ^ x) * (ln(x) + (x / x)))");
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(diff_str("sin(x)"), "cos(x)");
+        assert_eq!(diff_str("cos(2 * x)"), "(-(2 * sin((2 * x))))");
+        assert_eq!(diff_str("exp(x ^ 2)"), "(exp((x ^ 2)) * (2 * x))");
+        assert_eq!(diff_str("ln(x)"), "(1 / x)");
+        assert_eq!(diff_str("sqrt(x)"), "(1 / (2 * sqrt(x)))");
+        // min/max 只有 dual 支持
+        assert_eq!(diff(&parse("max(x, 1)"), "x").unwrap_err().to_string(), "无法求导: 函数 max 的导数分段, 请用 dual 在具体的点上求值");
+        assert!(diff(&parse("x > 1"), "x").is_err());
+    }
+
+    // 和数值差分比较
+    #[test]
+    fn test_matches_finite_difference() {
+        let inputs = [
+            "x ^ 3 - 2 * x",
+            "sin(x) * cos(x)",
+            "exp(x) / (1 + x ^ 2)",
+            "sqrt(x) * ln(x)",
+            "x ^ x",
+            "tan(x) + log10(x) - abs(x)",
+            "pow(x, 2.5) + 3 ^ x",
+        ];
+        let policy = EvalPolicy::default();
+        for input in inputs {
+            let expr = parse(input);
+            let derivative = diff(&expr, "x").unwrap();
+            for x in [0.3, 0.7, 1.9] {
+                let at = |x: f64| {
+                    let mut ctx = Context::new();
+                    ctx.set("x", x);
+                    ctx
+                };
+                let value = |e: &Expr, x: f64| match eval_with(e, &at(x), &policy).unwrap() {
+                    Value::Number(n) => n,
+                    v => panic!("{v}"),
+                };
+                let h = 1e-6;
+                let numeric = (value(&expr, x + h) - value(&expr, x - h)) / (2.0 * h);
+                let symbolic = value(&derivative, x);
+                assert!((numeric - symbolic).abs() < 1e-5, "{input}: {derivative} at {x}: {numeric} vs {symbolic}");
+            }
+        }
+    }
+}
@@ -0,0 +1,273 @@
+// 前向自动求导: 用对偶数求值, 同时得到结果和对指定变量的偏导数
+//
+// 每个数都带着一个梯度向量, 第 i 项是对 vars[i] 的偏导. 只支持数字运算和内置数学函数,
+// 比较, bool 运算, 列表和宿主函数没有导数, 会报错.
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+use crate::{
+    ast::{BinaryOp, Expr},
+    eval::Context,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dual {
+    pub value: f64,
+    pub grad: Vec<f64>, // 与传入的变量顺序一致
+}
+
+impl Dual {
+    pub fn constant(value: f64, n: usize) -> Self {
+        Dual { value, grad: vec![0.0; n] }
+    }
+
+    // 第 i 个自变量: 对自己的偏导是 1
+    pub fn variable(value: f64, i: usize, n: usize) -> Self {
+        let mut grad = vec![0.0; n];
+        grad[i] = 1.0;
+        Dual { value, grad }
+    }
+
+    // 链式法则: f(u)' = f'(u) * u'
+    fn chain(&self, value: f64, derivative: f64) -> Dual {
+        Dual { value, grad: self.grad.iter().map(|g| g * derivative).collect() }
+    }
+
+    fn is_constant(&self) -> bool {
+        self.grad.iter().all(|g| *g == 0.0)
+    }
+
+    pub fn powd(&self, exp: &Dual) -> Dual {
+        let value = self.value.powf(exp.value);
+        if exp.is_constant() {
+            // (u^c)' = c * u^(c - 1) * u', u 为负数时也成立
+            return self.chain(value, exp.value * self.value.powf(exp.value - 1.0));
+        }
+        // (u^v)' = u^v * (v' * ln(u) + v * u' / u)
+        let ln = self.value.ln();
+        let grad = self
+            .grad
+            .iter()
+            .zip(&exp.grad)
+            .map(|(du, dv)| {
+                let from_base = if *du == 0.0 { 0.0 } else { exp.value * du / self.value };
+                value * (dv * ln + from_base)
+            })
+            .collect();
+        Dual { value, grad }
+    }
+}
+
+impl Add for Dual {
+    type Output = Dual;
+
+    fn add(self, rhs: Dual) -> Dual {
+        let grad = self.grad.iter().zip(&rhs.grad).map(|(a, b)| a + b).collect();
+        Dual { value: self.value + rhs.value, grad }
+    }
+}
+
+impl Sub for Dual {
+    type Output = Dual;
+
+    fn sub(self, rhs: Dual) -> Dual {
+        let grad = self.grad.iter().zip(&rhs.grad).map(|(a, b)| a - b).collect();
+        Dual { value: self.value - rhs.value, grad }
+    }
+}
+
+impl Mul for Dual {
+    type Output = Dual;
+
+    // 乘法法则: (uv)' = u'v + uv'
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn mul(self, rhs: Dual) -> Dual {
+        let grad = self.grad.iter().zip(&rhs.grad).map(|(a, b)| a * rhs.value + self.value * b).collect();
+        Dual { value: self.value * rhs.value, grad }
+    }
+}
+
+impl Div for Dual {
+    type Output = Dual;
+
+    // 除法法则: (u/v)' = (u'v - uv') / v^2
+    #[allow(clippy::suspicious_arithmetic_impl)]
+    fn div(self, rhs: Dual) -> Dual {
+        let v2 = rhs.value * rhs.value;
+        let grad = self.grad.iter().zip(&rhs.grad).map(|(a, b)| (a * rhs.value - self.value * b) / v2).collect();
+        Dual { value: self.value / rhs.value, grad }
+    }
+}
+
+impl Neg for Dual {
+    type Output = Dual;
+
+    fn neg(self) -> Dual {
+        Dual { value: -self.value, grad: self.grad.iter().map(|g| -g).collect() }
+    }
+}
+
+// 在 ctx 中求值, 同时求对 vars 的偏导; 其他变量按常数处理
+pub fn gradient(expr: &Expr, ctx: &Context, vars: &[&str]) -> anyhow::Result<Dual> {
+    let mut scope = Vec::new();
+    DualEval { ctx, vars }.eval(expr, &mut scope)
+}
+
+struct DualEval<'a> {
+    ctx: &'a Context,
+    vars: &'a [&'a str],
+}
+
+impl DualEval<'_> {
+    fn eval(&self, expr: &Expr, scope: &mut Vec<(String, Dual)>) -> anyhow::Result<Dual> {
+        let n = self.vars.len();
+        Ok(match expr {
+            Expr::Number(v) => Dual::constant(*v, n),
+            Expr::Var(name) => {
+                if let Some((_, d)) = scope.iter().rev().find(|(s, _)| s == name) {
+                    return Ok(d.clone());
+                }
+                let value = f64::try_from(self.ctx.lookup(name)?)?;
+                match self.vars.iter().position(|v| v == name) {
+                    Some(i) => Dual::variable(value, i, n),
+                    None => Dual::constant(value, n),
+                }
+            }
+            Expr::UnaryOp { op: BinaryOp::Sub, expr } => -self.eval(expr, scope)?,
+            Expr::UnaryOp { op: BinaryOp::Add, expr } => self.eval(expr, scope)?,
+            Expr::BinaryOp { left, op, right } => {
+                let (l, r) = (self.eval(left, scope)?, self.eval(right, scope)?);
+                match op {
+                    BinaryOp::Add => l + r,
+                    BinaryOp::Sub => l - r,
+                    BinaryOp::Mul => l * r,
+                    BinaryOp::Div => {
+                        if r.value == 0.0 {
+                            anyhow::bail!("除以零错误");
+                        }
+                        l / r
+                    }
+                    BinaryOp::Pow => l.powd(&r),
+                    _ => anyhow::bail!("无法求导: {expr}"),
+                }
+            }
+            Expr::Call { name, args } => {
+                let args = args.iter().map(|a| self.eval(a, scope)).collect::<anyhow::Result<Vec<_>>>()?;
+                call(name, &args)?
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.eval(value, scope)?;
+                scope.push((name.clone(), value));
+                let result = self.eval(body, scope);
+                scope.pop();
+                result?
+            }
+            _ => anyhow::bail!("无法求导: {expr}"),
+        })
+    }
+}
+
+fn call(name: &str, args: &[Dual]) -> anyhow::Result<Dual> {
+    if let [a, b] = args {
+        return Ok(match name {
+            "pow" => a.powd(b),
+            // 取较大或较小的一边, 相等时取左边
+            "min" => if a.value <= b.value { a.clone() } else { b.clone() },
+            "max" => if a.value >= b.value { a.clone() } else { b.clone() },
+            _ => anyhow::bail!("无法求导: 函数 {name}"),
+        });
+    }
+    let [u] = args else { anyhow::bail!("无法求导: {name} 的参数个数不对") };
+    let x = u.value;
+    Ok(match name {
+        "abs" => u.chain(x.abs(), if x == 0.0 { 0.0 } else { x.signum() }),
+        "sqrt" => u.chain(x.sqrt(), 0.5 / x.sqrt()),
+        "exp" => u.chain(x.exp(), x.exp()),
+        "ln" => u.chain(x.ln(), 1.0 / x),
+        "log10" => u.chain(x.log10(), 1.0 / (x * std::f64::consts::LN_10)),
+        "sin" => u.chain(x.sin(), x.cos()),
+        "cos" => u.chain(x.cos(), -x.sin()),
+        "tan" => u.chain(x.tan(), 1.0 / (x.cos() * x.cos())),
+        // 阶梯函数几乎处处导数为 0
+        "floor" => u.chain(x.floor(), 0.0),
+        "ceil" => u.chain(x.ceil(), 0.0),
+        "round" => u.chain(x.round(), 0.0),
+        _ => anyhow::bail!("无法求导: 函数 {name}"),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diff::diff,
+        eval::{eval_with, EvalPolicy, Value},
+        lexer::tokenize,
+        parser::Parser,
+    };
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_gradient() {
+        let mut ctx = Context::new();
+        ctx.set("x", 3.0).set("y", 2.0).set("k", 10.0);
+        let result = gradient(&parse("x * x * y + k * y"), &ctx, &["x", "y"]).unwrap();
+        assert_eq!(result, Dual { value: 38.0, grad: vec![12.0, 19.0] });
+
+        // 没有列出的变量按常数处理
+        let result = gradient(&parse("k * x ^ 2"), &ctx, &["x"]).unwrap();
+        assert_eq!(result, Dual { value: 90.0, grad: vec![60.0] });
+    }
+
+    #[test]
+    fn test_gradient_errors() {
+        let mut ctx = Context::new();
+        ctx.set("x", 0.0);
+        assert!(gradient(&parse("1 / x"), &ctx, &["x"]).is_err());
+        assert!(gradient(&parse("x > 1"), &ctx, &["x"]).is_err());
+        assert!(gradient(&parse("y + x"), &ctx, &["x"]).is_err());
+        assert!(gradient(&parse("x + \"a\""), &ctx, &["x"]).is_err());
+    }
+
+    #[test]
+    fn test_let_binding() {
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0);
+        let expr = crate::cse::eliminate(&parse("(x + 1) * (x + 1)"), ctx.functions()).expr;
+        assert_eq!(gradient(&expr, &ctx, &["x"]).unwrap(), Dual { value: 9.0, grad: vec![6.0] });
+    }
+
+    // 和符号求导的结果一致
+    #[test]
+    fn test_matches_symbolic() {
+        let inputs = [
+            "x ^ 3 - 2 * x * y",
+            "sin(x) * cos(y)",
+            "exp(x * y) / (1 + x ^ 2)",
+            "sqrt(x) * ln(y)",
+            "x ^ y + y ^ x",
+            "tan(x) + log10(y) - abs(x - y)",
+            "pow(x, 2.5) + 3 ^ y - floor(x)",
+        ];
+        let policy = EvalPolicy::default();
+        for input in inputs {
+            let expr = parse(input);
+            for (x, y) in [(0.3, 1.2), (0.7, 0.4), (1.9, 2.5)] {
+                let mut ctx = Context::new();
+                ctx.set("x", x).set("y", y);
+                let result = gradient(&expr, &ctx, &["x", "y"]).unwrap();
+                let Value::Number(value) = eval_with(&expr, &ctx, &policy).unwrap() else { panic!() };
+                assert!((result.value - value).abs() < 1e-12, "{input}");
+                for (i, var) in ["x", "y"].into_iter().enumerate() {
+                    let Value::Number(d) = eval_with(&diff(&expr, var).unwrap(), &ctx, &policy).unwrap() else {
+                        panic!()
+                    };
+                    assert!((result.grad[i] - d).abs() < 1e-9, "{input} d/d{var} at ({x}, {y})");
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+// 编译一次, 多次求值
+use crate::{
+    ast::Expr,
+    compiler::{compile, Chunk},
+    cse::eliminate,
+    eval::{Context, EvalPolicy, Value},
+    functions::Functions,
+    lexer::tokenize,
+    optimize::optimize,
+    parser::{Parser, ParserOptions},
+    vm,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Engine {
+    pub parser_options: ParserOptions,
+    pub policy: EvalPolicy,
+    pub functions: Functions,
+}
+
+impl Engine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_policy(policy: EvalPolicy) -> Self {
+        Engine { policy, ..Default::default() }
+    }
+
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        pure: bool,
+        f: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.functions.register(name, pure, f);
+        self
+    }
+
+    // 词法分析 -> 语法分析 -> 化简 -> 公共子表达式消除 -> 变量和函数解析成槽位 -> 字节码, 只做一次
+    pub fn compile(&self, input: &str) -> anyhow::Result<CompiledExpr> {
+        let tokens = tokenize(input)?;
+        let expr = Parser::with_options(tokens, self.parser_options.clone()).parse_program()?;
+        let optimized = optimize(&expr, &self.policy);
+        let eliminated = eliminate(&optimized.expr, &self.functions);
+        let chunk = compile(&eliminated.expr, &self.functions)?;
+        let mut optimizations = optimized.changes;
+        optimizations.extend(eliminated.changes);
+        Ok(CompiledExpr { expr: eliminated.expr, chunk, policy: self.policy.clone(), optimizations })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompiledExpr {
+    expr: Expr, // 化简后的表达式
+    chunk: Chunk,
+    policy: EvalPolicy,
+    optimizations: Vec<String>,
+}
+
+impl CompiledExpr {
+    // 只执行字节码, 函数在编译时已经解析, 只从 ctx 中读取变量; 纯数字表达式在热路径上不分配内存
+    pub fn eval(&self, ctx: &Context) -> anyhow::Result<Value> {
+        vm::run(&self.chunk, ctx, &self.policy)
+    }
+
+    // 把 contexts 分成若干段, 每段在一个线程里求值; 结果按输入顺序排列, 每行单独返回错误.
+    // 宿主函数 panic 时只有那一行返回错误
+    pub fn eval_parallel(&self, contexts: &[Context]) -> Vec<anyhow::Result<Value>> {
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        self.eval_parallel_with(contexts, threads)
+    }
+
+    pub fn eval_parallel_with(&self, contexts: &[Context], threads: usize) -> Vec<anyhow::Result<Value>> {
+        let size = contexts.len().div_ceil(threads.max(1)).max(1);
+        if size >= contexts.len() {
+            return contexts.iter().map(|ctx| self.eval_row(ctx)).collect();
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = contexts
+                .chunks(size)
+                .map(|chunk| scope.spawn(move || chunk.iter().map(|ctx| self.eval_row(ctx)).collect::<Vec<_>>()))
+                .collect();
+            // 每一行的 panic 已经在 eval_row 里接住, 线程本身不会 panic
+            handles.into_iter().flat_map(|h| h.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))).collect()
+        })
+    }
+
+    fn eval_row(&self, ctx: &Context) -> anyhow::Result<Value> {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| self.eval(ctx)))
+            .unwrap_or_else(|panic| Err(anyhow::anyhow!("求值 panic: {}", panic_message(&*panic))))
+    }
+
+    pub fn expr(&self) -> &Expr {
+        &self.expr
+    }
+
+    pub fn chunk(&self) -> &Chunk {
+        &self.chunk
+    }
+
+    pub fn optimizations(&self) -> &[String] {
+        &self.optimizations
+    }
+
+    // 表达式用到的变量, 按槽位顺序
+    pub fn variables(&self) -> &[String] {
+        &self.chunk.names
+    }
+}
+
+// panic 的参数通常是 &str 或 String
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("未知错误")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_and_eval() {
+        let engine = Engine::new();
+        let compiled = engine.compile("x * x + y > 10 && x in 1..10").unwrap();
+        assert_eq!(compiled.variables(), ["x", "y"]);
+
+        let mut ctx = Context::new();
+        ctx.set("x", 3.0).set("y", 2.0);
+        assert_eq!(compiled.eval(&ctx).unwrap(), Value::Bool(true));
+        ctx.set("y", 1.0);
+        assert_eq!(compiled.eval(&ctx).unwrap(), Value::Bool(false));
+        assert!(compiled.eval(&Context::new()).is_err());
+
+        // 没有定义的变量只在用到时报错
+        let compiled = engine.compile("y > 0 && x").unwrap();
+        let mut ctx = Context::new();
+        ctx.set("y", 0.0);
+        assert_eq!(compiled.eval(&ctx).unwrap(), Value::Bool(false));
+        ctx.set("y", 1.0);
+        assert_eq!(compiled.eval(&ctx).unwrap_err().to_string(), "未定义的变量: x");
+    }
+
+    #[test]
+    fn test_compile_optimizes() {
+        let compiled = Engine::new().compile("2 * 3 + x * 1 + 0").unwrap();
+        assert_eq!(compiled.expr().to_string(), "(6 + x)");
+        assert_eq!(compiled.optimizations().len(), 3);
+        let mut ctx = Context::new();
+        ctx.set("x", 1.0);
+        assert_eq!(compiled.eval(&ctx).unwrap(), Value::Number(7.0));
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let counter = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut engine = Engine::new();
+        let c = counter.clone();
+        engine.register_fn("tick", false, move |_| {
+            Ok(Value::Number(c.fetch_add(1, std::sync::atomic::Ordering::SeqCst) as f64))
+        });
+        engine.register_fn("twice", true, |args| Ok(Value::Number(f64::try_from(&args[0])? * 2.0)));
+
+        let compiled = engine.compile("twice(x) + twice(x) + tick() + tick()").unwrap();
+        assert_eq!(compiled.expr().to_string(), "(let $0 = twice(x) in ((($0 + $0) + tick()) + tick()))");
+        let mut ctx = Context::new();
+        ctx.set("x", 1.0);
+        assert_eq!(compiled.eval(&ctx).unwrap(), Value::Number(5.0));
+        assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert!(engine.compile("nope(1)").is_err());
+    }
+
+    #[test]
+    fn test_engine_options() {
+        let mut engine = Engine::new();
+        engine.parser_options.strict_comparisons = true;
+        assert!(engine.compile("1 < 2 < 3").is_err());
+        assert!(engine.compile("1 +").is_err());
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Engine>();
+        assert_send_sync::<CompiledExpr>();
+        assert_send_sync::<Expr>();
+        assert_send_sync::<Context>();
+    }
+
+    #[test]
+    fn test_eval_parallel() {
+        let compiled = Engine::new().compile("10 / (x - 3) + x").unwrap();
+        let contexts: Vec<Context> = (0..1000)
+            .map(|i| {
+                let mut ctx = Context::new();
+                ctx.set("x", (i % 7) as f64);
+                ctx
+            })
+            .collect();
+        let expected: Vec<String> = contexts.iter().map(|ctx| format!("{:?}", compiled.eval(ctx).ok())).collect();
+        for threads in [1, 3, 8, 2000] {
+            let results = compiled.eval_parallel_with(&contexts, threads);
+            let results: Vec<String> = results.iter().map(|r| format!("{:?}", r.as_ref().ok())).collect();
+            assert_eq!(results, expected);
+        }
+        // x = 3 的行单独报错
+        let results = compiled.eval_parallel(&contexts);
+        assert!(results[3].is_err());
+        assert_eq!(results[4].as_ref().unwrap(), &Value::Number(14.0));
+        assert!(compiled.eval_parallel(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_eval_parallel_panic() {
+        let mut engine = Engine::new();
+        engine.register_fn("boom", false, |args| {
+            if args[0] == Value::Number(5.0) {
+                panic!("x 是 5");
+            }
+            Ok(args[0].clone())
+        });
+        let compiled = engine.compile("boom(x)").unwrap();
+        let contexts: Vec<Context> = (0..8)
+            .map(|i| {
+                let mut ctx = Context::new();
+                ctx.set("x", i as f64);
+                ctx
+            })
+            .collect();
+        // 每段 2 行, 只有 x = 5 这一行出错, 同一段的 x = 4 正常
+        for threads in [1, 4] {
+            let results = compiled.eval_parallel_with(&contexts, threads);
+            assert_eq!(results.len(), 8);
+            assert_eq!(results[5].as_ref().unwrap_err().to_string(), "求值 panic: x 是 5");
+            for (i, result) in results.iter().enumerate().filter(|(i, _)| *i != 5) {
+                assert_eq!(result.as_ref().unwrap(), &Value::Number(i as f64));
+            }
+        }
+    }
+}
@@ -1,42 +1,140 @@
-use std::{fmt::Display, ops::{Add, Div, Mul, Sub}};
+use std::{cmp::Ordering, collections::HashMap, fmt::Display, ops::{Add, Div, Mul, Sub}};
 
 // 求值器
-use crate::ast::{BinaryOp, Expr};
+use crate::{ast::{BinaryOp, Expr}, functions::{Function, Functions}};
+
+// 比较规则:
+//   数字和数字: 按 float_eq 判断相等; NaN 和任何值都不相等, 也没有大小
+//   bool 和 bool: false < true
+//   数字和 bool: bool 按 0/1 转换成数字, 和算术运算保持一致 (1 == true)
+//   字符串和字符串: 按 string_order 比较
+//   列表和列表: 逐个元素判断相等, 不能比较大小
+//   其它类型组合: == 为 false, != 为 true, 比较大小是类型错误
+//
+// 浮点规则:
+//   non_finite: 算术结果是 inf/NaN 时是否报错
+//   div_by_zero: 除以零报错, 还是按 IEEE 754 得到 inf/NaN
+#[derive(Debug, Clone, Default)]
+pub struct EvalPolicy {
+    pub float_eq: FloatEq,
+    pub string_order: StringOrder,
+    pub non_finite: NonFinite,
+    pub div_by_zero: DivByZero,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FloatEq {
+    // 完全相等
+    #[default]
+    Exact,
+    // |a - b| <= eps
+    Abs(f64),
+    // |a - b| <= eps * max(|a|, |b|)
+    Rel(f64),
+}
+
+impl FloatEq {
+    // 先比较是否完全相等: inf - inf 是 NaN, 否则 inf 和自己不相等.
+    // 相对误差在 inf 和有限值之间也会是 inf <= inf, 所以只对有限值用
+    pub fn approx_eq(&self, a: f64, b: f64) -> bool {
+        match self {
+            FloatEq::Exact => a == b,
+            FloatEq::Abs(eps) => a == b || (a - b).abs() <= *eps,
+            FloatEq::Rel(eps) => a == b || (a.is_finite() && b.is_finite() && (a - b).abs() <= eps * a.abs().max(b.abs())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NonFinite {
+    #[default]
+    Allow,
+    Error,
+}
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DivByZero {
+    #[default]
+    Error,
+    Infinity,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StringOrder {
+    // 按 unicode 码点逐个比较
+    #[default]
+    Lexicographic,
+    // 忽略大小写比较, 相同时再按码点区分; 没有区域数据时最接近 locale 排序的方式
+    IgnoreCase,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Number(f64),
     Bool(bool),
+    Str(String),
+    // 惰性范围, 不展开成列表; 元素为 start, start + 1, ... (不超过 end)
+    Range { start: f64, end: f64, inclusive: bool },
+    List(Vec<Value>),
 }
 
 impl Value {
-    fn and(&self, right: &Value) -> anyhow::Result<bool> {
-        match (self, right) {
-            (Value::Number(a), Value::Number(b)) => {
-                if *a > 0.0 && *b > 0.0 {
-                    return Ok(true);
+    // 范围/列表的成员判断. 还没有 map 类型, 所以不支持 "k" in map
+    fn contains(&self, item: &Value, policy: &EvalPolicy) -> anyhow::Result<bool> {
+        match self {
+            Value::Range { start, end, inclusive } => {
+                let n = f64::try_from(item)?;
+                let in_bounds = n >= *start && if *inclusive { n <= *end } else { n < *end };
+                Ok(in_bounds && (n - start).fract() == 0.0)
+            }
+            Value::List(items) => {
+                for i in items {
+                    if item.equals(i, policy) {
+                        return Ok(true);
+                    }
                 }
-
                 Ok(false)
-            },
-            (Value::Number(a), Value::Bool(b)) => Ok(*a > 0.0 && *b),
-            (Value::Bool(a), Value::Bool(b)) => Ok(*a && *b),
-            (Value::Bool(a), Value::Number(b)) => Ok(*a && *b > 0.0),
+            }
+            _ => anyhow::bail!("`in` 右边必须是范围或列表 (还不支持 map): {self}"),
+        }
+    }
+
+    pub fn equals(&self, other: &Value, policy: &EvalPolicy) -> bool {
+        match (self, other) {
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(x, y)| x.equals(y, policy))
+            }
+            (Value::Range { .. }, Value::Range { .. }) => self == other,
+            _ => matches!(self.compare(other, policy), Ok(Some(Ordering::Equal))),
         }
     }
 
-    fn or(&self, right: &Value) -> anyhow::Result<bool> {
-        match (self, right) {
-            (Value::Number(a), Value::Number(b)) => {
-                if *a > 0.0 || *b > 0.0 {
-                    return Ok(true);
+    // 返回 None 表示无法排序 (NaN)
+    pub fn compare(&self, other: &Value, policy: &EvalPolicy) -> anyhow::Result<Option<Ordering>> {
+        match (self, other) {
+            (Value::Number(_) | Value::Bool(_), Value::Number(_))
+            | (Value::Number(_), Value::Bool(_)) => {
+                let (a, b) = (f64::try_from(self)?, f64::try_from(other)?);
+                if policy.float_eq.approx_eq(a, b) {
+                    return Ok(Some(Ordering::Equal));
                 }
+                Ok(a.partial_cmp(&b))
+            }
+            (Value::Bool(a), Value::Bool(b)) => Ok(Some(a.cmp(b))),
+            (Value::Str(a), Value::Str(b)) => Ok(Some(match policy.string_order {
+                StringOrder::Lexicographic => a.cmp(b),
+                StringOrder::IgnoreCase => a.to_lowercase().cmp(&b.to_lowercase()).then_with(|| a.cmp(b)),
+            })),
+            _ => anyhow::bail!("无法比较大小: {self} 和 {other}"),
+        }
+    }
 
-                Ok(false)
-            },
-            (Value::Number(a), Value::Bool(b)) => Ok(*a > 0.0 || *b),
-            (Value::Bool(a), Value::Bool(b)) => Ok(*a || *b),
-            (Value::Bool(a), Value::Number(b)) => Ok(*a || *b > 0.0),
+    // && 和 || 使用的真值: 数字大于 0 为真
+    pub fn truthy(&self) -> anyhow::Result<bool> {
+        match self {
+            Value::Number(n) => Ok(*n > 0.0),
+            Value::Bool(b) => Ok(*b),
+            _ => anyhow::bail!("不支持的bool运算: {self}"),
         }
     }
 
@@ -47,122 +145,281 @@ impl Display for Value {
         match self {
             Value::Number(n) => write!(f, "{}", n),
             Value::Bool(b) => write!(f, "{}", b),
+            Value::Str(s) => write!(f, "{:?}", s),
+            Value::Range { start, end, inclusive } => {
+                write!(f, "{}{}{}", start, if *inclusive { "..=" } else { ".." }, end)
+            }
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
 
-impl From<Value> for f64 {
-    fn from(value: Value) -> Self {
-        match value {
-            Value::Number(n) => n,
-            Value::Bool(b) => if b {1.0} else {0.0}
-        }
+impl From<f64> for Value {
+    fn from(n: f64) -> Self {
+        Value::Number(n)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Bool(b)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Str(s.to_string())
     }
 }
 
-impl From<&Value> for f64 {
-    fn from(value: &Value) -> Self {
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Str(s)
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        f64::try_from(&value)
+    }
+}
+
+impl TryFrom<&Value> for f64 {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &Value) -> Result<Self, Self::Error> {
         match value {
-            Value::Number(n) => *n,
-            Value::Bool(b) => if *b {1.0} else {0.0}
+            Value::Number(n) => Ok(*n),
+            Value::Bool(b) => Ok(if *b {1.0} else {0.0}),
+            _ => anyhow::bail!("无法作为数字运算: {value}"),
         }
     }
 }
 
 impl Add for Value {
-    type Output = Value;
+    type Output = anyhow::Result<Value>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        Value::Number(f64::from(self) + f64::from(rhs))
+        Ok(Value::Number(f64::try_from(self)? + f64::try_from(rhs)?))
     }
 }
 
 impl Sub for Value {
-    type Output = Value;
+    type Output = anyhow::Result<Value>;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        Value::Number(f64::from(self) - f64::from(rhs))
+        Ok(Value::Number(f64::try_from(self)? - f64::try_from(rhs)?))
     }
 }
 
 impl Mul for Value {
-    type Output = Value;
+    type Output = anyhow::Result<Value>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        Value::Number(f64::from(self) * f64::from(rhs))
+        Ok(Value::Number(f64::try_from(self)? * f64::try_from(rhs)?))
     }
 }
 
 impl Div for Value {
-    type Output = Value;
+    type Output = anyhow::Result<Value>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        Value::Number(f64::from(self) / f64::from(rhs))
+        Ok(Value::Number(f64::try_from(self)? / f64::try_from(rhs)?))
+    }
+}
+
+// 变量表和函数表, 求值时按名字查找
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    vars: HashMap<String, Value>,
+    functions: Functions,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, name: &str, value: impl Into<Value>) -> &mut Self {
+        self.vars.insert(name.to_string(), value.into());
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.vars.get(name)
+    }
+
+    pub(crate) fn lookup(&self, name: &str) -> anyhow::Result<&Value> {
+        self.get(name).ok_or_else(|| anyhow::anyhow!("未定义的变量: {name}"))
+    }
+
+    pub fn register_fn(
+        &mut self,
+        name: &str,
+        pure: bool,
+        f: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.functions.register(name, pure, f);
+        self
+    }
+
+    pub fn functions(&self) -> &Functions {
+        &self.functions
     }
 }
 
 pub fn eval(expr: &Expr) -> anyhow::Result<Value> {
+    eval_with(expr, &Context::default(), &EvalPolicy::default())
+}
+
+pub fn eval_with(expr: &Expr, ctx: &Context, policy: &EvalPolicy) -> anyhow::Result<Value> {
+    eval_scoped(expr, ctx, policy, &mut Vec::new())
+}
+
+// locals 是外层 let 绑定的值, 里层的在后面, 查找变量时先于 ctx
+fn eval_scoped<'a>(
+    expr: &'a Expr,
+    ctx: &Context,
+    policy: &EvalPolicy,
+    locals: &mut Vec<(&'a str, Value)>,
+) -> anyhow::Result<Value> {
+    if let Expr::Let { name, value, body } = expr {
+        let value = eval_scoped(value, ctx, policy, locals)?;
+        locals.push((name, value));
+        let result = eval_scoped(body, ctx, policy, locals);
+        locals.pop();
+        return result;
+    }
+    if let Expr::Var(name) = expr
+        && let Some((_, value)) = locals.iter().rev().find(|(n, _)| n == name)
+    {
+        return Ok(value.clone());
+    }
+
+    let mut eval = |e| eval_scoped(e, ctx, policy, locals);
     match expr {
         Expr::Number(n) => Ok(Value::Number(*n)),
-        Expr::UnaryOp { op, expr } => match op {
-            BinaryOp::Add | BinaryOp::Sub => {
-                let mut n = eval(expr)?;
-                
-                match n {
-                    Value::Number(num) => {
-                        if *op == BinaryOp::Sub {
-                            n = Value::Number(-num);
-                        }
-                        
-                    }
-                    Value::Bool(b) => {
-                        if *op == BinaryOp::Sub {
-                            n = Value::Bool(!b);
-                        }
-                    }
-                    // _ => anyhow::bail!("错误 bool 值无法数学运算"),
+        Expr::Str(s) => Ok(Value::Str(s.clone())),
+        Expr::Bool(b) => Ok(Value::Bool(*b)),
+        Expr::Var(name) => Ok(ctx.lookup(name)?.clone()),
+        Expr::Error => anyhow::bail!("表达式有语法错误"),
+        Expr::List(items) => Ok(Value::List(items.iter().map(&mut eval).collect::<anyhow::Result<_>>()?)),
+        Expr::UnaryOp { op, expr } => unary(op, eval(expr)?),
+        Expr::BinaryOp { left, op, right } => match op {
+            // 短路求值, 左边已经能决定结果时右边不再求值
+            BinaryOp::And => {
+                if !eval(left)?.truthy()? {
+                    return Ok(Value::Bool(false));
                 }
-
-                Ok(n)
+                Ok(Value::Bool(eval(right)?.truthy()?))
             }
-            BinaryOp::Not => {
-                // let mut n = eval(expr)?;
-                match eval(expr)? {
-                    Value::Number(num) => {
-                        Ok(Value::Bool(num == 0.0))
-                    }
-                    Value::Bool(b) => {
-                        Ok(Value::Bool(!b))
-                    }
+            BinaryOp::Or => {
+                if eval(left)?.truthy()? {
+                    return Ok(Value::Bool(true));
                 }
+                Ok(Value::Bool(eval(right)?.truthy()?))
             }
-            _ => anyhow::bail!("不支持的单目运算符"),
+            _ => binary(op, eval(left)?, eval(right)?, policy),
+        },
+        Expr::Compare { first, rest } => {
+            let mut l = eval(first)?;
+            for (op, expr) in rest {
+                let r = eval(expr)?;
+                // 和 && 一样, 一旦为 false 后面的操作数不再求值
+                if !compare(op, &l, &r, policy)? {
+                    return Ok(Value::Bool(false));
+                }
+                l = r;
+            }
+            Ok(Value::Bool(true))
         }
-        Expr::BinaryOp { left, op, right } => {
-            let l = eval(left)?;
-            let r = eval(right)?;
-
-            match op {
-                BinaryOp::Add => Ok(l + r),
-                BinaryOp::Sub => Ok(l - r),
-                BinaryOp::Mul => Ok(l * r),
-                BinaryOp::Div => {
-                    if r == Value::Number(0.0) || r == Value::Bool(false) {
-                        anyhow::bail!("除以零错误");
-                    }
-                    Ok(l / r)
-                },
-                BinaryOp::Eq  => Ok(if l == r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::Neq => Ok(if l != r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::Gt  => Ok(if l >  r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::Gte => Ok(if l >= r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::Lt  => Ok(if l <  r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::Lte => Ok(if l <= r { Value::Bool(true) } else { Value::Bool(false)}),
-                BinaryOp::And => Ok(if l.and(&r)? {Value::Bool(true)} else {Value::Bool(false)}),
-                BinaryOp::Or => Ok(if l.or(&r)? {Value::Bool(true)} else {Value::Bool(false)}),
-                _ => anyhow::bail!("不支持的双目运算符"),
+        Expr::Call { name, args } => {
+            let args = args.iter().map(&mut eval).collect::<anyhow::Result<Vec<_>>>()?;
+            call(ctx.functions.lookup(name)?, &args, policy)
+        }
+        Expr::Let { .. } => unreachable!("let 在上面处理"),
+    }
+}
+
+// 调用函数, 返回值和算术结果一样受 non_finite 约束
+pub(crate) fn call(f: &Function, args: &[Value], policy: &EvalPolicy) -> anyhow::Result<Value> {
+    check_finite(f.call(args), policy)
+}
+
+// 单目运算, 树遍历求值和字节码虚拟机共用
+pub(crate) fn unary(op: &BinaryOp, n: Value) -> anyhow::Result<Value> {
+    match op {
+        BinaryOp::Add | BinaryOp::Sub => match n {
+            Value::Number(num) if *op == BinaryOp::Sub => Ok(Value::Number(-num)),
+            Value::Bool(b) if *op == BinaryOp::Sub => Ok(Value::Bool(!b)),
+            Value::Number(_) | Value::Bool(_) => Ok(n),
+            _ => anyhow::bail!("无法作为数字运算: {n}"),
+        },
+        BinaryOp::Not => match n {
+            Value::Number(num) => Ok(Value::Bool(num == 0.0)),
+            Value::Bool(b) => Ok(Value::Bool(!b)),
+            v => anyhow::bail!("无法取反: {v}"),
+        },
+        _ => anyhow::bail!("不支持的单目运算符"),
+    }
+}
+
+// 双目运算 (&& 和 || 需要短路, 由调用方处理)
+pub(crate) fn binary(op: &BinaryOp, l: Value, r: Value, policy: &EvalPolicy) -> anyhow::Result<Value> {
+    match op {
+        BinaryOp::Add => check_finite(l + r, policy),
+        BinaryOp::Sub => check_finite(l - r, policy),
+        BinaryOp::Mul => check_finite(l * r, policy),
+        BinaryOp::Div => {
+            if policy.div_by_zero == DivByZero::Error && (r == Value::Number(0.0) || r == Value::Bool(false)) {
+                anyhow::bail!("除以零错误");
             }
+            check_finite(l / r, policy)
+        },
+        BinaryOp::Pow => check_finite(Ok(Value::Number(f64::try_from(l)?.powf(f64::try_from(r)?))), policy),
+        BinaryOp::Eq | BinaryOp::Neq | BinaryOp::Gt | BinaryOp::Gte | BinaryOp::Lt | BinaryOp::Lte
+        | BinaryOp::In | BinaryOp::NotIn => Ok(Value::Bool(compare(op, &l, &r, policy)?)),
+        BinaryOp::Range | BinaryOp::RangeInclusive => Ok(Value::Range {
+            start: f64::try_from(l)?,
+            end: f64::try_from(r)?,
+            inclusive: *op == BinaryOp::RangeInclusive,
+        }),
+        _ => anyhow::bail!("不支持的双目运算符"),
+    }
+}
+
+fn check_finite(value: anyhow::Result<Value>, policy: &EvalPolicy) -> anyhow::Result<Value> {
+    match value? {
+        Value::Number(n) if !n.is_finite() && policy.non_finite == NonFinite::Error => {
+            anyhow::bail!("运算结果不是有限数: {n}")
         }
+        v => Ok(v),
+    }
+}
+
+pub(crate) fn compare(op: &BinaryOp, l: &Value, r: &Value, policy: &EvalPolicy) -> anyhow::Result<bool> {
+    let ordering = || l.compare(r, policy);
+    match op {
+        BinaryOp::Eq  => Ok(l.equals(r, policy)),
+        BinaryOp::Neq => Ok(!l.equals(r, policy)),
+        BinaryOp::Gt  => Ok(matches!(ordering()?, Some(Ordering::Greater))),
+        BinaryOp::Gte => Ok(matches!(ordering()?, Some(Ordering::Greater | Ordering::Equal))),
+        BinaryOp::Lt  => Ok(matches!(ordering()?, Some(Ordering::Less))),
+        BinaryOp::Lte => Ok(matches!(ordering()?, Some(Ordering::Less | Ordering::Equal))),
+        BinaryOp::In => r.contains(l, policy),
+        BinaryOp::NotIn => Ok(!r.contains(l, policy)?),
+        _ => anyhow::bail!("不是比较运算符: {op}"),
     }
 }
 
@@ -318,4 +575,211 @@ mod tests {
         let result = eval(&expr);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_let_scope() {
+        // let $0 = x + 1 in (let $0 = $0 * 2 in $0) + $0, 里层遮住外层, 出了 body 恢复
+        let var = |name: &str| Box::new(Expr::Var(name.to_string()));
+        let add = |l, r| Box::new(Expr::BinaryOp { left: l, op: BinaryOp::Add, right: r });
+        let inner = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(Expr::BinaryOp { left: var("$0"), op: BinaryOp::Mul, right: Box::new(number_expr(2.0)) }),
+            body: var("$0"),
+        };
+        let expr = Expr::Let { name: "$0".to_string(), value: add(var("x"), Box::new(number_expr(1.0))), body: add(Box::new(inner), var("$0")) };
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0);
+        assert_eq!(eval_with(&expr, &ctx, &EvalPolicy::default()).unwrap(), Value::Number(9.0));
+        assert!(ctx.get("$0").is_none());
+    }
+
+    fn eval_str(input: &str) -> anyhow::Result<Value> {
+        let tokens = crate::lexer::tokenize(input)?;
+        eval(&crate::parser::Parser::new(tokens).parse_program()?)
+    }
+
+    #[test]
+    fn test_range_membership() {
+        assert_eq!(eval_str("3 in 1..10").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("10 in 1..10").unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("10 in 1..=10").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("2.5 in 1..10").unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("5 not in 1..10").unwrap(), Value::Bool(false));
+        // 大范围不会展开
+        assert_eq!(eval_str("1 + 1 in 0..1000000000000").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_list_membership() {
+        assert_eq!(eval_str("2 in [1, 2, 3]").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("4 not in [1, 2, 3]").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("1 in []").unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("[1, 1 + 1]").unwrap(), Value::List(vec![Value::Number(1.0), Value::Number(2.0)]));
+    }
+
+    #[test]
+    fn test_in_requires_container() {
+        assert_eq!(eval_str("1 in 2").unwrap_err().to_string(), "`in` 右边必须是范围或列表 (还不支持 map): 2");
+        assert!(eval_str("[1] + 1").is_err());
+    }
+
+    #[test]
+    fn test_chained_comparison() {
+        assert_eq!(eval_str("1 < 2 < 3").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("3 > 2 > 1").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("1 < 3 < 2").unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("1 <= 1 == 1 < 2").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("0 < 2 in [1, 2]").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_chained_comparison_short_circuit() {
+        // 第一个比较为 false 时, 后面的 1 in 2 不会求值也就不会报错
+        assert_eq!(eval_str("2 < 1 < 1 in 2").unwrap(), Value::Bool(false));
+    }
+
+    fn eval_policy(input: &str, policy: &EvalPolicy) -> anyhow::Result<Value> {
+        let tokens = crate::lexer::tokenize(input)?;
+        eval_with(&crate::parser::Parser::new(tokens).parse_program()?, &Context::default(), policy)
+    }
+
+    #[test]
+    fn test_mixed_number_bool() {
+        let policy = EvalPolicy::default();
+        // bool 按 0/1 参与比较, 和 true + 0 == 1 保持一致
+        assert!(Value::Number(1.0).equals(&Value::Bool(true), &policy));
+        assert!(compare(&BinaryOp::Gt, &Value::Number(5.0), &Value::Bool(false), &policy).unwrap());
+        assert_eq!(eval_str("[1 < 2] == [1]").unwrap(), Value::Bool(true));
+        assert_eq!(eval_str("0 in [1 > 2]").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_nan_comparison() {
+        let nan = Value::Number(f64::NAN);
+        let policy = EvalPolicy::default();
+        assert!(!nan.equals(&nan, &policy));
+        assert_eq!(nan.compare(&Value::Number(1.0), &policy).unwrap(), None);
+        assert!(!compare(&BinaryOp::Lt, &nan, &Value::Number(1.0), &policy).unwrap());
+        assert!(compare(&BinaryOp::Neq, &nan, &nan, &policy).unwrap());
+    }
+
+    #[test]
+    fn test_float_eq() {
+        let policy = EvalPolicy { float_eq: FloatEq::Abs(1e-9), ..Default::default() };
+        assert_eq!(eval_str("0.1 + 0.2 == 0.3").unwrap(), Value::Bool(false));
+        assert_eq!(eval_policy("0.1 + 0.2 == 0.3", &policy).unwrap(), Value::Bool(true));
+        assert_eq!(eval_policy("0.1 + 0.2 != 0.3", &policy).unwrap(), Value::Bool(false));
+        assert_eq!(eval_policy("0.1 + 0.2 > 0.3", &policy).unwrap(), Value::Bool(false));
+
+        let policy = EvalPolicy { float_eq: FloatEq::Rel(1e-6), ..Default::default() };
+        assert_eq!(eval_policy("1000000 == 1000000.5", &policy).unwrap(), Value::Bool(true));
+        assert_eq!(eval_policy("0.001 == 0.0015", &policy).unwrap(), Value::Bool(false));
+        assert!(!FloatEq::Rel(1e-6).approx_eq(f64::NAN, f64::NAN));
+    }
+
+    #[test]
+    fn test_float_eq_infinity() {
+        for float_eq in [FloatEq::Exact, FloatEq::Abs(1e-9), FloatEq::Rel(1e-6)] {
+            assert!(float_eq.approx_eq(f64::INFINITY, f64::INFINITY), "{float_eq:?}");
+            assert!(float_eq.approx_eq(f64::NEG_INFINITY, f64::NEG_INFINITY), "{float_eq:?}");
+            assert!(!float_eq.approx_eq(f64::INFINITY, f64::NEG_INFINITY), "{float_eq:?}");
+            assert!(!float_eq.approx_eq(f64::INFINITY, 1e308), "{float_eq:?}");
+        }
+        let policy = EvalPolicy { float_eq: FloatEq::Abs(1e-9), div_by_zero: DivByZero::Infinity, ..Default::default() };
+        assert_eq!(eval_policy("1 / 0 == 2 / 0", &policy).unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_div_by_zero_policy() {
+        let policy = EvalPolicy { div_by_zero: DivByZero::Infinity, ..Default::default() };
+        assert_eq!(eval_policy("1 / 0", &policy).unwrap(), Value::Number(f64::INFINITY));
+        assert_eq!(eval_policy("-1 / 0", &policy).unwrap(), Value::Number(f64::NEG_INFINITY));
+        assert!(eval_str("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_non_finite_policy() {
+        let big = format!("1{}", "0".repeat(300)); // 1e300, 词法分析不支持科学计数法
+        let input = format!("{big} * {big}");
+        assert_eq!(eval_str(&input).unwrap(), Value::Number(f64::INFINITY));
+
+        let policy = EvalPolicy { non_finite: NonFinite::Error, ..Default::default() };
+        assert!(eval_policy(&input, &policy).is_err());
+
+        let policy = EvalPolicy { non_finite: NonFinite::Error, div_by_zero: DivByZero::Infinity, ..Default::default() };
+        assert!(eval_policy("0 / 0", &policy).is_err());
+        assert_eq!(eval_policy("1 / 2", &policy).unwrap(), Value::Number(0.5));
+    }
+
+    #[test]
+    fn test_string_ordering() {
+        assert_eq!(eval_str(r#""abc" < "abd""#).unwrap(), Value::Bool(true));
+        assert_eq!(eval_str(r#""B" < "a""#).unwrap(), Value::Bool(true));
+        let policy = EvalPolicy { string_order: StringOrder::IgnoreCase, ..Default::default() };
+        assert_eq!(eval_policy(r#""B" < "a""#, &policy).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str(r#""a" in ["b", "a"]"#).unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_mixed_type_comparison() {
+        assert_eq!(eval_str(r#""1" == 1"#).unwrap(), Value::Bool(false));
+        assert_eq!(eval_str(r#""1" != 1"#).unwrap(), Value::Bool(true));
+        assert!(eval_str(r#""1" < 1"#).is_err());
+        assert!(eval_str("[1] < [2]").is_err());
+        assert_eq!(eval_str("[1, 2] == [1, 1 + 1]").unwrap(), Value::Bool(true));
+    }
+
+    #[test]
+    fn test_and_or_short_circuit() {
+        assert_eq!(eval_str("0 && 1 / 0").unwrap(), Value::Bool(false));
+        assert_eq!(eval_str("1 || 1 / 0").unwrap(), Value::Bool(true));
+        assert!(eval_str("1 && 1 / 0").is_err());
+        assert!(eval_str(r#"1 && "a""#).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let tokens = crate::lexer::tokenize("x * 2 + y in [7, 8]").unwrap();
+        let expr = crate::parser::Parser::new(tokens).parse_program().unwrap();
+        let mut ctx = Context::new();
+        ctx.set("x", 3.0).set("y", true);
+        assert_eq!(eval_with(&expr, &ctx, &EvalPolicy::default()).unwrap(), Value::Bool(true));
+        assert!(eval(&expr).is_err());
+    }
+
+    #[test]
+    fn test_function_call() {
+        assert_eq!(eval_str("sqrt(16) + max(1, 2)").unwrap(), Value::Number(6.0));
+        assert!(eval_str("nope(1)").is_err());
+
+        let tokens = crate::lexer::tokenize("double(x) + 1").unwrap();
+        let expr = crate::parser::Parser::new(tokens).parse_program().unwrap();
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0).register_fn("double", true, |args| Ok(Value::Number(f64::try_from(&args[0])? * 2.0)));
+        assert_eq!(eval_with(&expr, &ctx, &EvalPolicy::default()).unwrap(), Value::Number(5.0));
+
+        let policy = EvalPolicy { non_finite: NonFinite::Error, ..Default::default() };
+        assert!(eval_policy("ln(0)", &policy).is_err());
+    }
+
+    #[test]
+    fn test_let() {
+        let expr = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(Expr::Number(3.0)),
+            body: Box::new(Expr::BinaryOp {
+                left: Box::new(Expr::Var("$0".to_string())),
+                op: BinaryOp::Mul,
+                right: Box::new(Expr::Var("$0".to_string())),
+            }),
+        };
+        assert_eq!(eval(&expr).unwrap(), Value::Number(9.0));
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(eval_str("2 ^ 3 ^ 2").unwrap(), Value::Number(512.0));
+        assert_eq!(eval_str("-2 ^ 2").unwrap(), Value::Number(-4.0));
+        assert_eq!(eval_str("4 ^ -1 * 2").unwrap(), Value::Number(0.5));
+    }
 }
@@ -0,0 +1,383 @@
+// 格式化: 按优先级只加必要括号的输出, 以及保留注释的源码格式化 (expr fmt)
+//
+//   ((1 + 2) * 3)          =>  (1 + 2) * 3
+//   !(a > 1) && b          =>  (!(a > 1)) && b    `!` 会吞掉右边整个表达式, 后面还有内容时必须加括号
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+
+use crate::{
+    ast::{BinaryOp, Expr, ATOM_PRECEDENCE, UNARY_PRECEDENCE},
+    lexer::{split_statements, tokenize, tokenize_with_comments, Token},
+    parser::Parser,
+};
+
+// 源码格式化时一行的最大宽度, 超过时在 || 和 && 前面断行
+pub const MAX_WIDTH: usize = 80;
+const INDENT: usize = 4;
+
+pub fn pretty(expr: &Expr) -> String {
+    let mut out = String::new();
+    write_expr(&mut out, expr, 0, true);
+    out
+}
+
+// min: 这个位置允许的最低优先级, 低于它要加括号
+// last: 右边直到括号/逗号/结尾都没有别的内容了. `!` 和 let 会吞掉右边的整个表达式, 不在最右边时要加括号
+fn write_expr(out: &mut String, expr: &Expr, min: u8, last: bool) {
+    let (precedence, greedy) = match expr {
+        Expr::Let { .. } => (0, true),
+        Expr::UnaryOp { op: BinaryOp::Not, .. } => (UNARY_PRECEDENCE, true),
+        _ => (expr.precedence(), false),
+    };
+    if precedence < min || (greedy && !last) {
+        out.push('(');
+        write_bare(out, expr, true);
+        out.push(')');
+    } else {
+        write_bare(out, expr, last);
+    }
+}
+
+fn write_bare(out: &mut String, expr: &Expr, last: bool) {
+    match expr {
+        Expr::Number(n) => write!(out, "{n}").unwrap(),
+        Expr::Str(s) => out.push_str(&quote(s)),
+        Expr::Var(name) => out.push_str(name),
+        Expr::Bool(b) => write!(out, "{b}").unwrap(),
+        Expr::Error => out.push_str("<错误>"),
+        // `!` 的操作数是二元运算时也加上括号, `!a > 1` 虽然合法但容易看错
+        Expr::UnaryOp { op: BinaryOp::Not, expr } => {
+            out.push('!');
+            write_expr(out, expr, UNARY_PRECEDENCE, last);
+        }
+        // 负号的操作数是乘方或更紧的: -x ^ 2 == -(x ^ 2)
+        Expr::UnaryOp { op, expr } => {
+            write!(out, "{op}").unwrap();
+            write_expr(out, expr, BinaryOp::Pow.precedence(), last);
+        }
+        Expr::BinaryOp { left, op, right } => {
+            let precedence = op.precedence();
+            let (left_min, right_min) = match op {
+                // 右结合, 底数只能是原子, 指数可以带负号
+                BinaryOp::Pow => (ATOM_PRECEDENCE, UNARY_PRECEDENCE),
+                // 比较和范围不能连写, 两边都要更紧
+                _ if (3..=4).contains(&precedence) => (precedence + 1, precedence + 1),
+                _ => (precedence, precedence + 1),
+            };
+            write_expr(out, left, left_min, false);
+            write!(out, " {op} ").unwrap();
+            write_expr(out, right, right_min, last);
+        }
+        Expr::Compare { first, rest } => {
+            let min = rest[0].0.precedence() + 1;
+            write_expr(out, first, min, false);
+            for (i, (op, expr)) in rest.iter().enumerate() {
+                write!(out, " {op} ").unwrap();
+                write_expr(out, expr, min, last && i == rest.len() - 1);
+            }
+        }
+        Expr::List(items) => {
+            out.push('[');
+            write_list(out, items);
+            out.push(']');
+        }
+        Expr::Call { name, args } => {
+            write!(out, "{name}(").unwrap();
+            write_list(out, args);
+            out.push(')');
+        }
+        Expr::Let { name, value, body } => {
+            write!(out, "let {name} = ").unwrap();
+            write_expr(out, value, 0, false);
+            out.push_str(" in ");
+            write_expr(out, body, 0, last);
+        }
+    }
+}
+
+fn write_list(out: &mut String, items: &[Expr]) {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            out.push_str(", ");
+        }
+        write_expr(out, item, 0, true);
+    }
+}
+
+// 字符串字面量, 只用词法分析认识的转义
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// 一行里的一个 token; brk 是可以在它前面断行的位置 (括号深度, || 为 1, && 为 2)
+struct Piece {
+    text: String,
+    space: bool,
+    brk: Option<(usize, u8)>,
+}
+
+// 格式化源码: 统一空格, 过长的行在 || 和 && 前断行, 保留所有注释和语句之间的空行.
+// 按 split_statements 分成语句, 每条语句单独格式化, 和脚本模式读文件的方式一致
+pub fn format_source(source: &str) -> Result<String> {
+    let mut out = String::new();
+    for statement in split_statements(source) {
+        let code = statement.source.trim_start();
+        if code.starts_with(':') {
+            out.push_str(code.trim_end());
+            out.push('\n');
+        } else if tokenize(&statement.source).is_ok_and(|t| t.is_empty()) {
+            // 只有空行和注释
+            for line in statement.source.split('\n') {
+                out.push_str(line.trim());
+                out.push('\n');
+            }
+        } else {
+            out.push_str(&format_statement(&statement.source).with_context(|| format!("第 {} 行", statement.line))?);
+        }
+    }
+    Ok(out)
+}
+
+// 只调整空白, 不增删 token, 所以格式化前后的语法树一定相同; 最后仍会再解析一遍确认
+fn format_statement(source: &str) -> Result<String> {
+    let tokens = tokenize_with_comments(source)?;
+    let code: Vec<Token> = tokens.iter().filter(|t| !matches!(t, Token::Comment { .. })).cloned().collect();
+    let expected = Parser::new(code).parse_program()?;
+
+    let mut lines = Vec::new();
+    let mut line: Vec<Piece> = Vec::new();
+    let mut indent = 0;
+    let mut depth = 0;
+    let mut prev: Option<&Token> = None;
+    let mut prev_unary = false;
+    for (i, token) in tokens.iter().enumerate() {
+        if let Token::Comment { text, own_line } = token {
+            let rest_is_code = tokens[i..].iter().any(|t| !matches!(t, Token::Comment { .. }));
+            if *own_line {
+                render(&line, indent, &mut lines);
+                line.clear();
+                let indent = if prev.is_some() && rest_is_code { INDENT * (depth + 1) } else { 0 };
+                lines.push(format!("{:indent$}//{text}", ""));
+            } else {
+                line.push(Piece { text: format!("//{text}"), space: true, brk: None });
+                render(&line, indent, &mut lines);
+                line.clear();
+            }
+            if prev.is_some() {
+                indent = INDENT * (depth + 1);
+            }
+            continue;
+        }
+
+        if let Token::RParen | Token::RBracket = token {
+            depth = depth.saturating_sub(1);
+        }
+        let unary = match token {
+            Token::Not => true,
+            Token::Plus | Token::Minus => !matches!(
+                prev,
+                Some(Token::Number(_) | Token::Str(_) | Token::Ident(_) | Token::Bool(_) | Token::RParen | Token::RBracket)
+            ),
+            _ => false,
+        };
+        let space = match (prev, token) {
+            (None | Some(Token::LParen | Token::LBracket), _) => false,
+            _ if prev_unary => false,
+            (_, Token::RParen | Token::RBracket | Token::Comma) => false,
+            (Some(Token::Ident(_)), Token::LParen) => false,
+            _ => true,
+        };
+        let brk = match token {
+            Token::Or => Some((depth, 1)),
+            Token::And => Some((depth, 2)),
+            _ => None,
+        };
+        let text = match token {
+            Token::Str(s) => quote(s),
+            _ => token.to_string(),
+        };
+        line.push(Piece { text, space: space && !line.is_empty(), brk });
+        if let Token::LParen | Token::LBracket = token {
+            depth += 1;
+        }
+        prev = Some(token);
+        prev_unary = unary;
+    }
+    render(&line, indent, &mut lines);
+
+    let mut out = lines.join("\n");
+    out.push('\n');
+
+    let actual = Parser::new(tokenize(&out)?).parse_program()?;
+    if actual != expected {
+        anyhow::bail!("格式化改变了语法树: {expected} => {actual}");
+    }
+    Ok(out)
+}
+
+// 输出一行, 太长时在最外层 (括号最浅, 优先 ||) 的运算符前断开, 断开的部分再递归处理
+fn render(pieces: &[Piece], indent: usize, lines: &mut Vec<String>) {
+    if pieces.is_empty() {
+        return;
+    }
+    let mut text = format!("{:indent$}", "");
+    for (i, piece) in pieces.iter().enumerate() {
+        if i > 0 && piece.space {
+            text.push(' ');
+        }
+        text.push_str(&piece.text);
+    }
+    let best = pieces[1..].iter().filter_map(|p| p.brk).min();
+    let Some(best) = best.filter(|_| text.chars().count() > MAX_WIDTH) else {
+        lines.push(text);
+        return;
+    };
+
+    let mut start = 0;
+    let mut segment_indent = indent;
+    for i in 1..=pieces.len() {
+        if i == pieces.len() || pieces[i].brk == Some(best) {
+            render(&pieces[start..i], segment_indent, lines);
+            start = i;
+            segment_indent = INDENT * (best.0 + 1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{random_expr, Rng};
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    fn reformat(input: &str) -> String {
+        pretty(&parse(input))
+    }
+
+    #[test]
+    fn test_pretty_minimal_parentheses() {
+        assert_eq!(reformat("((1 + 2) * 3)"), "(1 + 2) * 3");
+        assert_eq!(reformat("1 + (2 * 3)"), "1 + 2 * 3");
+        assert_eq!(reformat("(a - b) - (c - d)"), "a - b - (c - d)");
+        assert_eq!(reformat("(a ^ b) ^ c ^ -d"), "(a ^ b) ^ c ^ -d");
+        assert_eq!(reformat("-(x ^ 2) + (-x) ^ 2 - -(-x)"), "-x ^ 2 + (-x) ^ 2 - -(-x)");
+        assert_eq!(reformat("(a < b) == (c < d < e)"), "(a < b) == (c < d < e)");
+        assert_eq!(reformat("(1..3) in [x, (y || z)]"), "1 .. 3 in [x, y || z]");
+        assert_eq!(reformat("max(\"a\\\"b\", (x))"), "max(\"a\\\"b\", x)");
+    }
+
+    #[test]
+    fn test_pretty_not() {
+        assert_eq!(reformat("(!a) && b"), "(!a) && b");
+        assert_eq!(reformat("!(a && b)"), "!(a && b)");
+        assert_eq!(reformat("!a && b"), "!(a && b)");
+        assert_eq!(reformat("a || (b && (!c)) || d"), "a || b && (!c) || d");
+        assert_eq!(reformat("x + (!y)"), "x + !y");
+        assert_eq!(reformat("!!x"), "!!x");
+    }
+
+    #[test]
+    fn test_pretty_let() {
+        let expr = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(parse("x + 1")),
+            body: Box::new(parse("y * 2")),
+        };
+        assert_eq!(pretty(&expr), "let $0 = x + 1 in y * 2");
+        let list = Expr::BinaryOp { left: Box::new(expr.clone()), op: BinaryOp::Add, right: Box::new(Expr::Number(1.0)) };
+        assert_eq!(pretty(&list), "(let $0 = x + 1 in y * 2) + 1");
+    }
+
+    #[test]
+    fn test_pretty_round_trip() {
+        let mut rng = Rng(0x5eed_f0a7);
+        let mut checked = 0;
+        for _ in 0..2000 {
+            // 随机树未必能由解析得到 (比如负数常量), 所以先经过一次解析
+            let Ok(expected) = tokenize(&random_expr(&mut rng, 4).to_string())
+                .and_then(|tokens| Parser::new(tokens).parse_program())
+            else {
+                continue;
+            };
+            let printed = pretty(&expected);
+            let actual = Parser::new(tokenize(&printed).unwrap()).parse_program().unwrap();
+            assert_eq!(actual, expected, "{printed}");
+            if printed.len() <= MAX_WIDTH {
+                assert_eq!(format_source(&printed).unwrap().trim_end(), printed);
+            }
+            checked += 1;
+        }
+        assert!(checked > 500, "{checked}");
+    }
+
+    #[test]
+    fn test_format_source_spacing() {
+        assert_eq!(format_source("1+2*( 3-4 )/-x").unwrap(), "1 + 2 * (3 - 4) / -x\n");
+        assert_eq!(format_source("max( a ,-b )^2 in[1,2 ,3]").unwrap(), "max(a, -b) ^ 2 in [1, 2, 3]\n");
+        assert_eq!(format_source("!( a&&b )||x   not   in 1..=3").unwrap(), "!(a && b) || x not in 1 ..= 3\n");
+        // 多余的括号保留, 只调整空白
+        assert_eq!(format_source("((x))").unwrap(), "((x))\n");
+    }
+
+    #[test]
+    fn test_format_source_comments() {
+        let source = "// 检查范围\n  x>=0 // 下限\n&&x<10\n   // 结束\n";
+        assert_eq!(format_source(source).unwrap(), "// 检查范围\nx >= 0 // 下限\n    && x < 10\n// 结束\n");
+        let source = "f(a,\n// 第二个参数\nb)";
+        assert_eq!(format_source(source).unwrap(), "f(a,\n        // 第二个参数\n        b)\n");
+    }
+
+    #[test]
+    fn test_format_source_line_breaking() {
+        let long = "alpha_value > 100 && beta_value < 200 || gamma_value == 300 && delta_value != 400 || epsilon";
+        assert_eq!(
+            format_source(long).unwrap(),
+            "alpha_value > 100 && beta_value < 200\n    || gamma_value == 300 && delta_value != 400\n    || epsilon\n"
+        );
+        let nested = "result && (first_condition_is_long || second_condition_is_long || third_condition_too_long)";
+        assert_eq!(
+            format_source(nested).unwrap(),
+            "result\n    && (first_condition_is_long\n        || second_condition_is_long\n        || third_condition_too_long)\n"
+        );
+        // 已经格式化过的结果不会再变
+        let once = format_source(long).unwrap();
+        assert_eq!(format_source(&once).unwrap(), once);
+    }
+
+    #[test]
+    fn test_format_source_errors() {
+        assert!(format_source("1 +").is_err());
+        assert!(format_source("\"abc").is_err());
+        assert_eq!(format_source("1\n2 +\n").unwrap_err().to_string(), "第 2 行");
+    }
+
+    #[test]
+    fn test_format_source_statements() {
+        // 每行一条语句, 和脚本模式一致; 语句之间的空行和注释保留
+        let source = "// 规则\n1+2\n\n  x*2 // 注释\n:trace\n// 结尾";
+        assert_eq!(format_source(source).unwrap(), "// 规则\n1 + 2\n\nx * 2 // 注释\n:trace\n// 结尾\n");
+        assert_eq!(format_source("// 只有注释").unwrap(), "// 只有注释\n");
+        // 断开的长表达式再格式化仍然是一条语句
+        let long = "alpha_value > 100 && beta_value < 200 || gamma_value == 300 && delta_value != 400 || epsilon";
+        let twice = format!("{long}\n-1\n{long}");
+        let once = format_source(&twice).unwrap();
+        assert_eq!(once.lines().count(), 7);
+        assert_eq!(format_source(&once).unwrap(), once);
+    }
+}
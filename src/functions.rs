@@ -0,0 +1,137 @@
+// 函数表: 内置数学函数和宿主注册的函数
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use crate::eval::Value;
+
+pub type HostFn = Arc<dyn Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync>;
+
+#[derive(Clone)]
+pub struct Function {
+    // 纯函数: 相同参数总是得到相同结果且没有副作用, 优化时可以合并或提前计算
+    pub pure: bool,
+    f: HostFn,
+}
+
+impl Function {
+    pub fn call(&self, args: &[Value]) -> anyhow::Result<Value> {
+        (self.f)(args)
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Function {{ pure: {} }}", self.pure)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Functions {
+    map: HashMap<String, Function>,
+}
+
+impl Default for Functions {
+    // 默认带有内置数学函数
+    fn default() -> Self {
+        let mut functions = Functions { map: HashMap::new() };
+        for (name, f) in [
+            ("abs", f64::abs as fn(f64) -> f64),
+            ("sqrt", f64::sqrt),
+            ("exp", f64::exp),
+            ("ln", f64::ln),
+            ("log10", f64::log10),
+            ("sin", f64::sin),
+            ("cos", f64::cos),
+            ("tan", f64::tan),
+            ("floor", f64::floor),
+            ("ceil", f64::ceil),
+            ("round", f64::round),
+        ] {
+            functions.register(name, true, move |args| {
+                let [x] = numbers::<1>(name, args)?;
+                Ok(Value::Number(f(x)))
+            });
+        }
+        for (name, f) in [
+            ("min", f64::min as fn(f64, f64) -> f64),
+            ("max", f64::max),
+            ("pow", f64::powf),
+        ] {
+            functions.register(name, true, move |args| {
+                let [a, b] = numbers::<2>(name, args)?;
+                Ok(Value::Number(f(a, b)))
+            });
+        }
+        functions
+    }
+}
+
+impl Functions {
+    // 不带内置函数的空表
+    pub fn empty() -> Self {
+        Functions { map: HashMap::new() }
+    }
+
+    pub fn register(
+        &mut self,
+        name: &str,
+        pure: bool,
+        f: impl Fn(&[Value]) -> anyhow::Result<Value> + Send + Sync + 'static,
+    ) -> &mut Self {
+        self.map.insert(name.to_string(), Function { pure, f: Arc::new(f) });
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Function> {
+        self.map.get(name)
+    }
+
+    pub fn lookup(&self, name: &str) -> anyhow::Result<&Function> {
+        self.get(name).ok_or_else(|| anyhow::anyhow!("未定义的函数: {name}"))
+    }
+
+    // 未知函数按不纯处理
+    pub fn is_pure(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|f| f.pure)
+    }
+}
+
+fn numbers<const N: usize>(name: &str, args: &[Value]) -> anyhow::Result<[f64; N]> {
+    if args.len() != N {
+        anyhow::bail!("函数 {name} 需要 {N} 个参数, 实际是 {} 个", args.len());
+    }
+    let mut out = [0.0; N];
+    for (o, a) in out.iter_mut().zip(args) {
+        *o = f64::try_from(a)?;
+    }
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtins() {
+        let functions = Functions::default();
+        let call = |name: &str, args: &[f64]| {
+            let args: Vec<Value> = args.iter().map(|n| Value::Number(*n)).collect();
+            functions.lookup(name).unwrap().call(&args)
+        };
+        assert_eq!(call("sqrt", &[9.0]).unwrap(), Value::Number(3.0));
+        assert_eq!(call("max", &[1.0, 2.0]).unwrap(), Value::Number(2.0));
+        assert_eq!(call("pow", &[2.0, 10.0]).unwrap(), Value::Number(1024.0));
+        assert!(call("sqrt", &[1.0, 2.0]).is_err());
+        assert!(functions.is_pure("sin"));
+        assert!(!functions.is_pure("nope"));
+    }
+
+    #[test]
+    fn test_register_host_function() {
+        let mut functions = Functions::empty();
+        functions.register("answer", false, |_| Ok(Value::Number(42.0)));
+        assert_eq!(functions.lookup("answer").unwrap().call(&[]).unwrap(), Value::Number(42.0));
+        assert!(!functions.is_pure("answer"));
+        assert!(functions.lookup("sqrt").is_err());
+    }
+}
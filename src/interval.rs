@@ -0,0 +1,499 @@
+// 区间求值: 给定每个输入变量的取值范围, 推算表达式结果的范围
+//
+// 数字用 [lo, hi] 表示, 比较和 bool 运算的结果是 true / false / unknown.
+// 结果是保守的: 真实结果一定落在区间内, 但区间可能比真实范围大 (例如 x 在 [0, 1] 内时 x - x 得到 [-1, 1]
+// 而不是 0, 因为两个 x 被当成互相独立). 端点没有做向外舍入, 只保证到浮点误差范围内.
+//
+// 可能除以零, 函数参数可能超出定义域等情况记录在 warnings 里; 一定会出错时直接返回错误.
+use std::{collections::HashMap, f64::consts::PI, fmt::Display};
+
+use crate::ast::{BinaryOp, Expr};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f64,
+    pub hi: f64,
+}
+
+impl Interval {
+    pub fn new(lo: f64, hi: f64) -> anyhow::Result<Self> {
+        if lo.is_nan() || hi.is_nan() || lo > hi {
+            anyhow::bail!("区间下界必须不大于上界: [{lo}, {hi}]");
+        }
+        Ok(Interval { lo, hi })
+    }
+
+    pub fn point(v: f64) -> Self {
+        Interval { lo: v, hi: v }
+    }
+
+    pub fn contains(&self, v: f64) -> bool {
+        self.lo <= v && v <= self.hi
+    }
+
+    fn whole() -> Self {
+        Interval { lo: f64::NEG_INFINITY, hi: f64::INFINITY }
+    }
+
+    // 所有候选端点中的最小值和最大值
+    fn hull(points: &[f64]) -> Self {
+        let lo = points.iter().copied().fold(f64::INFINITY, f64::min);
+        let hi = points.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        Interval { lo, hi }
+    }
+
+    fn add(self, other: Self) -> Self {
+        Interval { lo: self.lo + other.lo, hi: self.hi + other.hi }
+    }
+
+    fn sub(self, other: Self) -> Self {
+        Interval { lo: self.lo - other.hi, hi: self.hi - other.lo }
+    }
+
+    fn neg(self) -> Self {
+        Interval { lo: -self.hi, hi: -self.lo }
+    }
+
+    fn mul(self, other: Self) -> Self {
+        // 区间端点可能是无穷, 0 * inf 按 0 处理
+        let m = |a: f64, b: f64| if a == 0.0 || b == 0.0 { 0.0 } else { a * b };
+        Interval::hull(&[m(self.lo, other.lo), m(self.lo, other.hi), m(self.hi, other.lo), m(self.hi, other.hi)])
+    }
+
+    // 1 / x, 不含 x = 0 这一点本身
+    fn recip(self) -> Self {
+        let Interval { lo, hi } = self;
+        if lo < 0.0 && hi > 0.0 {
+            Interval::whole()
+        } else if lo == 0.0 {
+            Interval { lo: 1.0 / hi, hi: f64::INFINITY }
+        } else if hi == 0.0 {
+            Interval { lo: f64::NEG_INFINITY, hi: 1.0 / lo }
+        } else {
+            Interval { lo: 1.0 / hi, hi: 1.0 / lo }
+        }
+    }
+
+    fn pow(self, exp: Self) -> Option<Self> {
+        if exp.lo == exp.hi && exp.lo.fract() == 0.0 {
+            let n = exp.lo;
+            if n < 0.0 {
+                return self.pow(Interval::point(-n)).map(Interval::recip);
+            }
+            let (a, b) = (self.lo.powf(n), self.hi.powf(n));
+            return Some(if n % 2.0 == 1.0 || self.lo >= 0.0 {
+                Interval::hull(&[a, b])
+            } else if self.hi <= 0.0 {
+                Interval { lo: b, hi: a }
+            } else {
+                Interval { lo: 0.0, hi: a.max(b) }
+            });
+        }
+        if self.lo >= 0.0 {
+            // 底数非负时 x^y 对 x 和 y 分别单调, 最值在四个角上
+            let p = |a: f64, b: f64| a.powf(b);
+            return Some(Interval::hull(&[
+                p(self.lo, exp.lo),
+                p(self.lo, exp.hi),
+                p(self.hi, exp.lo),
+                p(self.hi, exp.hi),
+            ]));
+        }
+        // 负数的非整数次幂不是实数
+        None
+    }
+
+    // 单调递增函数直接作用在两个端点上
+    fn map(self, f: fn(f64) -> f64) -> Self {
+        Interval { lo: f(self.lo), hi: f(self.hi) }
+    }
+
+    // 区间内是否有 offset + k * period 形式的点
+    fn hits(self, offset: f64, period: f64) -> bool {
+        let k = ((self.lo - offset) / period).ceil();
+        offset + k * period <= self.hi
+    }
+
+    fn sin(self) -> Self {
+        if self.hi - self.lo >= 2.0 * PI {
+            return Interval { lo: -1.0, hi: 1.0 };
+        }
+        let ends = Interval::hull(&[self.lo.sin(), self.hi.sin()]);
+        Interval {
+            lo: if self.hits(-PI / 2.0, 2.0 * PI) { -1.0 } else { ends.lo },
+            hi: if self.hits(PI / 2.0, 2.0 * PI) { 1.0 } else { ends.hi },
+        }
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+// 三值逻辑
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Truth {
+    True,
+    False,
+    Unknown,
+}
+
+impl Truth {
+    fn not(self) -> Self {
+        match self {
+            Truth::True => Truth::False,
+            Truth::False => Truth::True,
+            Truth::Unknown => Truth::Unknown,
+        }
+    }
+
+    fn and(self, other: Self) -> Self {
+        match (self, other) {
+            (Truth::False, _) | (_, Truth::False) => Truth::False,
+            (Truth::True, Truth::True) => Truth::True,
+            _ => Truth::Unknown,
+        }
+    }
+
+    fn or(self, other: Self) -> Self {
+        self.not().and(other.not()).not()
+    }
+}
+
+impl Display for Truth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Truth::True => write!(f, "true"),
+            Truth::False => write!(f, "false"),
+            Truth::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Bounds {
+    Number(Interval),
+    Bool(Truth),
+}
+
+impl Bounds {
+    // 和求值器一样, bool 参与算术和比较时按 0/1 处理
+    fn interval(self) -> Interval {
+        match self {
+            Bounds::Number(i) => i,
+            Bounds::Bool(Truth::True) => Interval::point(1.0),
+            Bounds::Bool(Truth::False) => Interval::point(0.0),
+            Bounds::Bool(Truth::Unknown) => Interval { lo: 0.0, hi: 1.0 },
+        }
+    }
+
+    // && 和 || 使用的真值: 数字大于 0 为真
+    fn truth(self) -> Truth {
+        match self {
+            Bounds::Bool(t) => t,
+            Bounds::Number(i) if i.lo > 0.0 => Truth::True,
+            Bounds::Number(i) if i.hi <= 0.0 => Truth::False,
+            Bounds::Number(_) => Truth::Unknown,
+        }
+    }
+}
+
+impl Display for Bounds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Bounds::Number(i) => write!(f, "{i}"),
+            Bounds::Bool(t) => write!(f, "{t}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub bounds: Bounds,
+    pub warnings: Vec<String>,
+}
+
+pub fn analyze(expr: &Expr, inputs: &HashMap<String, Interval>) -> anyhow::Result<Analysis> {
+    let mut analyzer = Analyzer { inputs, scope: Vec::new(), warnings: Vec::new() };
+    let bounds = analyzer.eval(expr)?;
+    Ok(Analysis { bounds, warnings: analyzer.warnings })
+}
+
+struct Analyzer<'a> {
+    inputs: &'a HashMap<String, Interval>,
+    scope: Vec<(String, Bounds)>,
+    warnings: Vec<String>,
+}
+
+impl Analyzer<'_> {
+    fn warn(&mut self, message: String) {
+        if !self.warnings.contains(&message) {
+            self.warnings.push(message);
+        }
+    }
+
+    fn eval(&mut self, expr: &Expr) -> anyhow::Result<Bounds> {
+        Ok(match expr {
+            Expr::Number(n) => Bounds::Number(Interval::point(*n)),
+            Expr::Error => anyhow::bail!("表达式有语法错误"),
+            Expr::Bool(b) => Bounds::Bool(if *b { Truth::True } else { Truth::False }),
+            Expr::Var(name) => match self.scope.iter().rev().find(|(s, _)| s == name) {
+                Some((_, b)) => *b,
+                None => match self.inputs.get(name) {
+                    Some(i) => Bounds::Number(*i),
+                    None => anyhow::bail!("未定义的变量: {name}"),
+                },
+            },
+            Expr::UnaryOp { op, expr: inner } => match (op, self.eval(inner)?) {
+                (BinaryOp::Add, b) => b,
+                (BinaryOp::Sub, Bounds::Bool(t)) | (BinaryOp::Not, Bounds::Bool(t)) => Bounds::Bool(t.not()),
+                (BinaryOp::Sub, Bounds::Number(i)) => Bounds::Number(i.neg()),
+                (BinaryOp::Not, Bounds::Number(i)) => Bounds::Bool(equal(i, Interval::point(0.0))),
+                _ => anyhow::bail!("区间求值不支持: {expr}"),
+            },
+            Expr::BinaryOp { left, op: op @ (BinaryOp::And | BinaryOp::Or), right } => {
+                // 左边已经能决定结果时右边不会被求值
+                let l = self.eval(left)?.truth();
+                match (op, l) {
+                    (BinaryOp::And, Truth::False) => Bounds::Bool(Truth::False),
+                    (BinaryOp::Or, Truth::True) => Bounds::Bool(Truth::True),
+                    (BinaryOp::And, _) => Bounds::Bool(l.and(self.eval(right)?.truth())),
+                    _ => Bounds::Bool(l.or(self.eval(right)?.truth())),
+                }
+            }
+            Expr::BinaryOp { left, op, right } => {
+                let (l, r) = (self.eval(left)?, self.eval(right)?);
+                self.binary(expr, *op, l.interval(), r.interval())?
+            }
+            Expr::Compare { first, rest } => {
+                let mut l = self.eval(first)?.interval();
+                let mut result = Truth::True;
+                for (op, e) in rest {
+                    let r = self.eval(e)?.interval();
+                    result = result.and(compare(*op, l, r)?);
+                    if result == Truth::False {
+                        break;
+                    }
+                    l = r;
+                }
+                Bounds::Bool(result)
+            }
+            Expr::Call { name, args } => {
+                let args = args.iter().map(|a| Ok(self.eval(a)?.interval())).collect::<anyhow::Result<Vec<_>>>()?;
+                Bounds::Number(self.call(expr, name, &args)?)
+            }
+            Expr::Let { name, value, body } => {
+                let value = self.eval(value)?;
+                self.scope.push((name.clone(), value));
+                let result = self.eval(body);
+                self.scope.pop();
+                result?
+            }
+            Expr::Str(_) | Expr::List(_) => anyhow::bail!("区间求值不支持: {expr}"),
+        })
+    }
+
+    fn binary(&mut self, expr: &Expr, op: BinaryOp, l: Interval, r: Interval) -> anyhow::Result<Bounds> {
+        Ok(Bounds::Number(match op {
+            BinaryOp::Add => l.add(r),
+            BinaryOp::Sub => l.sub(r),
+            BinaryOp::Mul => l.mul(r),
+            BinaryOp::Div => {
+                if r.lo == 0.0 && r.hi == 0.0 {
+                    anyhow::bail!("除以零错误: {expr}");
+                }
+                if r.contains(0.0) {
+                    self.warn(format!("可能除以零: {expr}"));
+                }
+                l.mul(r.recip())
+            }
+            BinaryOp::Pow => self.pow(expr, l, r),
+            _ => return Ok(Bounds::Bool(compare(op, l, r)?)),
+        }))
+    }
+
+    fn pow(&mut self, expr: &Expr, base: Interval, exp: Interval) -> Interval {
+        base.pow(exp).unwrap_or_else(|| {
+            self.warn(format!("结果可能不是实数: {expr}"));
+            Interval::whole()
+        })
+    }
+
+    fn call(&mut self, expr: &Expr, name: &str, args: &[Interval]) -> anyhow::Result<Interval> {
+        if let [a, b] = args {
+            return Ok(match name {
+                "min" => Interval { lo: a.lo.min(b.lo), hi: a.hi.min(b.hi) },
+                "max" => Interval { lo: a.lo.max(b.lo), hi: a.hi.max(b.hi) },
+                "pow" => self.pow(expr, *a, *b),
+                _ => anyhow::bail!("区间求值不支持函数: {name}"),
+            });
+        }
+        let [x] = args else { anyhow::bail!("区间求值不支持函数: {name}") };
+        let mut x = *x;
+        // 对数和开方只在 x >= 0 上有实数结果
+        if matches!(name, "sqrt" | "ln" | "log10") {
+            if x.hi < 0.0 {
+                anyhow::bail!("参数超出定义域: {expr}, 参数范围 {x}");
+            }
+            if x.lo < 0.0 {
+                self.warn(format!("参数可能超出定义域: {expr}"));
+                x.lo = 0.0;
+            }
+        }
+        Ok(match name {
+            "sqrt" => x.map(f64::sqrt),
+            "ln" => x.map(f64::ln),
+            "log10" => x.map(f64::log10),
+            "exp" => x.map(f64::exp),
+            "floor" => x.map(f64::floor),
+            "ceil" => x.map(f64::ceil),
+            "round" => x.map(f64::round),
+            "abs" if x.lo >= 0.0 => x,
+            "abs" if x.hi <= 0.0 => x.neg(),
+            "abs" => Interval { lo: 0.0, hi: x.hi.max(-x.lo) },
+            "sin" => x.sin(),
+            // cos(x) = sin(x + pi/2)
+            "cos" => x.add(Interval::point(PI / 2.0)).sin(),
+            "tan" if x.hi - x.lo >= PI || x.hits(PI / 2.0, PI) => Interval::whole(),
+            "tan" => x.map(f64::tan),
+            _ => anyhow::bail!("区间求值不支持函数: {name}"),
+        })
+    }
+}
+
+fn equal(l: Interval, r: Interval) -> Truth {
+    if l.lo == l.hi && r.lo == r.hi && l.lo == r.lo {
+        Truth::True
+    } else if l.hi < r.lo || r.hi < l.lo {
+        Truth::False
+    } else {
+        Truth::Unknown
+    }
+}
+
+fn compare(op: BinaryOp, l: Interval, r: Interval) -> anyhow::Result<Truth> {
+    let less = |l: Interval, r: Interval| {
+        if l.hi < r.lo {
+            Truth::True
+        } else if l.lo >= r.hi {
+            Truth::False
+        } else {
+            Truth::Unknown
+        }
+    };
+    Ok(match op {
+        BinaryOp::Eq => equal(l, r),
+        BinaryOp::Neq => equal(l, r).not(),
+        BinaryOp::Lt => less(l, r),
+        BinaryOp::Gt => less(r, l),
+        BinaryOp::Lte => less(r, l).not(),
+        BinaryOp::Gte => less(l, r).not(),
+        _ => anyhow::bail!("区间求值不支持运算符: {op}"),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::{eval_with, Context, EvalPolicy, Value},
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn analyze_str(input: &str, inputs: &[(&str, f64, f64)]) -> anyhow::Result<Analysis> {
+        let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        let inputs = inputs.iter().map(|(n, lo, hi)| (n.to_string(), Interval::new(*lo, *hi).unwrap())).collect();
+        analyze(&expr, &inputs)
+    }
+
+    fn bounds(input: &str, inputs: &[(&str, f64, f64)]) -> String {
+        analyze_str(input, inputs).unwrap().bounds.to_string()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let x = [("x", 0.0, 10.0)];
+        assert_eq!(bounds("x * 2 + 1", &x), "[1, 21]");
+        assert_eq!(bounds("x - x", &x), "[-10, 10]");
+        assert_eq!(bounds("-x * (x - 5)", &x), "[-50, 50]");
+        assert_eq!(bounds("x ^ 2", &[("x", -3.0, 2.0)]), "[0, 9]");
+        assert_eq!(bounds("x ^ 3", &[("x", -3.0, 2.0)]), "[-27, 8]");
+        assert_eq!(bounds("2 ^ x", &[("x", -1.0, 3.0)]), "[0.5, 8]");
+        assert!(Interval::new(2.0, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(bounds("sqrt(x) + abs(x - 4)", &[("x", 1.0, 9.0)]), "[1, 8]");
+        assert_eq!(bounds("sin(x)", &[("x", 0.0, 2.0)]), "[0, 1]");
+        assert_eq!(bounds("cos(x)", &[("x", 0.0, 4.0)]), "[-1, 1]");
+        assert_eq!(bounds("max(x, 3)", &[("x", 0.0, 10.0)]), "[3, 10]");
+        assert_eq!(bounds("tan(x)", &[("x", 1.0, 2.0)]), "[-inf, inf]");
+
+        let result = analyze_str("ln(x)", &[("x", -1.0, 1.0)]).unwrap();
+        assert_eq!(result.bounds.to_string(), "[-inf, 0]");
+        assert_eq!(result.warnings, ["参数可能超出定义域: ln(x)"]);
+        assert!(analyze_str("sqrt(x)", &[("x", -2.0, -1.0)]).is_err());
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let x = [("x", 0.0, 10.0)];
+        assert_eq!(bounds("x >= 0", &x), "true");
+        assert_eq!(bounds("x > 10", &x), "false");
+        assert_eq!(bounds("x > 5", &x), "unknown");
+        assert_eq!(bounds("0 <= x <= 10", &x), "true");
+        assert_eq!(bounds("x > 5 || x < 20", &x), "true");
+        assert_eq!(bounds("x > 20 && 1 / 0 > 1", &x), "false");
+        assert_eq!(bounds("!(x < 0)", &x), "true");
+        assert_eq!(bounds("(x > 20) + 1", &x), "[1, 1]");
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let result = analyze_str("1 / (x - 1)", &[("x", 0.0, 2.0)]).unwrap();
+        assert_eq!(result.bounds.to_string(), "[-inf, inf]");
+        assert_eq!(result.warnings, ["可能除以零: (1 / (x - 1))"]);
+
+        let result = analyze_str("1 / x", &[("x", 0.0, 2.0)]).unwrap();
+        assert_eq!(result.bounds.to_string(), "[0.5, inf]");
+        assert_eq!(result.warnings.len(), 1);
+
+        assert!(analyze_str("1 / (x - x)", &[("x", 1.0, 1.0)]).is_err());
+        assert!(analyze_str("10 / (x + 1)", &[("x", 0.0, 2.0)]).unwrap().warnings.is_empty());
+    }
+
+    // 区间内任意取点求值, 结果都要落在推算出的范围里
+    #[test]
+    fn test_sound_random() {
+        let policy = EvalPolicy::default();
+        let inputs = HashMap::from([("x".to_string(), Interval { lo: -2.0, hi: 3.0 })]);
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        for _ in 0..5000 {
+            let depth = 1 + rng.below(4) as u32;
+            let expr = random_expr(&mut rng, depth);
+            let Ok(analysis) = analyze(&expr, &inputs) else { continue };
+            for x in [-2.0, -1.5, -1.0, -0.25, 0.0, 0.5, 1.0, 2.0, 2.75, 3.0] {
+                let mut ctx = Context::new();
+                ctx.set("x", x);
+                match (eval_with(&expr, &ctx, &policy), analysis.bounds) {
+                    (Err(_), _) => {}
+                    (Ok(Value::Number(n)), _) if n.is_nan() => {}
+                    (Ok(Value::Number(n)), Bounds::Number(i)) => {
+                        let eps = 1e-9 * (1.0 + n.abs());
+                        assert!(i.lo - eps <= n && n <= i.hi + eps, "{expr} at x = {x}: {n} not in {i}");
+                    }
+                    (Ok(Value::Bool(b)), Bounds::Bool(t)) => {
+                        assert!(t == Truth::Unknown || (t == Truth::True) == b, "{expr} at x = {x}: {b} vs {t}");
+                    }
+                    (value, bounds) => panic!("{expr} at x = {x}: {value:?} vs {bounds}"),
+                }
+            }
+        }
+    }
+}
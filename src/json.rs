@@ -0,0 +1,584 @@
+// 语法树和值的 JSON 格式, 用来保存解析好的规则或在服务之间传递 (feature = "json")
+//
+// 最外层带版本号:
+//   {"version":1,"expr":<表达式>}
+//   {"version":1,"value":<值>}
+//
+// 表达式按 "type" 区分:
+//   {"type":"number","value":1.5}
+//   {"type":"str","value":"abc"}
+//   {"type":"var","name":"x"}
+//   {"type":"bool","value":true}
+//   {"type":"unary","op":"-","expr":<表达式>}                   op 只能是 "+", "-", "!"
+//   {"type":"binary","op":"+","left":<表达式>,"right":<表达式>}
+//   {"type":"list","items":[<表达式>,...]}
+//   {"type":"compare","first":<表达式>,"rest":[{"op":"<","expr":<表达式>},...]}
+//   {"type":"call","name":"max","args":[<表达式>,...]}
+//   {"type":"let","name":"$0","value":<表达式>,"body":<表达式>}
+//   {"type":"error"}                                            出错恢复时的占位
+// 运算符用源码中的写法: + - * / ^ == != > >= < <= && || .. ..= in "not in"
+//
+// 值:
+//   {"type":"number","value":1.5}
+//   {"type":"bool","value":true}
+//   {"type":"str","value":"abc"}
+//   {"type":"range","start":1,"end":10,"inclusive":false}
+//   {"type":"list","items":[<值>,...]}
+//
+// JSON 没有无穷和 NaN, 这些数字写成字符串 "inf", "-inf", "NaN".
+// 读取时版本不对, 缺字段或类型不认识都会报错, 多余的字段忽略.
+// 数组和对象最多嵌套 MAX_DEPTH 层, 避免恶意数据递归太深把栈用完.
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+
+use crate::{ast::{BinaryOp, Expr}, eval::Value};
+
+pub const VERSION: u32 = 1;
+pub const MAX_DEPTH: usize = 512;
+
+pub fn expr_to_json(expr: &Expr) -> String {
+    envelope("expr", encode_expr(expr))
+}
+
+pub fn expr_from_json(input: &str) -> Result<Expr> {
+    decode_expr(open_envelope(input, "expr")?)
+}
+
+pub fn value_to_json(value: &Value) -> String {
+    envelope("value", encode_value(value))
+}
+
+pub fn value_from_json(input: &str) -> Result<Value> {
+    decode_value(open_envelope(input, "value")?)
+}
+
+fn envelope(key: &str, content: Json) -> String {
+    let json = Json::Object(vec![("version".to_string(), Json::Number(VERSION as f64)), (key.to_string(), content)]);
+    let mut out = String::new();
+    json.write(&mut out);
+    out
+}
+
+fn open_envelope(input: &str, key: &str) -> Result<Json> {
+    let mut json = parse(input)?;
+    let version = json.field("version")?.number()?;
+    if version != VERSION as f64 {
+        anyhow::bail!("不支持的版本: {version}, 当前版本是 {VERSION}");
+    }
+    json.take(key)
+}
+
+fn node(kind: &str, fields: Vec<(&str, Json)>) -> Json {
+    let mut object = vec![("type".to_string(), Json::Str(kind.to_string()))];
+    object.extend(fields.into_iter().map(|(k, v)| (k.to_string(), v)));
+    Json::Object(object)
+}
+
+fn number(n: f64) -> Json {
+    match n {
+        _ if n.is_finite() => Json::Number(n),
+        _ if n.is_nan() => Json::Str("NaN".to_string()),
+        _ if n > 0.0 => Json::Str("inf".to_string()),
+        _ => Json::Str("-inf".to_string()),
+    }
+}
+
+fn op(op: BinaryOp) -> Json {
+    Json::Str(op.to_string())
+}
+
+fn encode_expr(expr: &Expr) -> Json {
+    let list = |items: &[Expr]| Json::Array(items.iter().map(encode_expr).collect());
+    match expr {
+        Expr::Number(n) => node("number", vec![("value", number(*n))]),
+        Expr::Str(s) => node("str", vec![("value", Json::Str(s.clone()))]),
+        Expr::Var(name) => node("var", vec![("name", Json::Str(name.clone()))]),
+        Expr::Bool(b) => node("bool", vec![("value", Json::Bool(*b))]),
+        Expr::UnaryOp { op: o, expr } => node("unary", vec![("op", op(*o)), ("expr", encode_expr(expr))]),
+        Expr::BinaryOp { left, op: o, right } => {
+            node("binary", vec![("op", op(*o)), ("left", encode_expr(left)), ("right", encode_expr(right))])
+        }
+        Expr::List(items) => node("list", vec![("items", list(items))]),
+        Expr::Compare { first, rest } => {
+            let rest = rest
+                .iter()
+                .map(|(o, e)| Json::Object(vec![("op".to_string(), op(*o)), ("expr".to_string(), encode_expr(e))]))
+                .collect();
+            node("compare", vec![("first", encode_expr(first)), ("rest", Json::Array(rest))])
+        }
+        Expr::Call { name, args } => node("call", vec![("name", Json::Str(name.clone())), ("args", list(args))]),
+        Expr::Let { name, value, body } => node(
+            "let",
+            vec![("name", Json::Str(name.clone())), ("value", encode_expr(value)), ("body", encode_expr(body))],
+        ),
+        Expr::Error => node("error", vec![]),
+    }
+}
+
+fn encode_value(value: &Value) -> Json {
+    match value {
+        Value::Number(n) => node("number", vec![("value", number(*n))]),
+        Value::Bool(b) => node("bool", vec![("value", Json::Bool(*b))]),
+        Value::Str(s) => node("str", vec![("value", Json::Str(s.clone()))]),
+        Value::Range { start, end, inclusive } => node(
+            "range",
+            vec![("start", number(*start)), ("end", number(*end)), ("inclusive", Json::Bool(*inclusive))],
+        ),
+        Value::List(items) => node("list", vec![("items", Json::Array(items.iter().map(encode_value).collect()))]),
+    }
+}
+
+fn decode_op(json: &Json) -> Result<BinaryOp> {
+    let symbol = json.str()?;
+    BinaryOp::ALL.into_iter().find(|op| op.to_string() == symbol).with_context(|| format!("未知的运算符: {symbol}"))
+}
+
+fn decode_expr(mut json: Json) -> Result<Expr> {
+    let boxed = |json: Json| decode_expr(json).map(Box::new);
+    let list = |json: Json| json.array()?.into_iter().map(decode_expr).collect::<Result<Vec<_>>>();
+    let kind = json.field("type")?.str()?.to_string();
+    let expr = match kind.as_str() {
+        "number" => Expr::Number(json.field("value")?.number()?),
+        "str" => Expr::Str(json.field("value")?.str()?.to_string()),
+        "var" => Expr::Var(json.field("name")?.str()?.to_string()),
+        "bool" => Expr::Bool(json.field("value")?.bool()?),
+        "unary" => {
+            let op = decode_op(json.field("op")?)?;
+            if !matches!(op, BinaryOp::Add | BinaryOp::Sub | BinaryOp::Not) {
+                anyhow::bail!("不是单目运算符: {op}");
+            }
+            Expr::UnaryOp { op, expr: boxed(json.take("expr")?)? }
+        }
+        "binary" => {
+            let op = decode_op(json.field("op")?)?;
+            if op == BinaryOp::Not {
+                anyhow::bail!("不是双目运算符: {op}");
+            }
+            Expr::BinaryOp { left: boxed(json.take("left")?)?, op, right: boxed(json.take("right")?)? }
+        }
+        "list" => Expr::List(list(json.take("items")?)?),
+        "compare" => {
+            let first = boxed(json.take("first")?)?;
+            let rest = json
+                .take("rest")?
+                .array()?
+                .into_iter()
+                .map(|mut item| Ok((decode_op(item.field("op")?)?, decode_expr(item.take("expr")?)?)))
+                .collect::<Result<Vec<_>>>()?;
+            if rest.is_empty() {
+                anyhow::bail!("连续比较至少要有一个运算符");
+            }
+            Expr::Compare { first, rest }
+        }
+        "call" => Expr::Call { name: json.field("name")?.str()?.to_string(), args: list(json.take("args")?)? },
+        "let" => Expr::Let {
+            name: json.field("name")?.str()?.to_string(),
+            value: boxed(json.take("value")?)?,
+            body: boxed(json.take("body")?)?,
+        },
+        "error" => Expr::Error,
+        _ => anyhow::bail!("未知的表达式类型: {kind}"),
+    };
+    Ok(expr)
+}
+
+fn decode_value(mut json: Json) -> Result<Value> {
+    let kind = json.field("type")?.str()?.to_string();
+    let value = match kind.as_str() {
+        "number" => Value::Number(json.field("value")?.number()?),
+        "bool" => Value::Bool(json.field("value")?.bool()?),
+        "str" => Value::Str(json.field("value")?.str()?.to_string()),
+        "range" => Value::Range {
+            start: json.field("start")?.number()?,
+            end: json.field("end")?.number()?,
+            inclusive: json.field("inclusive")?.bool()?,
+        },
+        "list" => Value::List(json.take("items")?.array()?.into_iter().map(decode_value).collect::<Result<_>>()?),
+        _ => anyhow::bail!("未知的值类型: {kind}"),
+    };
+    Ok(value)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    // 保持字段顺序, 输出稳定
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn write(&self, out: &mut String) {
+        match self {
+            Json::Null => out.push_str("null"),
+            Json::Bool(b) => write!(out, "{b}").unwrap(),
+            Json::Number(n) => write!(out, "{n}").unwrap(),
+            Json::Str(s) => write_str(out, s),
+            Json::Array(items) => {
+                out.push('[');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    item.write(out);
+                }
+                out.push(']');
+            }
+            Json::Object(fields) => {
+                out.push('{');
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    write_str(out, key);
+                    out.push(':');
+                    value.write(out);
+                }
+                out.push('}');
+            }
+        }
+    }
+
+    fn field(&self, key: &str) -> Result<&Json> {
+        match self {
+            Json::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v).with_context(|| format!("缺少字段: {key}")),
+            _ => anyhow::bail!("应该是对象"),
+        }
+    }
+
+    // 取出字段, 避免复制整棵子树
+    fn take(&mut self, key: &str) -> Result<Json> {
+        match self {
+            Json::Object(fields) => fields
+                .iter_mut()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| std::mem::replace(v, Json::Null))
+                .with_context(|| format!("缺少字段: {key}")),
+            _ => anyhow::bail!("应该是对象"),
+        }
+    }
+
+    fn number(&self) -> Result<f64> {
+        match self {
+            Json::Number(n) => Ok(*n),
+            Json::Str(s) if s == "inf" => Ok(f64::INFINITY),
+            Json::Str(s) if s == "-inf" => Ok(f64::NEG_INFINITY),
+            Json::Str(s) if s == "NaN" => Ok(f64::NAN),
+            _ => anyhow::bail!("应该是数字"),
+        }
+    }
+
+    fn str(&self) -> Result<&str> {
+        match self {
+            Json::Str(s) => Ok(s),
+            _ => anyhow::bail!("应该是字符串"),
+        }
+    }
+
+    fn bool(&self) -> Result<bool> {
+        match self {
+            Json::Bool(b) => Ok(*b),
+            _ => anyhow::bail!("应该是 true 或 false"),
+        }
+    }
+
+    fn array(self) -> Result<Vec<Json>> {
+        match self {
+            Json::Array(items) => Ok(items),
+            _ => anyhow::bail!("应该是数组"),
+        }
+    }
+}
+
+fn write_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn parse(input: &str) -> Result<Json> {
+    let mut parser = JsonParser { chars: input.chars().collect(), pos: 0, depth: 0 };
+    let json = parser.value()?;
+    parser.skip_whitespace();
+    if parser.pos < parser.chars.len() {
+        anyhow::bail!("JSON 末尾有多余的内容, 位置 {}", parser.pos);
+    }
+    Ok(json)
+}
+
+struct JsonParser {
+    chars: Vec<char>,
+    pos: usize,
+    depth: usize, // 当前所在的数组和对象层数
+}
+
+impl JsonParser {
+    fn skip_whitespace(&mut self) {
+        while let Some(' ' | '\t' | '\n' | '\r') = self.chars.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn next(&mut self) -> Result<char> {
+        let c = *self.chars.get(self.pos).context("JSON 不完整")?;
+        self.pos += 1;
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.next()? {
+            c if c == expected => Ok(()),
+            c => anyhow::bail!("JSON 格式错误: 位置 {} 应该是 `{expected}`, 实际是 `{c}`", self.pos - 1),
+        }
+    }
+
+    fn literal(&mut self, word: &str, json: Json) -> Result<Json> {
+        for expected in word.chars() {
+            if self.next()? != expected {
+                anyhow::bail!("JSON 格式错误: 位置 {} 应该是 {word}", self.pos - 1);
+            }
+        }
+        Ok(json)
+    }
+
+    fn value(&mut self) -> Result<Json> {
+        self.skip_whitespace();
+        match self.chars.get(self.pos).copied().context("JSON 不完整")? {
+            'n' => self.literal("null", Json::Null),
+            't' => self.literal("true", Json::Bool(true)),
+            'f' => self.literal("false", Json::Bool(false)),
+            '"' => Ok(Json::Str(self.string()?)),
+            c @ ('[' | '{') => {
+                self.depth += 1;
+                if self.depth > MAX_DEPTH {
+                    anyhow::bail!("JSON 嵌套超过 {MAX_DEPTH} 层");
+                }
+                self.pos += 1;
+                let json = if c == '[' { self.array() } else { self.object() };
+                self.depth -= 1;
+                json
+            }
+            '-' | '0'..='9' => {
+                let start = self.pos;
+                while let Some('-' | '+' | '.' | 'e' | 'E' | '0'..='9') = self.chars.get(self.pos) {
+                    self.pos += 1;
+                }
+                let text: String = self.chars[start..self.pos].iter().collect();
+                Ok(Json::Number(text.parse().with_context(|| format!("JSON 数字格式错误: {text}"))?))
+            }
+            c => anyhow::bail!("JSON 格式错误: 位置 {} 不能是 `{c}`", self.pos),
+        }
+    }
+
+    // `[` 已经吃掉
+    fn array(&mut self) -> Result<Json> {
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&']') {
+            self.pos += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => {}
+                ']' => return Ok(Json::Array(items)),
+                c => anyhow::bail!("JSON 格式错误: 位置 {} 应该是 `,` 或 `]`, 实际是 `{c}`", self.pos - 1),
+            }
+        }
+    }
+
+    // `{` 已经吃掉
+    fn object(&mut self) -> Result<Json> {
+        let mut fields = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.pos) == Some(&'}') {
+            self.pos += 1;
+            return Ok(Json::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.string()?;
+            self.expect(':')?;
+            fields.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.next()? {
+                ',' => {}
+                '}' => return Ok(Json::Object(fields)),
+                c => anyhow::bail!("JSON 格式错误: 位置 {} 应该是 `,` 或 `}}`, 实际是 `{c}`", self.pos - 1),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if self.next()? != '"' {
+            anyhow::bail!("JSON 格式错误: 位置 {} 应该是字符串", self.pos - 1);
+        }
+        let mut s = String::new();
+        loop {
+            match self.next()? {
+                '"' => return Ok(s),
+                '\\' => match self.next()? {
+                    '"' => s.push('"'),
+                    '\\' => s.push('\\'),
+                    '/' => s.push('/'),
+                    'b' => s.push('\u{8}'),
+                    'f' => s.push('\u{c}'),
+                    'n' => s.push('\n'),
+                    'r' => s.push('\r'),
+                    't' => s.push('\t'),
+                    'u' => {
+                        let high = self.hex4()?;
+                        // 基本平面以外的字符用一对代理项表示
+                        let code = if (0xd800..0xdc00).contains(&high) {
+                            if self.next()? != '\\' || self.next()? != 'u' {
+                                anyhow::bail!("JSON 字符串中的代理项不完整");
+                            }
+                            let low = self.hex4()?;
+                            0x10000 + ((high - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff)
+                        } else {
+                            high
+                        };
+                        s.push(char::from_u32(code).with_context(|| format!("无效的字符: \\u{code:04x}"))?);
+                    }
+                    c => anyhow::bail!("未知的转义字符: \\{c}"),
+                },
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let c = self.next()?;
+            code = code * 16 + c.to_digit(16).with_context(|| format!("无效的十六进制数字: {c}"))?;
+        }
+        Ok(code)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn parse_expr(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_expr_format() {
+        let json = expr_to_json(&parse_expr("-x + max(1, \"a\\\"\") in [true]"));
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"version":1,"expr":{"type":"binary","op":"in","left":{"type":"binary","op":"+","#,
+                r#""left":{"type":"unary","op":"-","expr":{"type":"var","name":"x"}},"#,
+                r#""right":{"type":"call","name":"max","args":[{"type":"number","value":1},{"type":"str","value":"a\""}]}},"#,
+                r#""right":{"type":"list","items":[{"type":"bool","value":true}]}}}"#,
+            )
+        );
+        let compare = expr_to_json(&parse_expr("0 <= x < 1"));
+        assert!(compare.contains(r#""rest":[{"op":"<=","expr":{"type":"var","name":"x"}},{"op":"<","expr""#), "{compare}");
+    }
+
+    #[test]
+    fn test_expr_round_trip() {
+        let mut rng = Rng(0x15_0a);
+        for _ in 0..500 {
+            let expr = random_expr(&mut rng, 5);
+            assert_eq!(expr_from_json(&expr_to_json(&expr)).unwrap(), expr);
+        }
+        let expr = Expr::Let {
+            name: "$0".to_string(),
+            value: Box::new(Expr::Number(f64::NEG_INFINITY)),
+            body: Box::new(Expr::Str("换行\n\u{1}😀".to_string())),
+        };
+        assert_eq!(expr_from_json(&expr_to_json(&expr)).unwrap(), expr);
+        let expr = Expr::List(vec![Expr::Number(1.0), Expr::Error]);
+        assert_eq!(expr_from_json(&expr_to_json(&expr)).unwrap(), expr);
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let values = [
+            Value::Number(-0.5),
+            Value::Number(f64::INFINITY),
+            Value::Bool(false),
+            Value::Str("a\\b".to_string()),
+            Value::Range { start: 1.0, end: 10.0, inclusive: true },
+            Value::List(vec![Value::Number(1e300), Value::List(vec![]), Value::Str(String::new())]),
+        ];
+        for value in values {
+            assert_eq!(value_from_json(&value_to_json(&value)).unwrap(), value);
+        }
+        assert_eq!(value_to_json(&Value::Number(f64::NAN)), r#"{"version":1,"value":{"type":"number","value":"NaN"}}"#);
+        let Value::Number(n) = value_from_json(&value_to_json(&Value::Number(f64::NAN))).unwrap() else { panic!() };
+        assert!(n.is_nan());
+    }
+
+    #[test]
+    fn test_read_handwritten() {
+        let input = r#" {
+            "expr": {"type": "binary", "op": "not in", "comment": "忽略",
+                     "left": {"type": "str", "value": "é😀\/"},
+                     "right": {"type": "list", "items": []}},
+            "version": 1.0
+        } "#;
+        assert_eq!(expr_from_json(input).unwrap().to_string(), "(\"é😀/\" not in [])");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |input: &str| expr_from_json(input).unwrap_err().to_string();
+        assert_eq!(error(r#"{"version":2,"expr":{"type":"var","name":"x"}}"#), "不支持的版本: 2, 当前版本是 1");
+        assert_eq!(error(r#"{"expr":{"type":"var","name":"x"}}"#), "缺少字段: version");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"var"}}"#), "缺少字段: name");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"lambda"}}"#), "未知的表达式类型: lambda");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"unary","op":"*","expr":{"type":"bool","value":true}}}"#), "不是单目运算符: *");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"binary","op":"%"}}"#), "未知的运算符: %");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"compare","first":{"type":"bool","value":true},"rest":[]}}"#), "连续比较至少要有一个运算符");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"number","value":"1"}}"#), "应该是数字");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"bool","value":true}} x"#), "JSON 末尾有多余的内容, 位置 50");
+        assert_eq!(error(r#"{"version":1,"expr":{"type":"bool","value":true}"#), "JSON 不完整");
+        assert_eq!(error(r#"{"version":1 "expr":1}"#), "JSON 格式错误: 位置 13 应该是 `,` 或 `}`, 实际是 `\"`");
+        assert!(value_from_json(&expr_to_json(&Expr::Bool(true))).is_err());
+    }
+
+    #[test]
+    fn test_max_depth() {
+        // 每层一个对象: {"type":"unary","op":"-","expr":...}, 加上最外层的信封
+        let nested = |depth: usize| {
+            let unary = r#"{"type":"unary","op":"-","expr":"#;
+            format!(r#"{{"version":1,"expr":{}{{"type":"var","name":"x"}}{}}}"#, unary.repeat(depth), "}".repeat(depth))
+        };
+        assert!(expr_from_json(&nested(MAX_DEPTH - 2)).is_ok());
+        assert_eq!(expr_from_json(&nested(MAX_DEPTH - 1)).unwrap_err().to_string(), format!("JSON 嵌套超过 {MAX_DEPTH} 层"));
+        assert_eq!(expr_from_json(&nested(100_000)).unwrap_err().to_string(), format!("JSON 嵌套超过 {MAX_DEPTH} 层"));
+
+        let list = format!(r#"{{"version":1,"value":{}"#, "[".repeat(200_000));
+        assert_eq!(value_from_json(&list).unwrap_err().to_string(), format!("JSON 嵌套超过 {MAX_DEPTH} 层"));
+    }
+}
@@ -1,16 +1,20 @@
 // 词法分析
 
-use std::fmt::Display;
+use std::{fmt::Display, iter::Peekable, str::Chars};
 
 use anyhow::{Context, Result};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Number(f64),
+    Str(String),
+    Ident(String),
+    Bool(bool),
     Plus,
     Minus,
     Star,
     Slash,
+    Caret,      // ^
     LParen,
     RParen,
     Equal,      // ==
@@ -21,17 +25,31 @@ pub enum Token {
     LessEqual,
     And,
     Or,
-    Not
+    Not,
+    LBracket,   // [
+    RBracket,   // ]
+    Comma,
+    DotDot,     // ..
+    DotDotEq,   // ..=
+    In,         // in
+    NotIn,      // not in
+    // `//` 到行尾的注释; own_line 表示它前面同一行没有别的 token.
+    // tokenize 会丢掉注释, 只有 tokenize_with_comments 才保留 (给格式化用)
+    Comment { text: String, own_line: bool },
 }
 
 impl Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Token::Number(n) => write!(f, "{}", n),
+            Token::Str(s) => write!(f, "{:?}", s),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Bool(b) => write!(f, "{}", b),
             Token::Plus => write!(f, "+"),
             Token::Minus => write!(f, "-"),
             Token::Star => write!(f, "*"),
             Token::Slash => write!(f, "/"),
+            Token::Caret => write!(f, "^"),
             Token::LParen => write!(f, "("),
             Token::RParen => write!(f, ")"),
             Token::Equal => write!(f, "=="),
@@ -43,19 +61,49 @@ impl Display for Token {
             Token::And => write!(f, "&&"),
             Token::Or => write!(f, "||"),
             Token::Not => write!(f, "!"),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::Comma => write!(f, ","),
+            Token::DotDot => write!(f, ".."),
+            Token::DotDotEq => write!(f, "..="),
+            Token::In => write!(f, "in"),
+            Token::NotIn => write!(f, "not in"),
+            Token::Comment { text, .. } => write!(f, "//{}", text),
         }
     }
 }
 
 pub fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = tokenize_with_comments(input)?;
+    tokens.retain(|t| !matches!(t, Token::Comment { .. }));
+    Ok(tokens)
+}
+
+pub fn tokenize_with_comments(input: &str) -> Result<Vec<Token>> {
     let mut tokens = Vec::new();
     let mut chars = input.chars().peekable();
+    // 最近一次换行时已有的 token 数, 用来判断注释是否独占一行
+    let mut tokens_at_newline = 0;
 
     while let Some(&ch) = chars.peek() {
         match ch {
+            '.' if starts_range(&chars) => {
+                chars.next();
+                chars.next();
+                if let Some('=') = chars.peek() {
+                    chars.next();
+                    tokens.push(Token::DotDotEq);
+                } else {
+                    tokens.push(Token::DotDot);
+                }
+            }
             '0' ..='9' | '.' => {
                 let mut number = String::new();
                 while let Some(&d) = chars.peek() {
+                    // `1..10` 中的 `..` 属于范围运算符, 不是数字的一部分
+                    if d == '.' && starts_range(&chars) {
+                        break;
+                    }
                     if d.is_numeric() || d == '.' {
                         number.push(d);
                         chars.next();
@@ -66,12 +114,56 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>> {
 
                 tokens.push(Token::Number(number.parse().with_context(|| format!("数字解析失败: {number}"))?));
             }
+            '"' => {
+                chars.next();
+                tokens.push(Token::Str(read_string(&mut chars)?));
+            }
             '+' => { tokens.push(Token::Plus); chars.next(); }
             '-' => { tokens.push(Token::Minus); chars.next(); }
             '*' => { tokens.push(Token::Star); chars.next(); }
-            '/' => { tokens.push(Token::Slash); chars.next(); }
+            '/' => {
+                chars.next();
+                if let Some('/') = chars.peek() {
+                    chars.next();
+                    let mut text = String::new();
+                    while let Some(&c) = chars.peek() {
+                        if c == '\n' {
+                            break;
+                        }
+                        text.push(c);
+                        chars.next();
+                    }
+                    let own_line = tokens.len() == tokens_at_newline;
+                    tokens.push(Token::Comment { text: text.trim_end().to_string(), own_line });
+                } else {
+                    tokens.push(Token::Slash);
+                }
+            }
+            '^' => { tokens.push(Token::Caret); chars.next(); }
             '(' => { tokens.push(Token::LParen); chars.next(); }
             ')' => { tokens.push(Token::RParen); chars.next(); }
+            '[' => { tokens.push(Token::LBracket); chars.next(); }
+            ']' => { tokens.push(Token::RBracket); chars.next(); }
+            ',' => { tokens.push(Token::Comma); chars.next(); }
+            'a'..='z' | 'A'..='Z' | '_' => {
+                let word = read_word(&mut chars);
+                match word.as_str() {
+                    "in" => tokens.push(Token::In),
+                    "true" => tokens.push(Token::Bool(true)),
+                    "false" => tokens.push(Token::Bool(false)),
+                    "not" => {
+                        // `not` 只能和 `in` 组成 `not in`
+                        while let Some(' ' | '\t' | '\n') = chars.peek() {
+                            chars.next();
+                        }
+                        if read_word(&mut chars) != "in" {
+                            anyhow::bail!("`not` 后面必须是 `in`");
+                        }
+                        tokens.push(Token::NotIn);
+                    }
+                    _ => tokens.push(Token::Ident(word)),
+                }
+            }
             '&' | '|' => {
                 chars.next();
                 match (ch, chars.peek()) {
@@ -131,16 +223,138 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>> {
                     }
                 }
             }
-            ' ' | '\t' | '\n' => { chars.next(); },
-            _ => { 
-                println!("{}", ch.to_ascii_lowercase() as u8);
-                anyhow::bail!("错误的字符: {ch}") 
+            '\n' => {
+                chars.next();
+                tokens_at_newline = tokens.len();
             }
+            ' ' | '\t' | '\r' => { chars.next(); },
+            _ => anyhow::bail!("错误的字符: {ch}"),
         }
     }
     Ok(tokens)
 }
 
+// 源文件里的一条语句, 可以跨多行; line 是第一行的行号 (从 1 开始)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub source: String,
+}
+
+// 把源文件分成语句, 脚本, 检查和格式化都按这个规则读文件:
+//   - 每行开始一条新语句, 除非上一行的括号没有闭合, 上一行以运算符或逗号结尾,
+//     或者这一行以只能出现在中间的运算符开头 (比如格式化断开的 `|| b`)
+//   - 以 `:` 开头的行是交互命令, 总是单独一条
+//   - 语句之间只有空行和注释的行也单独作为一段返回
+// 把所有段的 source 按顺序用换行连起来就是原文件. 词法错误的行不在这里报错, 留给解析时报告.
+pub fn split_statements(source: &str) -> Vec<Statement> {
+    let mut statements: Vec<Statement> = Vec::new();
+    // 空行和注释要看到下一行代码才知道属于哪条语句
+    let mut pending: Option<Statement> = None;
+    // 最后一段是代码 (不是命令), 后面的行可能接上它
+    let mut last_is_code = false;
+    // 最后一条语句还没结束: 括号没闭合或者以运算符结尾
+    let mut open = false;
+    let mut depth = 0usize;
+    for (i, line) in source.lines().enumerate() {
+        let command = line.trim_start().starts_with(':');
+        let tokens = if command { None } else { tokenize(line).ok() };
+        if tokens.as_ref().is_some_and(|t| t.is_empty()) {
+            match &mut pending {
+                Some(p) => {
+                    p.source.push('\n');
+                    p.source.push_str(line);
+                }
+                None => pending = Some(Statement { line: i + 1, source: line.to_string() }),
+            }
+            continue;
+        }
+
+        let first = tokens.as_ref().and_then(|t| t.first());
+        let continues = !command && last_is_code && (open || first.is_some_and(continues_statement));
+        match statements.last_mut() {
+            Some(last) if continues => {
+                for part in pending.take().iter().map(|p| p.source.as_str()).chain([line]) {
+                    last.source.push('\n');
+                    last.source.push_str(part);
+                }
+            }
+            _ => {
+                statements.extend(pending.take());
+                statements.push(Statement { line: i + 1, source: line.to_string() });
+                depth = 0;
+            }
+        }
+
+        last_is_code = !command;
+        open = false;
+        if let Some(tokens) = &tokens {
+            for token in tokens {
+                match token {
+                    Token::LParen | Token::LBracket => depth += 1,
+                    Token::RParen | Token::RBracket => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+            }
+            let complete = matches!(
+                tokens.last(),
+                Some(Token::Number(_) | Token::Str(_) | Token::Ident(_) | Token::Bool(_) | Token::RParen | Token::RBracket)
+            );
+            open = depth > 0 || !complete;
+        }
+    }
+    statements.extend(pending);
+    statements
+}
+
+// 不能开始一个表达式, 只能接在前面的表达式后面的 token
+fn continues_statement(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::Star | Token::Slash | Token::Caret | Token::Equal | Token::NotEqual | Token::Greater
+            | Token::GreaterEqual | Token::Less | Token::LessEqual | Token::And | Token::Or | Token::DotDot
+            | Token::DotDotEq | Token::In | Token::NotIn | Token::Comma | Token::RParen | Token::RBracket
+    )
+}
+
+// 当前位置是否是 `..` (范围运算符)
+fn starts_range(chars: &Peekable<Chars>) -> bool {
+    let mut ahead = chars.clone();
+    matches!((ahead.next(), ahead.next()), (Some('.'), Some('.')))
+}
+
+// 读取字符串字面量, 开头的 `"` 已经被吃掉
+fn read_string(chars: &mut Peekable<Chars>) -> Result<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c @ ('"' | '\\')) => s.push(c),
+                Some(c) => anyhow::bail!("未知的转义字符: \\{c}"),
+                None => anyhow::bail!("字符串没有结束"),
+            },
+            Some(c) => s.push(c),
+            None => anyhow::bail!("字符串没有结束"),
+        }
+    }
+}
+
+fn read_word(chars: &mut Peekable<Chars>) -> String {
+    let mut word = String::new();
+    while let Some(&c) = chars.peek() {
+        if c.is_ascii_alphanumeric() || c == '_' {
+            word.push(c);
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    word
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -148,13 +362,14 @@ mod tests {
 
     #[test]
     fn test_simple_operators() {
-        let input = "+ - * / ( )";
+        let input = "+ - * / ^ ( )";
         let tokens = tokenize(input).unwrap();
         let expected = vec![
             Token::Plus,
             Token::Minus,
             Token::Star,
             Token::Slash,
+            Token::Caret,
             Token::LParen,
             Token::RParen,
         ];
@@ -259,4 +474,86 @@ mod tests {
             Token::NotEqual,
         ]);
     }
+
+    #[test]
+    fn test_range_and_in() {
+        let input = "1..10 in [1, 2.5] not in 0..=3";
+        let tokens = tokenize(input).unwrap();
+        assert_eq!(tokens, vec![
+            Token::Number(1.0),
+            Token::DotDot,
+            Token::Number(10.0),
+            Token::In,
+            Token::LBracket,
+            Token::Number(1.0),
+            Token::Comma,
+            Token::Number(2.5),
+            Token::RBracket,
+            Token::NotIn,
+            Token::Number(0.0),
+            Token::DotDotEq,
+            Token::Number(3.0),
+        ]);
+    }
+
+    #[test]
+    fn test_identifier() {
+        let tokens = tokenize("x1 + _y in inner || true").unwrap();
+        assert_eq!(tokens, vec![
+            Token::Ident("x1".to_string()),
+            Token::Plus,
+            Token::Ident("_y".to_string()),
+            Token::In,
+            Token::Ident("inner".to_string()),
+            Token::Or,
+            Token::Bool(true),
+        ]);
+        assert!(tokenize("1 not 2").is_err());
+    }
+
+    #[test]
+    fn test_comments() {
+        let input = "// 开头\n1 / 2 // 结尾  \n// 独占一行\n+ 3";
+        assert_eq!(tokenize(input).unwrap(), vec![
+            Token::Number(1.0),
+            Token::Slash,
+            Token::Number(2.0),
+            Token::Plus,
+            Token::Number(3.0),
+        ]);
+        let tokens = tokenize_with_comments(input).unwrap();
+        assert_eq!(tokens[0], Token::Comment { text: " 开头".to_string(), own_line: true });
+        assert_eq!(tokens[4], Token::Comment { text: " 结尾".to_string(), own_line: false });
+        assert_eq!(tokens[5], Token::Comment { text: " 独占一行".to_string(), own_line: true });
+    }
+
+    #[test]
+    fn test_string_literal() {
+        let tokens = tokenize(r#""abc" == "a\"b\\c\n""#).unwrap();
+        assert_eq!(tokens, vec![
+            Token::Str("abc".to_string()),
+            Token::Equal,
+            Token::Str("a\"b\\c\n".to_string()),
+        ]);
+        assert!(tokenize(r#""abc"#).is_err());
+    }
+
+    #[test]
+    fn test_split_statements() {
+        let source = "1 + 2\n\n// 注释\nf(a,\n  b)\n    || c\nx &&\n// 中间的注释\ny\n-1\n:trace\n\"abc\n// 结尾";
+        let statements = split_statements(source);
+        let lines: Vec<(usize, &str)> = statements.iter().map(|s| (s.line, s.source.as_str())).collect();
+        assert_eq!(lines, [
+            (1, "1 + 2"),
+            (2, "\n// 注释"),
+            (4, "f(a,\n  b)\n    || c"),
+            (7, "x &&\n// 中间的注释\ny"),
+            (10, "-1"),
+            (11, ":trace"),
+            (12, "\"abc"),
+            (13, "// 结尾"),
+        ]);
+        let joined: Vec<&str> = statements.iter().map(|s| s.source.as_str()).collect();
+        assert_eq!(joined.join("\n"), source);
+    }
 }
@@ -0,0 +1,27 @@
+pub mod ast;
+pub mod lexer;
+pub mod parser;
+pub mod eval;
+pub mod compiler;
+pub mod vm;
+pub mod engine;
+pub mod optimize;
+pub mod functions;
+pub mod cse;
+pub mod diff;
+pub mod dual;
+pub mod interval;
+pub mod batch;
+pub mod codegen;
+pub mod sql;
+pub mod render;
+pub mod format;
+pub mod arena;
+pub mod tree;
+#[cfg(feature = "json")]
+pub mod json;
+#[cfg(feature = "binary")]
+pub mod binary;
+
+#[cfg(test)]
+mod test_util;
@@ -1,31 +1,177 @@
-mod ast;
-mod lexer;
-mod parser;
-mod eval;
+use std::{
+    io::{self, BufRead, IsTerminal, Read, Write},
+    process::ExitCode,
+    sync::Arc,
+};
+use anyhow::Context as _;
+use expr_interpreter::{
+    ast::Expr, engine::Engine, eval::Context, format, lexer::{split_statements, tokenize}, parser::{Diagnostic, Parser, PrintTrace}, tree,
+};
 
-use std::io::{self, Write};
-use lexer::tokenize;
-use parser::Parser;
-use eval::eval;
-
-fn main() -> anyhow::Result<()> {
-    println!("表达式解释器（输入 Ctrl+C 退出）");
+// expr                 交互模式: 出错只打印错误, 继续下一行, Ctrl+D 退出
+// expr 文件            脚本模式: 逐条语句求值, 遇到错误立即停止并返回非零退出码
+//                      (一条语句可以跨多行, 规则见 lexer::split_statements, fmt 和 check 也一样)
+// expr < 文件          标准输入不是终端时同样是脚本模式
+// expr fmt [文件...]   原地格式化文件, 没有文件时从标准输入读, 输出到标准输出
+// expr check [文件...] 只检查语法, 一次列出所有错误, 有错误时返回非零退出码
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("fmt") => fmt(&args[1..]),
+        Some("check") => check(&args[1..]),
+        Some(file) => std::fs::read_to_string(file)
+            .with_context(|| format!("无法读取 {file}"))
+            .and_then(|source| script(&source)),
+        None if !io::stdin().is_terminal() => {
+            let mut source = String::new();
+            io::stdin().read_to_string(&mut source).context("无法读取标准输入").and_then(|_| script(&source))
+        }
+        None => {
+            repl();
+            Ok(())
+        }
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("错误: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
+fn repl() {
+    println!("表达式解释器（Ctrl+D 退出, :tree 或 :dot 加表达式查看语法树, :trace 开关解析跟踪）");
+    let mut session = Session::new(true);
     loop {
         print!(">>> ");
         io::stdout().flush().unwrap();
 
         let mut input = String::new();
-        io::stdin().read_line(&mut input).unwrap();
+        match io::stdin().lock().read_line(&mut input) {
+            // 读到结尾 (Ctrl+D)
+            Ok(0) => {
+                println!();
+                return;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                eprintln!("错误: {e}");
+                return;
+            }
+        }
+        if let Err(e) = session.run(&input) {
+            eprintln!("错误: {e:#}");
+        }
+    }
+}
+
+fn script(source: &str) -> anyhow::Result<()> {
+    let mut session = Session::new(false);
+    for statement in split_statements(source) {
+        session.run(&statement.source).with_context(|| format!("第 {} 行", statement.line))?;
+    }
+    Ok(())
+}
+
+struct Session {
+    engine: Engine,
+    ctx: Context,
+    // 交互模式下先回显优化后的表达式
+    echo: bool,
+}
+
+impl Session {
+    fn new(echo: bool) -> Self {
+        Session { engine: Engine::new(), ctx: Context::new(), echo }
+    }
 
-        let tokens = tokenize(&input)?;
-        let mut parser = Parser::new(tokens);
-        let ast = parser.parse_expr()?;
-        println!("{}", ast);
-        let result = eval(&ast)?;
+    fn run(&mut self, input: &str) -> anyhow::Result<()> {
+        // 命令名到空白或结尾为止, :treex 是未知命令而不是 :tree x
+        if let Some(command) = input.trim_start().strip_prefix(':') {
+            let (name, source) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+            match name {
+                "trace" if source.trim().is_empty() => {
+                    let on = self.engine.parser_options.trace.is_none();
+                    self.engine.parser_options.trace = if on { Some(Arc::new(PrintTrace)) } else { None };
+                    println!("解析跟踪已{}", if on { "打开" } else { "关闭" });
+                }
+                "trace" => anyhow::bail!(":trace 不接受参数"),
+                // :tree 表达式 / :dot 表达式  画出解析得到的语法树 (优化之前)
+                "tree" => print!("{}", tree::to_tree(&self.parse(source)?)),
+                "dot" => print!("{}", tree::to_dot(&self.parse(source)?)),
+                _ => anyhow::bail!("未知命令: :{name}"),
+            }
+            return Ok(());
+        }
 
+        // 跳过空行和只有注释的行
+        if tokenize(input)?.is_empty() {
+            return Ok(());
+        }
+        let compiled = self.engine.compile(input)?;
+        if self.echo {
+            println!("{}", format::pretty(compiled.expr()));
+        }
+        let result = compiled.eval(&self.ctx)?;
         println!("= {}", result);
+        Ok(())
     }
 
-    // Ok(())
+    fn parse(&self, source: &str) -> anyhow::Result<Expr> {
+        Parser::with_options(tokenize(source)?, self.engine.parser_options.clone()).parse_program()
+    }
+}
+
+fn check(files: &[String]) -> anyhow::Result<()> {
+    let mut sources = Vec::new();
+    if files.is_empty() {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source).context("无法读取标准输入")?;
+        sources.push(("<标准输入>".to_string(), source));
+    }
+    for file in files {
+        sources.push((file.clone(), std::fs::read_to_string(file).with_context(|| format!("无法读取 {file}"))?));
+    }
+
+    let mut count = 0;
+    for (file, source) in &sources {
+        for statement in split_statements(source) {
+            // 语句之间就是同步点; 以 `:` 开头的是交互命令
+            if statement.source.trim_start().starts_with(':') {
+                continue;
+            }
+            let diagnostics = match tokenize(&statement.source) {
+                // 空行和只有注释的行
+                Ok(tokens) if tokens.is_empty() => continue,
+                Ok(tokens) => Parser::new(tokens).parse_recovering().1,
+                Err(e) => vec![Diagnostic { message: e.to_string(), pos: 0 }],
+            };
+            for diagnostic in &diagnostics {
+                println!("{file}: 第 {} 行 {diagnostic}", statement.line);
+            }
+            count += diagnostics.len();
+        }
+    }
+    if count > 0 {
+        anyhow::bail!("发现 {count} 个语法错误");
+    }
+    Ok(())
+}
+
+fn fmt(files: &[String]) -> anyhow::Result<()> {
+    if files.is_empty() {
+        let mut source = String::new();
+        io::stdin().read_to_string(&mut source)?;
+        print!("{}", format::format_source(&source)?);
+        return Ok(());
+    }
+    for file in files {
+        let source = std::fs::read_to_string(file).with_context(|| format!("无法读取 {file}"))?;
+        let formatted = format::format_source(&source).with_context(|| format!("无法格式化 {file}"))?;
+        if formatted != source {
+            std::fs::write(file, formatted).with_context(|| format!("无法写入 {file}"))?;
+        }
+    }
+    Ok(())
 }
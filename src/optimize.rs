@@ -0,0 +1,302 @@
+// 常量折叠和代数化简
+//
+// 化简不能改变求值结果 (包括值的类型, -0 和 NaN), 所以:
+//   x * 1, x / 1, x - 0 只在 x 一定是数字, 或者外层运算本来就会把结果转成数字时才去掉
+//   x + 0 另外要求 x 不可能是 -0, 因为 -0 + 0 == +0
+//   x * 0 不化简, x 可能是 NaN 或 inf
+//   折叠出 inf/NaN 时保留原表达式
+use crate::{
+    ast::{walk_fold, BinaryOp, Expr, Folder},
+    eval::{eval_with, Context, EvalPolicy, Value},
+};
+
+#[derive(Debug, Clone)]
+pub struct Optimized {
+    pub expr: Expr,
+    pub changes: Vec<String>, // 每一次化简的记录, 用于调试
+}
+
+pub fn optimize(expr: &Expr, policy: &EvalPolicy) -> Optimized {
+    let mut optimizer = Optimizer { policy, changes: Vec::new(), coerce_next: false, coerce: false };
+    let expr = optimizer.fold_expr(expr.clone());
+    Optimized { expr, changes: optimizer.changes }
+}
+
+struct Optimizer<'a> {
+    policy: &'a EvalPolicy,
+    changes: Vec<String>,
+    // 下一个变换的节点的结果是否会被外层转成数字, 父节点在变换子节点前设置, 默认 false
+    coerce_next: bool,
+    // 当前节点的 coerce, 每个 fold 方法要在变换子节点之前读取
+    coerce: bool,
+}
+
+// 子节点都先化简, 再看能不能折叠或去掉单位元
+impl Folder for Optimizer<'_> {
+    fn fold_expr(&mut self, expr: Expr) -> Expr {
+        self.coerce = std::mem::take(&mut self.coerce_next);
+        walk_fold(self, expr)
+    }
+
+    fn fold_unary(&mut self, op: BinaryOp, expr: Expr) -> Expr {
+        let coerce = self.coerce;
+        let inner = self.fold_expr(expr);
+        if is_literal(&inner) {
+            return self.fold(Expr::UnaryOp { op, expr: Box::new(inner) });
+        }
+        match (op, inner) {
+            // -(-x) => x, 对 bool 来说就是 !!x
+            (BinaryOp::Sub, Expr::UnaryOp { op: BinaryOp::Sub, expr: x }) if coerce || is_numeric(&x) || is_bool(&x) => {
+                self.rewrite(format!("-(-{x})"), *x)
+            }
+            (BinaryOp::Not, Expr::UnaryOp { op: BinaryOp::Not, expr: x }) if is_bool(&x) => {
+                self.rewrite(format!("!(!{x})"), *x)
+            }
+            (BinaryOp::Add, x) if coerce || is_numeric(&x) || is_bool(&x) => self.rewrite(format!("+{x}"), x),
+            (op, x) => Expr::UnaryOp { op, expr: Box::new(x) },
+        }
+    }
+
+    fn fold_binary(&mut self, left: Expr, op: BinaryOp, right: Expr) -> Expr {
+        let coerce = self.coerce;
+        if let BinaryOp::And | BinaryOp::Or = op {
+            let left = self.fold_expr(left);
+            let right = self.fold_expr(right);
+            let node = Expr::BinaryOp { left: Box::new(left.clone()), op, right: Box::new(right.clone()) };
+            if !is_literal(&left) {
+                return node;
+            }
+            if is_literal(&right) {
+                return self.fold(node);
+            }
+            // 左边是常量时可以直接决定短路的结果
+            return match (op, literal_value(&left).truthy()) {
+                (BinaryOp::And, Ok(false)) => self.rewrite(node.to_string(), Expr::Bool(false)),
+                (BinaryOp::Or, Ok(true)) => self.rewrite(node.to_string(), Expr::Bool(true)),
+                (_, Ok(_)) if is_bool(&right) => self.rewrite(node.to_string(), right),
+                _ => node,
+            };
+        }
+        let arithmetic = matches!(
+            op,
+            BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow
+            | BinaryOp::Range | BinaryOp::RangeInclusive
+        );
+        let left = self.fold_coerced(left, arithmetic);
+        let right = self.fold_coerced(right, arithmetic);
+        if is_literal(&left) && is_literal(&right) {
+            return self.fold(Expr::BinaryOp { left: Box::new(left), op, right: Box::new(right) });
+        }
+        self.identity(left, op, right, coerce)
+    }
+
+    fn fold_compare(&mut self, first: Expr, rest: Vec<(BinaryOp, Expr)>) -> Expr {
+        let first = self.fold_expr(first);
+        let rest: Vec<_> = rest.into_iter().map(|(op, e)| (op, self.fold_expr(e))).collect();
+        let all_literal = is_literal(&first) && rest.iter().all(|(_, e)| is_literal(e));
+        let node = Expr::Compare { first: Box::new(first), rest };
+        if all_literal { self.fold(node) } else { node }
+    }
+
+    // 不知道函数是否是纯函数, fold_call 用默认实现, 只化简参数
+
+    fn fold_let(&mut self, name: String, value: Expr, body: Expr) -> Expr {
+        let coerce = self.coerce;
+        let value = self.fold_expr(value);
+        Expr::Let { name, value: Box::new(value), body: Box::new(self.fold_coerced(body, coerce)) }
+    }
+}
+
+impl Optimizer<'_> {
+    fn fold_coerced(&mut self, expr: Expr, coerce: bool) -> Expr {
+        self.coerce_next = coerce;
+        self.fold_expr(expr)
+    }
+
+    fn identity(&mut self, left: Expr, op: BinaryOp, right: Expr, coerce: bool) -> Expr {
+        let keeps_type = |e: &Expr| coerce || is_numeric(e);
+        let before = || format!("({left} {op} {right})");
+        match op {
+            BinaryOp::Mul if is_number(&right, 1.0) && keeps_type(&left) => self.rewrite(before(), left),
+            BinaryOp::Mul if is_number(&left, 1.0) && keeps_type(&right) => self.rewrite(before(), right),
+            BinaryOp::Div if is_number(&right, 1.0) && keeps_type(&left) => self.rewrite(before(), left),
+            BinaryOp::Pow if is_number(&right, 1.0) && keeps_type(&left) => self.rewrite(before(), left),
+            BinaryOp::Sub if is_positive_zero(&right) && keeps_type(&left) => self.rewrite(before(), left),
+            BinaryOp::Add if is_positive_zero(&right) && keeps_type(&left) && !may_be_negative_zero(&left) => {
+                self.rewrite(before(), left)
+            }
+            BinaryOp::Add if is_positive_zero(&left) && keeps_type(&right) && !may_be_negative_zero(&right) => {
+                self.rewrite(before(), right)
+            }
+            _ => Expr::BinaryOp { left: Box::new(left), op, right: Box::new(right) },
+        }
+    }
+
+    // 用和 eval 相同的规则计算常量子树; 出错的表达式留到运行时再报错
+    fn fold(&mut self, expr: Expr) -> Expr {
+        let folded = match eval_with(&expr, &Context::new(), self.policy) {
+            Ok(Value::Number(n)) if n.is_finite() => Expr::Number(n),
+            Ok(Value::Bool(b)) => Expr::Bool(b),
+            Ok(Value::Str(s)) => Expr::Str(s),
+            _ => return expr,
+        };
+        self.rewrite(expr.to_string(), folded)
+    }
+
+    fn rewrite(&mut self, before: String, after: Expr) -> Expr {
+        self.changes.push(format!("{before} => {after}"));
+        after
+    }
+}
+
+fn is_literal(expr: &Expr) -> bool {
+    matches!(expr, Expr::Number(_) | Expr::Str(_) | Expr::Bool(_))
+}
+
+fn literal_value(expr: &Expr) -> Value {
+    match expr {
+        Expr::Number(n) => Value::Number(*n),
+        Expr::Str(s) => Value::Str(s.clone()),
+        Expr::Bool(b) => Value::Bool(*b),
+        _ => unreachable!("不是字面量: {expr}"),
+    }
+}
+
+fn is_number(expr: &Expr, value: f64) -> bool {
+    matches!(expr, Expr::Number(n) if *n == value)
+}
+
+fn is_positive_zero(expr: &Expr) -> bool {
+    matches!(expr, Expr::Number(n) if *n == 0.0 && n.is_sign_positive())
+}
+
+// 结果一定是数字的表达式
+fn is_numeric(expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(_) => true,
+        Expr::BinaryOp { op: BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Pow, .. } => true,
+        Expr::UnaryOp { op: BinaryOp::Add | BinaryOp::Sub, expr } => is_numeric(expr),
+        _ => false,
+    }
+}
+
+// 结果一定是 bool 的表达式
+fn is_bool(expr: &Expr) -> bool {
+    match expr {
+        Expr::Bool(_) | Expr::Compare { .. } => true,
+        Expr::UnaryOp { op: BinaryOp::Not, .. } => true,
+        Expr::UnaryOp { op: BinaryOp::Add | BinaryOp::Sub, expr } => is_bool(expr),
+        Expr::BinaryOp { op, .. } => matches!(
+            op,
+            BinaryOp::Eq | BinaryOp::Neq | BinaryOp::Gt | BinaryOp::Gte | BinaryOp::Lt | BinaryOp::Lte
+            | BinaryOp::And | BinaryOp::Or | BinaryOp::In | BinaryOp::NotIn
+        ),
+        _ => false,
+    }
+}
+
+// 保守判断: 返回 false 时结果一定不是 -0
+fn may_be_negative_zero(expr: &Expr) -> bool {
+    match expr {
+        Expr::Number(n) => *n == 0.0 && n.is_sign_negative(),
+        Expr::Bool(_) | Expr::Str(_) => false,
+        // 舍入到最近时只有 -0 + -0 和 -0 - +0 得到 -0
+        Expr::BinaryOp { left, op: BinaryOp::Add, right } => may_be_negative_zero(left) && may_be_negative_zero(right),
+        Expr::BinaryOp { left, op: BinaryOp::Sub, .. } => may_be_negative_zero(left),
+        _ => true,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        eval::{DivByZero, FloatEq},
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    fn optimize_str(input: &str) -> Optimized {
+        let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        optimize(&expr, &EvalPolicy::default())
+    }
+
+    #[test]
+    fn test_fold_and_identities() {
+        let result = optimize_str("2 * 3 + x * 1 + 0");
+        assert_eq!(result.expr.to_string(), "(6 + x)");
+        assert_eq!(result.changes, vec![
+            "(2 * 3) => 6",
+            "(x * 1) => x",
+            "((6 + x) + 0) => (6 + x)",
+        ]);
+    }
+
+    #[test]
+    fn test_fold_comparison_and_strings() {
+        assert_eq!(optimize_str("1 < 2 < 3 && x").expr.to_string(), "(true && x)");
+        assert_eq!(optimize_str("1 > 2 && x").expr.to_string(), "false");
+        assert_eq!(optimize_str(r#"x || "a" < "b""#).expr.to_string(), "(x || true)");
+        assert_eq!(optimize_str("1 == 1 && x > 2").expr.to_string(), "(x > 2)");
+    }
+
+    #[test]
+    fn test_keeps_type() {
+        // x 可能是 bool 或字符串, 顶层的 x * 1 不能化简
+        assert_eq!(optimize_str("x * 1").expr.to_string(), "(x * 1)");
+        assert_eq!(optimize_str("x * 1 + 1").expr.to_string(), "(x + 1)");
+        // x 可能是 -0
+        assert_eq!(optimize_str("x * 2 + 0").expr.to_string(), "((x * 2) + 0)");
+        assert_eq!(optimize_str("x * 2 - 0").expr.to_string(), "(x * 2)");
+        // x * 0 不一定是 0
+        assert_eq!(optimize_str("x * 2 * 0").expr.to_string(), "((x * 2) * 0)");
+    }
+
+    #[test]
+    fn test_double_negation() {
+        let neg = |e: Expr| Expr::UnaryOp { op: BinaryOp::Sub, expr: Box::new(e) };
+        let policy = EvalPolicy::default();
+        let x = Expr::Var("x".to_string());
+        let x2 = optimize_str("x * 2").expr;
+
+        assert_eq!(optimize(&neg(neg(x2)), &policy).expr.to_string(), "(x * 2)");
+        // x 可能是字符串, -(-x) 会报错
+        assert_eq!(optimize(&neg(neg(x.clone())), &policy).expr.to_string(), "(-(-x))");
+        let sum = Expr::BinaryOp { left: Box::new(Expr::Number(1.0)), op: BinaryOp::Add, right: Box::new(neg(neg(x))) };
+        assert_eq!(optimize(&sum, &policy).expr.to_string(), "(1 + x)");
+    }
+
+    #[test]
+    fn test_errors_not_folded() {
+        assert_eq!(optimize_str("1 / 0 + x").expr.to_string(), "((1 / 0) + x)");
+        assert_eq!(optimize_str(r#""a" < 1"#).expr.to_string(), r#"("a" < 1)"#);
+    }
+
+    // 化简前后的求值结果必须完全一致
+    #[test]
+    fn test_differential_random() {
+        let policies = [
+            EvalPolicy::default(),
+            EvalPolicy { float_eq: FloatEq::Abs(0.5), div_by_zero: DivByZero::Infinity, ..Default::default() },
+        ];
+        let mut ctx = Context::new();
+        ctx.set("x", -0.0).set("s", "a");
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        for _ in 0..5000 {
+            let depth = 1 + rng.below(4) as u32;
+            let expr = random_expr(&mut rng, depth);
+            for policy in &policies {
+                let optimized = optimize(&expr, policy).expr;
+                let before = eval_with(&expr, &ctx, policy);
+                let after = eval_with(&optimized, &ctx, policy);
+                match (&before, &after) {
+                    (Ok(a), Ok(b)) => assert_eq!(format!("{a:?}"), format!("{b:?}"), "{expr} => {optimized}"),
+                    (Err(_), Err(_)) => {}
+                    _ => panic!("结果不一致: {expr} => {optimized}\n  {before:?}\n  {after:?}"),
+                }
+            }
+        }
+    }
+}
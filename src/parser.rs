@@ -1,15 +1,102 @@
+use std::{fmt, sync::Arc};
+
 use crate::{ast::{BinaryOp, Expr}, lexer::Token};
 
+#[derive(Clone, Default)]
+pub struct ParserOptions {
+    // 严格模式下 a < b < c 是语法错误, 否则按数学含义解析成连续比较
+    pub strict_comparisons: bool,
+    // 递归下降的跟踪, 每进入/离开一条规则发一个事件; None 时不跟踪
+    pub trace: Option<Arc<dyn TraceSink>>,
+}
+
+impl fmt::Debug for ParserOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ParserOptions")
+            .field("strict_comparisons", &self.strict_comparisons)
+            .field("trace", &self.trace.is_some())
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceKind {
+    Enter,
+    Exit,
+}
+
+// 跟踪事件: 哪条规则, 递归深度, 以及当时看到的 token 和它的位置 (第几个 token)
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEvent {
+    pub kind: TraceKind,
+    pub rule: &'static str,
+    pub depth: usize,
+    pub pos: usize,
+    pub token: Option<Token>,
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = if self.kind == TraceKind::Enter { '>' } else { '<' };
+        write!(f, "{:indent$}{arrow} {} @{} ", "", self.rule, self.pos, indent = self.depth * 2)?;
+        match &self.token {
+            Some(token) => write!(f, "{token}"),
+            None => write!(f, "<结尾>"),
+        }
+    }
+}
+
+// 接收跟踪事件的地方; 闭包也可以直接当作 sink
+pub trait TraceSink: Send + Sync {
+    fn event(&self, event: &TraceEvent);
+}
+
+impl<F: Fn(&TraceEvent) + Send + Sync> TraceSink for F {
+    fn event(&self, event: &TraceEvent) {
+        self(event)
+    }
+}
+
+// 按深度缩进打印到标准输出
+pub struct PrintTrace;
+
+impl TraceSink for PrintTrace {
+    fn event(&self, event: &TraceEvent) {
+        println!("{event}");
+    }
+}
+
+// 出错恢复时收集的一条错误, pos 是出错的 token 下标 (在结尾出错时等于 token 个数)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub pos: usize,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "位置 {}: {}", self.pos, self.message)
+    }
+}
+
 pub struct Parser {
     tokens: Vec<Token>,
     pos: usize,
-    depth: usize, // 用于打印缩进
+    depth: usize, // 跟踪事件里的递归深度
+    options: ParserOptions,
+    // 恢复模式: 出错时记下诊断, 跳到同步点继续解析, 解析失败的部分用 Expr::Error 代替
+    recovering: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
         // tokens.iter().for_each(|t| println!("{}", *t));
-        Parser { tokens, pos: 0, depth: 0 }
+        Self::with_options(tokens, ParserOptions::default())
+    }
+
+    pub fn with_options(tokens: Vec<Token>, options: ParserOptions) -> Self {
+        Parser { tokens, pos: 0, depth: 0, options, recovering: false, diagnostics: Vec::new() }
     }
 
     fn current(&self) -> Option<&Token> {
@@ -22,181 +109,358 @@ impl Parser {
         token
     }
 
-    fn log_enter(&mut self, name: &str) {
-        println!("{:indent$}> {} depth : {}", "", name, self.depth, indent = self.depth * 2);
+    // 跟踪一条规则: 不管 f 是正常返回还是用 `?` 提前返回, 都会发出 Exit 并恢复 depth
+    fn rule<T>(&mut self, name: &'static str, f: impl FnOnce(&mut Self) -> anyhow::Result<T>) -> anyhow::Result<T> {
+        self.trace(TraceKind::Enter, name);
         self.depth += 1;
+        let res = f(self);
+        self.depth -= 1;
+        self.trace(TraceKind::Exit, name);
+        res
     }
 
-    fn log_exit(&mut self, name: &str) {
-        self.depth -= 1;
-        println!("{:indent$}< {} depth : {}", "", name, self.depth, indent = self.depth * 2);
+    fn trace(&self, kind: TraceKind, rule: &'static str) {
+        if let Some(sink) = &self.options.trace {
+            sink.event(&TraceEvent { kind, rule, depth: self.depth, pos: self.pos, token: self.current().cloned() });
+        }
     }
 
-    pub fn parse_expr(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_expr");
-        let res = self.parse_or();
-        self.log_exit("parse_expr");
-        res
+    // 解析完整的输入: 表达式后面必须是结尾, 不能留下没用到的 token
+    pub fn parse_program(&mut self) -> anyhow::Result<Expr> {
+        let expr = self.parse_expr()?;
+        if let Some(token) = self.current() {
+            anyhow::bail!("unexpected token {token}, expected operator or end of input");
+        }
+        Ok(expr)
     }
 
-    fn parse_or(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_or");
+    // 一次解析出所有错误, 而不是停在第一个. 返回的语法树里出错的部分是 Expr::Error,
+    // 有诊断时不应该拿去求值
+    pub fn parse_recovering(&mut self) -> (Expr, Vec<Diagnostic>) {
+        self.recovering = true;
+        let mut expr = self.parse_expr().unwrap_or_else(|e| self.failed(e));
+        // 多出来的 token: 报告以后接着解析后面的部分, 只为了找出更多错误
+        while let Some(token) = self.current() {
+            let message = format!("unexpected token {token}, expected operator or end of input");
+            self.error(message).expect("恢复模式不会返回错误");
+            if let Some(Token::RParen | Token::RBracket | Token::Comma) = self.current() {
+                self.eat();
+            } else if let Err(e) = self.parse_expr() {
+                self.failed(e);
+            }
+            expr = Expr::Error;
+        }
+        self.recovering = false;
+        (expr, std::mem::take(&mut self.diagnostics))
+    }
+
+    // 恢复模式下仍然返回的错误 (只有内部错误), 记下来当作整个表达式出错
+    fn failed(&mut self, error: anyhow::Error) -> Expr {
+        self.diagnostics.push(Diagnostic { message: error.to_string(), pos: self.pos.min(self.tokens.len()) });
+        Expr::Error
+    }
 
-        let mut node = self.parse_and()?;
-        while let Some(token) = self.current()  {
+    // 报告语法错误: 普通模式下直接返回错误; 恢复模式下记下诊断, 由调用者跳到同步点.
+    // 同一个位置只记第一条, 避免一个错误引起一串诊断
+    fn error(&mut self, message: String) -> anyhow::Result<()> {
+        if !self.recovering {
+            anyhow::bail!(message);
+        }
+        let pos = self.pos.min(self.tokens.len());
+        if self.diagnostics.last().is_none_or(|d| d.pos != pos) {
+            self.diagnostics.push(Diagnostic { message, pos });
+        }
+        Ok(())
+    }
+
+    // 跳过 token 直到同一层的 `)` `]` 或结尾 (stop_at_comma 时也停在逗号), 不吃掉停下的 token
+    fn synchronize(&mut self, stop_at_comma: bool) {
+        let mut depth = 0;
+        while let Some(token) = self.current() {
             match token {
-                Token::Or => {
-                    let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
-                    self.eat();
-                    let right = self.parse_and()?;
-                    node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
-                },
-                _ => break,
-            }   
+                Token::LParen | Token::LBracket => depth += 1,
+                Token::RParen | Token::RBracket if depth == 0 => return,
+                Token::RParen | Token::RBracket => depth -= 1,
+                Token::Comma if depth == 0 && stop_at_comma => return,
+                _ => {}
+            }
+            self.eat();
         }
-        self.log_exit("parse_or");
+    }
 
-        Ok(node)
+    // 解析一个表达式, 停在第一个不能接上的 token 前
+    pub fn parse_expr(&mut self) -> anyhow::Result<Expr> {
+        self.rule("parse_expr", |p| p.parse_or())
+    }
+
+    fn parse_or(&mut self) -> anyhow::Result<Expr> {
+        self.rule("parse_or", |p| {
+            let mut node = p.parse_and()?;
+            while let Some(token) = p.current()  {
+                match token {
+                    Token::Or => {
+                        let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
+                        p.eat();
+                        let right = p.parse_and()?;
+                        node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
+                    },
+                    _ => break,
+                }   
+            }
+
+            Ok(node)
+        })
     }
 
     fn parse_and(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_and");
-        let mut node = self.parse_cmd()?;
+        self.rule("parse_and", |p| {
+            let mut node = p.parse_cmd()?;
 
-        while let Some(token) = self.current()  {
-            match token {
-                Token::And => {
-                    let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
-                    self.eat();
-                    let right = self.parse_cmd()?;
-                    node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
-                },
-                _ => break,
-            }   
-        }
-        self.log_exit("parse_and");
-        Ok(node)
+            while let Some(token) = p.current()  {
+                match token {
+                    Token::And => {
+                        let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
+                        p.eat();
+                        let right = p.parse_cmd()?;
+                        node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
+                    },
+                    _ => break,
+                }   
+            }
+            Ok(node)
+        })
     }
     
     fn parse_cmd(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_cmd");
-        let mut node = self.parse_term()?;
-        while let Some(token) = self.current() {
-            match token {
-                Token::Equal | Token::NotEqual | Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual => {
-                    let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
-                    self.eat();
-                    let right = self.parse_term()?;
-                    node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
+        self.rule("parse_cmd", |p| {
+            let first = p.parse_range()?;
+            let mut rest = Vec::new();
+            while let Some(token) = p.current() {
+                match token {
+                    Token::Equal | Token::NotEqual | Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual
+                    | Token::In | Token::NotIn => {
+                        let op = BinaryOp::try_from(token)?; // 把 Token 转成 BinaryOp
+                        let chained = !rest.is_empty() && p.options.strict_comparisons;
+                        if chained {
+                            p.error(format!("比较运算不能连写: {op}"))?;
+                        }
+                        p.eat();
+                        let right = p.parse_range()?;
+                        // 恢复模式下丢掉连写的部分
+                        if !chained {
+                            rest.push((op, right));
+                        }
+                    }
+                    _ => break,
                 }
-                _ => break,
             }
-        }
-        self.log_exit("parse_cmd");
 
-        Ok(node)
+            // 只有一个比较时仍然是普通的双目运算
+            let node = match rest.len() {
+                0 => first,
+                1 => {
+                    let (op, right) = rest.pop().unwrap();
+                    Expr::BinaryOp { left: Box::new(first), op, right: Box::new(right) }
+                }
+                _ => Expr::Compare { first: Box::new(first), rest },
+            };
+            Ok(node)
+        })
+    }
+
+    // 范围: term (.. | ..=) term, 不能连写 (1..2..3)
+    fn parse_range(&mut self) -> anyhow::Result<Expr> {
+        self.rule("parse_range", |p| {
+            let mut node = p.parse_term()?;
+            if let Some(token @ (Token::DotDot | Token::DotDotEq)) = p.current() {
+                let op = BinaryOp::try_from(token)?;
+                p.eat();
+                let right = p.parse_term()?;
+                node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) };
+                if let Some(Token::DotDot | Token::DotDotEq) = p.current() {
+                    p.error("范围不能连写".to_string())?;
+                    p.eat();
+                    p.parse_term()?;
+                }
+            }
+            Ok(node)
+        })
     }
 
     fn parse_term(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_term");
-        let mut node = self.parse_factor()?;
+        self.rule("parse_term", |p| {
+            let mut node = p.parse_factor()?;
 
-        while let Some(token) = self.current() {
-            match token {
-                Token::Number(_) => {
-                    // 如果遇到数字后面紧跟数字，应该提示错误
-                    anyhow::bail!("缺少操作符，语法无效");
-                }
-                Token::Plus | Token::Minus => {
-                    let op = if let Token::Plus = token { BinaryOp::Add } else { BinaryOp::Sub };
-                    self.eat();
-                    let right = self.parse_factor()?;
-                    node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) }
+            while let Some(token) = p.current() {
+                match token {
+                    Token::Plus | Token::Minus => {
+                        let op = if let Token::Plus = token { BinaryOp::Add } else { BinaryOp::Sub };
+                        p.eat();
+                        let right = p.parse_factor()?;
+                        node = Expr::BinaryOp { left: Box::new(node), op, right: Box::new(right) }
+                    }
+                    _ => break,
                 }
-                _ => break,
             }
-        }
 
-        self.log_exit("parse_term");
-        Ok(node)
+            Ok(node)
+        })
     }
 
     fn parse_factor(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_factor");
-        let mut node = self.parse_unary()?;
-        while let Some(token) = self.current() {
-            // println!("{:depth$}token: {token}", "", depth = self.depth * 2);
-            match token {
-                Token::Star | Token::Slash => {
-                    let op = if let Token::Star = token { BinaryOp::Mul } else { BinaryOp::Div };
-                    self.eat();
-                    let right = self.parse_unary()?;
-                    node = Expr::BinaryOp { 
-                        left: Box::new(node), op, right: Box::new(right) 
+        self.rule("parse_factor", |p| {
+            let mut node = p.parse_unary()?;
+            while let Some(token) = p.current() {
+                // println!("{:depth$}token: {token}", "", depth = p.depth * 2);
+                match token {
+                    Token::Star | Token::Slash => {
+                        let op = if let Token::Star = token { BinaryOp::Mul } else { BinaryOp::Div };
+                        p.eat();
+                        let right = p.parse_unary()?;
+                        node = Expr::BinaryOp { 
+                            left: Box::new(node), op, right: Box::new(right) 
+                        }
                     }
+                    _ => break,
                 }
-                _ => break,
-            }
-        };
+            };
 
-        self.log_exit("parse_factor");
-        Ok(node)
+            Ok(node)
+        })
     }
 
     fn parse_unary(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_unary");
-        // let t = self.current();
-        // dbg!(t);
-        let res = match self.current() {
-            Some(Token::Minus) | Some(Token::Plus) => {
-                let op = if let Some(Token::Minus) = self.current() { BinaryOp::Sub } else { BinaryOp::Add };
-                self.eat();
-                Ok(Expr::UnaryOp {
-                    op,
-                    expr: Box::new(self.parse_primary()?),
-                })
+        self.rule("parse_unary", |p| {
+            // let t = p.current();
+            // dbg!(t);
+            match p.current() {
+                Some(Token::Minus) | Some(Token::Plus) => {
+                    let op = if let Some(Token::Minus) = p.current() { BinaryOp::Sub } else { BinaryOp::Add };
+                    p.eat();
+                    Ok(Expr::UnaryOp {
+                        op,
+                        expr: Box::new(p.parse_power()?),
+                    })
+                }
+                // Some(Token::Not) => {                
+                //     Ok(Expr::UnaryOp {
+                //         op: BinaryOp::Not, 
+                //         expr: Box::new(p.parse_primary()?) })
+                // }
+                _ => p.parse_power(),
             }
-            // Some(Token::Not) => {                
-            //     Ok(Expr::UnaryOp {
-            //         op: BinaryOp::Not, 
-            //         expr: Box::new(self.parse_primary()?) })
-            // }
-            _ => self.parse_primary(),
-        };
-        self.log_exit("parse_unary");
-        res
+        })
+    }
+
+    // 乘方: 右结合, 比负号优先级高, -2^2 == -(2^2), 2^-1 合法
+    fn parse_power(&mut self) -> anyhow::Result<Expr> {
+        self.rule("parse_power", |p| {
+            let mut node = p.parse_primary()?;
+            if let Some(Token::Caret) = p.current() {
+                p.eat();
+                let right = p.parse_unary()?;
+                node = Expr::BinaryOp { left: Box::new(node), op: BinaryOp::Pow, right: Box::new(right) };
+            }
+            Ok(node)
+        })
     }
 
     fn parse_primary(&mut self) -> anyhow::Result<Expr> {
-        self.log_enter("parse_primary");
-        // let depth = self.depth;
-        let res = match self.eat() {
-            Some(Token::Number(n)) => {
-                let n = *n;
-                // println!("{:indent$}=> Number({})", "", n, indent = depth * 2);
-                match self.current() {
-                    Some(Token::Not) | Some(Token::LParen) | Some(Token::RParen) => anyhow::bail!("错误的语法1111"),
-                    _ => {},
+        self.rule("parse_primary", |p| {
+            match p.eat() {
+                Some(Token::Number(n)) => Ok(Expr::Number(*n)),
+                Some(Token::Str(s)) => Ok(Expr::Str(s.clone())),
+                Some(Token::Ident(name)) => {
+                    let name = name.clone();
+                    if let Some(Token::LParen) = p.current() {
+                        p.eat();
+                        Ok(Expr::Call { name, args: p.parse_args()? })
+                    } else {
+                        Ok(Expr::Var(name))
+                    }
                 }
-                Ok(Expr::Number(n))
-            }
-            Some(Token::Not) => {                
-                Ok(Expr::UnaryOp {
-                    op: BinaryOp::Not, 
-                    expr: Box::new(self.parse_expr()?) })
-            }
-            Some(Token::LParen) => {
-                let expr = self.parse_expr()?;
-                if let Some(Token::RParen) = self.eat() {
+                Some(Token::Bool(b)) => Ok(Expr::Bool(*b)),
+                Some(Token::Not) => {                
+                    Ok(Expr::UnaryOp {
+                        op: BinaryOp::Not, 
+                        expr: Box::new(p.parse_expr()?) })
+                }
+                Some(Token::LBracket) => {
+                    let mut items = Vec::new();
+                    loop {
+                        // 允许空列表 [] 和结尾逗号 [1, 2,]
+                        if let Some(Token::RBracket) = p.current() {
+                            p.eat();
+                            break;
+                        }
+                        items.push(p.parse_expr()?);
+                        if !p.list_separator(Token::RBracket, "列表缺少 `]`")? {
+                            break;
+                        }
+                    }
+                    Ok(Expr::List(items))
+                }
+                Some(Token::LParen) => {
+                    let expr = p.parse_expr()?;
+                    if let Some(Token::RParen) = p.current() {
+                        p.eat();
+                    } else {
+                        // 跳到配对的 `)`, 中间的逗号也跳过
+                        p.error("括号不匹配".to_string())?;
+                        p.synchronize(false);
+                        if let Some(Token::RParen) = p.current() {
+                            p.eat();
+                        }
+                    }
                     Ok(expr)
-                } else {
-                    anyhow::bail!("括号不匹配");
+                }
+                _ => {
+                    // 退回到出错的 token 上报告; 结尾, 逗号和右括号留给外层处理
+                    p.pos -= 1;
+                    p.error("非法表达式".to_string())?;
+                    if !matches!(p.current(), None | Some(Token::Comma | Token::RParen | Token::RBracket)) {
+                        p.eat();
+                        p.synchronize(true);
+                    }
+                    Ok(Expr::Error)
                 }
             }
-            _ => anyhow::bail!("非法表达式"),
-        };
-        self.log_exit("parse_primary");
-        res
+        })
+    }
+
+    // 函数参数, `(` 已经被吃掉
+    fn parse_args(&mut self) -> anyhow::Result<Vec<Expr>> {
+        let mut args = Vec::new();
+        if let Some(Token::RParen) = self.current() {
+            self.eat();
+            return Ok(args);
+        }
+        loop {
+            args.push(self.parse_expr()?);
+            if !self.list_separator(Token::RParen, "函数参数缺少 `)`")? {
+                return Ok(args);
+            }
+        }
+    }
+
+    // 列表和参数的一项后面: 逗号返回 true 继续下一项, close 结束.
+    // 恢复模式下跳到逗号或 close; 遇到外层的右括号或结尾时当作结束, 不吃掉
+    fn list_separator(&mut self, close: Token, message: &str) -> anyhow::Result<bool> {
+        if !matches!(self.current(), Some(Token::Comma)) && self.current() != Some(&close) {
+            self.error(message.to_string())?;
+            self.synchronize(true);
+        }
+        match self.current() {
+            Some(Token::Comma) => {
+                self.eat();
+                Ok(true)
+            }
+            Some(token) if *token == close => {
+                self.eat();
+                Ok(false)
+            }
+            _ => Ok(false),
+        }
     }
 }
 
@@ -232,4 +496,168 @@ mod tests {
         let mut parser = Parser::new(tokens);
         assert!(parser.parse_expr().is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parser_range_in()
+    {
+        let tokens = crate::lexer::tokenize("1 + 1 not in 0..=2 * 3").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "((1 + 1) not in (0 ..= (2 * 3)))");
+
+        let tokens = crate::lexer::tokenize("1..2..3").unwrap();
+        assert!(Parser::new(tokens).parse_expr().is_err());
+    }
+
+    #[test]
+    fn parser_list()
+    {
+        let tokens = crate::lexer::tokenize("[1, 2 + 3,]").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "[1, (2 + 3)]");
+
+        let tokens = crate::lexer::tokenize("[1, 2").unwrap();
+        assert!(Parser::new(tokens).parse_expr().is_err());
+    }
+
+    #[test]
+    fn parser_chained_comparison()
+    {
+        let tokens = crate::lexer::tokenize("1 < 2 <= 3 == 3").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "(1 < 2 <= 3 == 3)");
+
+        let tokens = crate::lexer::tokenize("1 < 2").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "(1 < 2)");
+    }
+
+    #[test]
+    fn parser_strict_comparison()
+    {
+        let options = ParserOptions { strict_comparisons: true, ..Default::default() };
+        let tokens = crate::lexer::tokenize("1 < 2 < 3").unwrap();
+        assert!(Parser::with_options(tokens, options.clone()).parse_expr().is_err());
+
+        let tokens = crate::lexer::tokenize("1 < 2 && 2 < 3").unwrap();
+        assert!(Parser::with_options(tokens, options).parse_expr().is_ok());
+    }
+
+    #[test]
+    fn parser_call()
+    {
+        let tokens = crate::lexer::tokenize("max(1, x * 2) + now()").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "(max(1, (x * 2)) + now())");
+
+        let tokens = crate::lexer::tokenize("max(1, 2").unwrap();
+        assert!(Parser::new(tokens).parse_expr().is_err());
+    }
+
+    #[test]
+    fn parser_power()
+    {
+        let tokens = crate::lexer::tokenize("-2 ^ 3 ^ -x * 2").unwrap();
+        let expr = Parser::new(tokens).parse_expr().unwrap();
+        assert_eq!(expr.to_string(), "((-(2 ^ (3 ^ (-x)))) * 2)");
+    }
+
+    #[test]
+    fn parser_trace()
+    {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let options = ParserOptions {
+            trace: Some(Arc::new(move |event: &TraceEvent| sink.lock().unwrap().push(event.clone()))),
+            ..Default::default()
+        };
+        let tokens = crate::lexer::tokenize("x * 2").unwrap();
+        Parser::with_options(tokens, options).parse_expr().unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], TraceEvent { kind: TraceKind::Enter, rule: "parse_expr", depth: 0, pos: 0, token: Some(Token::Ident("x".to_string())) });
+        assert_eq!(events.last().unwrap().to_string(), "< parse_expr @3 <结尾>");
+        let enters = events.iter().filter(|e| e.kind == TraceKind::Enter).count();
+        assert_eq!(enters * 2, events.len());
+        // parse_factor 看到 `*` 以后再进入 parse_unary
+        let line = events.iter().map(|e| e.to_string()).find(|l| l.contains("@2")).unwrap();
+        assert_eq!(line, "              > parse_unary @2 2");
+    }
+
+    #[test]
+    fn parser_trace_on_error()
+    {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = events.clone();
+        let options = ParserOptions {
+            trace: Some(Arc::new(move |event: &TraceEvent| sink.lock().unwrap().push(event.clone()))),
+            ..Default::default()
+        };
+        // 出错时用 `?` 提前返回, 每条规则仍然有配对的 Exit, 深度回到 0
+        let tokens = crate::lexer::tokenize("1 + (2 *").unwrap();
+        assert!(Parser::with_options(tokens, options).parse_expr().is_err());
+
+        let events = events.lock().unwrap();
+        let enters = events.iter().filter(|e| e.kind == TraceKind::Enter).count();
+        let exits = events.iter().filter(|e| e.kind == TraceKind::Exit).count();
+        assert_eq!(enters, exits);
+        assert_eq!(events.last().unwrap().to_string(), "< parse_expr @5 <结尾>");
+    }
+
+    #[test]
+    fn parser_program_requires_end()
+    {
+        let parse = |input: &str| Parser::new(crate::lexer::tokenize(input).unwrap()).parse_program();
+        let error = |input: &str| parse(input).unwrap_err().to_string();
+        assert_eq!(error("1 + 2 )"), "unexpected token ), expected operator or end of input");
+        assert_eq!(error("(1) 2"), "unexpected token 2, expected operator or end of input");
+        assert_eq!(error("1 2"), "unexpected token 2, expected operator or end of input");
+        assert_eq!(error("2 !x"), "unexpected token !, expected operator or end of input");
+        assert_eq!(error("2 (3)"), "unexpected token (, expected operator or end of input");
+        assert_eq!(error("f(1) x"), "unexpected token x, expected operator or end of input");
+        assert_eq!(parse("(1 + 2) * 3").unwrap().to_string(), "((1 + 2) * 3)");
+
+        // parse_expr 只解析前缀, 剩下的 token 留给调用者
+        let mut parser = Parser::new(crate::lexer::tokenize("1 + 2 ]").unwrap());
+        assert_eq!(parser.parse_expr().unwrap().to_string(), "(1 + 2)");
+        assert_eq!(parser.current(), Some(&Token::RBracket));
+    }
+
+    #[test]
+    fn parser_recovering()
+    {
+        let parse = |input: &str| Parser::new(crate::lexer::tokenize(input).unwrap()).parse_recovering();
+        let messages = |diagnostics: &[Diagnostic]| diagnostics.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+
+        // 三个错误一次报告出来, 出错的地方换成 <错误>, 其余部分照常解析
+        let (expr, diagnostics) = parse("max(1 +, 2 3, x) + [4 * , 5 6] - (7 8)");
+        assert_eq!(expr.to_string(), "((max((1 + <错误>), 2, x) + [(4 * <错误>), 5]) - 7)");
+        assert_eq!(messages(&diagnostics), [
+            "位置 4: 非法表达式",
+            "位置 6: 函数参数缺少 `)`",
+            "位置 14: 非法表达式",
+            "位置 16: 列表缺少 `]`",
+            "位置 21: 括号不匹配",
+        ]);
+
+        // 同一个位置只报一次: 非法表达式之后不再报多余的 `)`, 但后面的 `*` 另外报告
+        let (expr, diagnostics) = parse("1 + ) * 2");
+        assert_eq!(expr, Expr::Error);
+        assert_eq!(messages(&diagnostics), ["位置 2: 非法表达式", "位置 3: unexpected token *, expected operator or end of input"]);
+
+        let (_, diagnostics) = parse("1..2..3 + (");
+        assert_eq!(messages(&diagnostics), ["位置 3: 范围不能连写", "位置 7: 非法表达式"]);
+
+        let options = ParserOptions { strict_comparisons: true, ..Default::default() };
+        let tokens = crate::lexer::tokenize("1 < 2 < 3 && [1").unwrap();
+        let (expr, diagnostics) = Parser::with_options(tokens, options).parse_recovering();
+        assert_eq!(expr.to_string(), "((1 < 2) && [1])");
+        assert_eq!(messages(&diagnostics), ["位置 3: 比较运算不能连写: <", "位置 8: 列表缺少 `]`"]);
+
+        // 没有错误时和 parse_program 结果一样, 普通模式的错误信息不变
+        let (expr, diagnostics) = parse("[1, 2,] in x");
+        assert!(diagnostics.is_empty());
+        assert_eq!(expr, Parser::new(crate::lexer::tokenize("[1, 2,] in x").unwrap()).parse_program().unwrap());
+        let error = Parser::new(crate::lexer::tokenize("(1 2").unwrap()).parse_program().unwrap_err();
+        assert_eq!(error.to_string(), "括号不匹配");
+    }
+}
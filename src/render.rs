@@ -0,0 +1,303 @@
+// 把表达式渲染成 LaTeX 或 MathML, 只在优先级需要时加括号
+//
+//   (a + b) / 2 * sqrt(x) ^ 2   =>  \frac{a + b}{2} \cdot \sqrt{x}^{2}
+// 分数, 根号, 指数的花括号本身就能分组, 里面不再加括号. 内部的 let 绑定会先展开.
+use crate::ast::{BinaryOp, Expr, ATOM_PRECEDENCE};
+
+pub fn to_latex(expr: &Expr) -> String {
+    Renderer { format: Format::Latex }.render(&expr.inline_lets())
+}
+
+pub fn to_mathml(expr: &Expr) -> String {
+    let body = Renderer { format: Format::MathMl }.render(&expr.inline_lets());
+    format!("<math xmlns=\"http://www.w3.org/1998/Math/MathML\">{body}</math>")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Latex,
+    MathMl,
+}
+
+// 分数和乘方作为乘方的底数时需要括号
+const POW_PRECEDENCE: u8 = 8;
+
+struct Renderer {
+    format: Format,
+}
+
+impl Renderer {
+    fn render(&self, expr: &Expr) -> String {
+        match expr {
+            Expr::Number(n) if n.is_sign_negative() && *n != 0.0 => {
+                self.row(&[self.op("-"), self.number(-n)])
+            }
+            Expr::Number(n) => self.number(*n),
+            Expr::Var(name) => self.identifier(name),
+            Expr::Str(s) => match self.format {
+                Format::Latex => format!("\\text{{\"{}\"}}", latex_escape(s)),
+                Format::MathMl => format!("<ms>{}</ms>", xml_escape(s)),
+            },
+            Expr::Bool(b) => match self.format {
+                Format::Latex => format!("\\mathrm{{{b}}}"),
+                Format::MathMl => format!("<mi>{b}</mi>"),
+            },
+            Expr::UnaryOp { op, expr } => {
+                let symbol = if *op == BinaryOp::Not { "!" } else { &op.to_string() };
+                self.row(&[self.op(symbol), self.child(expr, POW_PRECEDENCE)])
+            }
+            Expr::BinaryOp { left, op: BinaryOp::Div, right } => match self.format {
+                Format::Latex => format!("\\frac{{{}}}{{{}}}", self.render(left), self.render(right)),
+                Format::MathMl => format!("<mfrac>{}{}</mfrac>", self.render(left), self.render(right)),
+            },
+            Expr::BinaryOp { left, op: BinaryOp::Pow, right } => self.power(left, right),
+            Expr::BinaryOp { left, op: op @ (BinaryOp::Range | BinaryOp::RangeInclusive), right } => {
+                let close = if *op == BinaryOp::Range { ")" } else { "]" };
+                self.bracket("[", &[self.render(left), self.render(right)], close)
+            }
+            Expr::BinaryOp { left, op, right } => {
+                // 左结合: 右边优先级相同时也要加括号, a - (b - c)
+                let p = op.precedence();
+                self.row(&[self.child(left, p), self.op(&op.to_string()), self.child(right, p + 1)])
+            }
+            Expr::Compare { first, rest } => {
+                let mut parts = vec![self.child(first, rest[0].0.precedence() + 1)];
+                for (op, e) in rest {
+                    parts.push(self.op(&op.to_string()));
+                    parts.push(self.child(e, op.precedence() + 1));
+                }
+                self.row(&parts)
+            }
+            Expr::List(items) => {
+                let items: Vec<String> = items.iter().map(|e| self.render(e)).collect();
+                self.bracket("[", &items, "]")
+            }
+            Expr::Call { name, args } => self.call(name, args),
+            Expr::Let { .. } => self.render(&expr.inline_lets()),
+            Expr::Error => match self.format {
+                Format::Latex => "\\text{?}".to_string(),
+                Format::MathMl => "<merror><mtext>?</mtext></merror>".to_string(),
+            },
+        }
+    }
+
+    // 优先级低于 min 时加括号
+    fn child(&self, expr: &Expr, min: u8) -> String {
+        let prec = match expr {
+            Expr::BinaryOp { op: BinaryOp::Div, .. } => POW_PRECEDENCE,
+            Expr::BinaryOp { op: BinaryOp::Range | BinaryOp::RangeInclusive, .. } => ATOM_PRECEDENCE,
+            Expr::Call { name, .. } if name == "exp" || name == "pow" => POW_PRECEDENCE,
+            _ => expr.precedence(),
+        };
+        let rendered = self.render(expr);
+        if prec < min { self.bracket("(", &[rendered], ")") } else { rendered }
+    }
+
+    // 乘方右结合, 指数写在上标里不需要括号; 底数不能是分数或另一个乘方
+    fn power(&self, base: &Expr, exp: &Expr) -> String {
+        let base = self.child(base, POW_PRECEDENCE + 1);
+        match self.format {
+            Format::Latex => format!("{base}^{{{}}}", self.render(exp)),
+            Format::MathMl => format!("<msup>{base}{}</msup>", self.render(exp)),
+        }
+    }
+
+    fn call(&self, name: &str, args: &[Expr]) -> String {
+        let latex = self.format == Format::Latex;
+        match (name, args) {
+            ("sqrt", [x]) if latex => format!("\\sqrt{{{}}}", self.render(x)),
+            ("sqrt", [x]) => format!("<msqrt>{}</msqrt>", self.render(x)),
+            ("abs", [x]) => self.bracket("|", &[self.render(x)], "|"),
+            ("floor", [x]) => self.bracket("⌊", &[self.render(x)], "⌋"),
+            ("ceil", [x]) => self.bracket("⌈", &[self.render(x)], "⌉"),
+            ("exp", [x]) => self.power(&Expr::Var("e".to_string()), x),
+            ("pow", [a, b]) => self.power(a, b),
+            _ => {
+                let f = match (name, self.format) {
+                    ("ln" | "sin" | "cos" | "tan" | "min" | "max", Format::Latex) => format!("\\{name}"),
+                    ("log10", Format::Latex) => "\\log_{10}".to_string(),
+                    ("log10", Format::MathMl) => "<msub><mi>log</mi><mn>10</mn></msub>".to_string(),
+                    (_, Format::Latex) => format!("\\operatorname{{{}}}", latex_escape(name)),
+                    (_, Format::MathMl) => format!("<mi>{}</mi><mo>&#x2061;</mo>", xml_escape(name)),
+                };
+                let args: Vec<String> = args.iter().map(|e| self.render(e)).collect();
+                let args = self.bracket("(", &args, ")");
+                if latex { format!("{f}{args}") } else { format!("<mrow>{f}{args}</mrow>") }
+            }
+        }
+    }
+
+    fn number(&self, n: f64) -> String {
+        match self.format {
+            Format::Latex => n.to_string(),
+            Format::MathMl => format!("<mn>{n}</mn>"),
+        }
+    }
+
+    // LaTeX 里多个字母的变量名用 \mathrm, 避免被排成几个变量相乘
+    fn identifier(&self, name: &str) -> String {
+        match self.format {
+            Format::Latex if name.chars().count() == 1 => name.to_string(),
+            Format::Latex => format!("\\mathrm{{{}}}", latex_escape(name)),
+            Format::MathMl => format!("<mi>{}</mi>", xml_escape(name)),
+        }
+    }
+
+    fn op(&self, symbol: &str) -> String {
+        match self.format {
+            Format::Latex => match symbol {
+                "*" => "\\cdot",
+                "==" => "=",
+                "!=" => "\\neq",
+                ">=" => "\\geq",
+                "<=" => "\\leq",
+                "&&" => "\\land",
+                "||" => "\\lor",
+                "!" => "\\lnot",
+                "in" => "\\in",
+                "not in" => "\\notin",
+                s => s,
+            }
+            .to_string(),
+            Format::MathMl => {
+                let symbol = match symbol {
+                    "-" => "−",
+                    "*" => "⋅",
+                    "==" => "=",
+                    "!=" => "≠",
+                    ">=" => "≥",
+                    "<=" => "≤",
+                    "&&" => "∧",
+                    "||" => "∨",
+                    "!" => "¬",
+                    "in" => "∈",
+                    "not in" => "∉",
+                    s => s,
+                };
+                format!("<mo>{}</mo>", xml_escape(symbol))
+            }
+        }
+    }
+
+    // 按顺序排列的几个部分; 二元运算符两边加空格, 单目运算符紧贴操作数
+    fn row(&self, parts: &[String]) -> String {
+        match self.format {
+            Format::Latex if parts.len() == 2 => {
+                let sep = if parts[0].starts_with('\\') { " " } else { "" };
+                format!("{}{sep}{}", parts[0], parts[1])
+            }
+            Format::Latex => parts.join(" "),
+            Format::MathMl => format!("<mrow>{}</mrow>", parts.concat()),
+        }
+    }
+
+    // 括号, 中括号, 绝对值等成对的符号, 里面的多个部分用逗号分开
+    fn bracket(&self, open: &str, items: &[String], close: &str) -> String {
+        match self.format {
+            Format::Latex => {
+                let delimiter = |d: &str| match d {
+                    "|" => "|",
+                    "⌊" => "\\lfloor",
+                    "⌋" => "\\rfloor",
+                    "⌈" => "\\lceil",
+                    "⌉" => "\\rceil",
+                    d => d,
+                }
+                .to_string();
+                let space = |d: &str| if d.starts_with('\\') { " " } else { "" };
+                let (open, close) = (delimiter(open), delimiter(close));
+                format!("\\left{open}{}{}{}\\right{close}", space(&open), items.join(", "), space(&close))
+            }
+            Format::MathMl => {
+                format!(
+                    "<mrow><mo>{}</mo>{}<mo>{}</mo></mrow>",
+                    xml_escape(open),
+                    items.join("<mo>,</mo>"),
+                    xml_escape(close)
+                )
+            }
+        }
+    }
+}
+
+fn latex_escape(s: &str) -> String {
+    let mut out = String::new();
+    for c in s.chars() {
+        match c {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '_' | '{' | '}' | '$' | '&' | '#' | '%' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    fn latex(input: &str) -> String {
+        to_latex(&parse(input))
+    }
+
+    #[test]
+    fn test_latex_minimal_parentheses() {
+        assert_eq!(latex("(a + b) / 2 * sqrt(x) ^ 2"), "\\frac{a + b}{2} \\cdot \\sqrt{x}^{2}");
+        assert_eq!(latex("a - (b - c) + (d + e)"), "a - \\left(b - c\\right) + \\left(d + e\\right)");
+        assert_eq!(latex("(a * b) * c / (d / e)"), "\\frac{a \\cdot b \\cdot c}{\\frac{d}{e}}");
+        assert_eq!(latex("(a + b) ^ 2 ^ n"), "\\left(a + b\\right)^{2^{n}}");
+        assert_eq!(latex("(a ^ b) ^ c"), "\\left(a^{b}\\right)^{c}");
+        assert_eq!(latex("-x ^ 2 + (-x) * y"), "-x^{2} + -x \\cdot y");
+        assert_eq!(latex("-(a + b)"), "-\\left(a + b\\right)");
+        assert_eq!(latex("(a - b) - c"), "a - b - c");
+    }
+
+    #[test]
+    fn test_latex_logic_and_functions() {
+        assert_eq!(latex("x >= 1 && (!(y != 2)) || z"), "x \\geq 1 \\land \\lnot \\left(y \\neq 2\\right) \\lor z");
+        assert_eq!(latex("(a || b) && c"), "\\left(a \\lor b\\right) \\land c");
+        assert_eq!(latex("0 <= x < 10"), "0 \\leq x < 10");
+        assert_eq!(latex("x in 1..5 && y not in [1, 2]"), "x \\in \\left[1, 5\\right) \\land y \\notin \\left[1, 2\\right]");
+        assert_eq!(latex("abs(x) + floor(y)"), "\\left|x\\right| + \\left\\lfloor y \\right\\rfloor");
+        assert_eq!(latex("exp(x) * ln(x) + log10(x)"), "e^{x} \\cdot \\ln\\left(x\\right) + \\log_{10}\\left(x\\right)");
+        assert_eq!(latex("max(a, b) + my_fn(rate)"), "\\max\\left(a, b\\right) + \\operatorname{my\\_fn}\\left(\\mathrm{rate}\\right)");
+        assert_eq!(latex("name == \"x_1\""), "\\mathrm{name} = \\text{\"x\\_1\"}");
+    }
+
+    #[test]
+    fn test_mathml() {
+        assert_eq!(
+            to_mathml(&parse("(a + 1) / b <= x ^ 2")),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow>\
+             <mfrac><mrow><mi>a</mi><mo>+</mo><mn>1</mn></mrow><mi>b</mi></mfrac>\
+             <mo>≤</mo><msup><mi>x</mi><mn>2</mn></msup></mrow></math>"
+        );
+        assert_eq!(
+            to_mathml(&parse("-(a - b) * sqrt(x)")),
+            "<math xmlns=\"http://www.w3.org/1998/Math/MathML\"><mrow>\
+             <mrow><mo>−</mo><mrow><mo>(</mo><mrow><mi>a</mi><mo>−</mo><mi>b</mi></mrow><mo>)</mo></mrow></mrow>\
+             <mo>⋅</mo><msqrt><mi>x</mi></msqrt></mrow></math>"
+        );
+        assert!(to_mathml(&parse("x < 1 && sin(x) > 0")).contains("<mi>sin</mi><mo>&#x2061;</mo>"));
+    }
+
+    // 内部的 let 绑定展开后再渲染
+    #[test]
+    fn test_inline_lets() {
+        let expr = crate::cse::eliminate(&parse("(a + b) * (a + b)"), &Default::default()).expr;
+        assert_eq!(to_latex(&expr), "\\left(a + b\\right) \\cdot \\left(a + b\\right)");
+    }
+}
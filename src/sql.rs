@@ -0,0 +1,398 @@
+// 把过滤表达式翻译成参数化的 SQL WHERE 条件
+//
+// 字面量都变成占位符, 值按顺序放在 params 里; 变量是加了双引号的列名.
+//   x in [1, 2]   =>  "x" IN (?, ?)
+//   x in 1..=5    =>  "x" BETWEEN ? AND ? AND "x" - ? = FLOOR("x" - ?)   (范围里只有整数步长的值)
+//   1 <= x <= 5   =>  "x" BETWEEN ? AND ?
+//   x == null     =>  "x" IS NULL   (null 只在翻译成 SQL 时有意义)
+// 和求值器一致: && 和 || 的操作数不是 bool 时按 "大于 0 为真" 转换, 包括不知道类型的变量;
+// 对数字取反 !n 是 n == 0.
+// in 以外的列表和范围, 自定义函数等在 SQL 里没有对应写法, 会报错.
+use crate::{
+    ast::{BinaryOp, Expr},
+    eval::Value,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Placeholder {
+    // ? (SQLite, MySQL)
+    #[default]
+    Question,
+    // $1, $2, ... (PostgreSQL)
+    Numbered,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sql {
+    pub sql: String,
+    pub params: Vec<Value>,
+}
+
+pub fn to_sql(expr: &Expr) -> anyhow::Result<Sql> {
+    to_sql_with(expr, Placeholder::default())
+}
+
+pub fn to_sql_with(expr: &Expr, placeholder: Placeholder) -> anyhow::Result<Sql> {
+    let mut translator = Translator { placeholder, params: Vec::new(), scope: Vec::new() };
+    let sql = translator.condition(expr)?.0;
+    Ok(Sql { sql, params: translator.params })
+}
+
+// 优先级, 越大结合越紧
+const OR: u8 = 1;
+const AND: u8 = 2;
+const NOT: u8 = 3;
+const COMPARE: u8 = 4;
+const ADD: u8 = 5;
+const MUL: u8 = 6;
+const UNARY: u8 = 7;
+const ATOM: u8 = 8;
+
+struct Translator<'a> {
+    placeholder: Placeholder,
+    params: Vec<Value>,
+    scope: Vec<(&'a str, &'a Expr)>, // let 绑定在用到的地方展开
+}
+
+impl<'a> Translator<'a> {
+    fn param(&mut self, value: Value) -> (String, u8) {
+        self.params.push(value);
+        let sql = match self.placeholder {
+            Placeholder::Question => "?".to_string(),
+            Placeholder::Numbered => format!("${}", self.params.len()),
+        };
+        (sql, ATOM)
+    }
+
+    // 子表达式的优先级不够时加括号
+    fn operand(&mut self, expr: &'a Expr, min: u8) -> anyhow::Result<String> {
+        let (sql, prec) = self.expr(expr)?;
+        Ok(if prec < min { format!("({sql})") } else { sql })
+    }
+
+    // 以 - 开头时加括号: `a - -b` 里的 -- 在 SQL 里是行注释
+    fn right_operand(&mut self, expr: &'a Expr, min: u8) -> anyhow::Result<String> {
+        let sql = self.operand(expr, min)?;
+        Ok(if sql.starts_with('-') { format!("({sql})") } else { sql })
+    }
+
+    fn cond_operand(&mut self, expr: &'a Expr, min: u8) -> anyhow::Result<String> {
+        let (sql, prec) = self.condition(expr)?;
+        Ok(if prec < min { format!("({sql})") } else { sql })
+    }
+
+    // 用在 AND, OR, NOT 和 WHERE 里的条件: 数字表达式按大于 0 为真
+    fn condition(&mut self, expr: &'a Expr) -> anyhow::Result<(String, u8)> {
+        if let Expr::Let { name, value, body } = expr {
+            self.scope.push((name, value));
+            let result = self.condition(body);
+            self.scope.pop();
+            return result;
+        }
+        if self.is_bool(expr) {
+            return self.expr(expr);
+        }
+        Ok((format!("{} > 0", self.operand(expr, ADD)?), COMPARE))
+    }
+
+    // 结果一定是 bool: let 绑定的变量看绑定的值, 其他变量当作数字
+    fn is_bool(&self, expr: &Expr) -> bool {
+        match expr {
+            Expr::Var(name) => self.scope.iter().rev().find(|(n, _)| n == name).is_some_and(|(_, value)| self.is_bool(value)),
+            _ => is_condition(expr),
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Expr) -> anyhow::Result<(String, u8)> {
+        Ok(match expr {
+            Expr::Number(n) => self.param(Value::Number(*n)),
+            Expr::Str(s) => self.param(Value::Str(s.clone())),
+            Expr::Bool(b) => self.param(Value::Bool(*b)),
+            Expr::Var(name) => match self.scope.iter().rev().find(|(n, _)| n == name) {
+                Some((_, value)) => self.expr(value)?,
+                None if name == "null" => ("NULL".to_string(), ATOM),
+                None => (quote(name), ATOM),
+            },
+            Expr::UnaryOp { op: BinaryOp::Add, expr } => self.expr(expr)?,
+            Expr::UnaryOp { op: BinaryOp::Sub, expr: inner } if !self.is_bool(inner) => {
+                (format!("-{}", self.right_operand(inner, UNARY)?), UNARY)
+            }
+            Expr::UnaryOp { op: BinaryOp::Not, expr: inner } if !self.is_bool(inner) => {
+                (format!("{} = 0", self.operand(inner, ADD)?), COMPARE)
+            }
+            Expr::UnaryOp { op: BinaryOp::Sub | BinaryOp::Not, expr } => {
+                (format!("NOT {}", self.cond_operand(expr, NOT)?), NOT)
+            }
+            Expr::BinaryOp { left, op: BinaryOp::And, right } => {
+                (format!("{} AND {}", self.cond_operand(left, AND)?, self.cond_operand(right, AND + 1)?), AND)
+            }
+            Expr::BinaryOp { left, op: BinaryOp::Or, right } => {
+                (format!("{} OR {}", self.cond_operand(left, OR)?, self.cond_operand(right, OR + 1)?), OR)
+            }
+            Expr::BinaryOp { left, op: op @ (BinaryOp::Add | BinaryOp::Sub), right } => {
+                (format!("{} {op} {}", self.operand(left, ADD)?, self.right_operand(right, ADD + 1)?), ADD)
+            }
+            Expr::BinaryOp { left, op: op @ (BinaryOp::Mul | BinaryOp::Div), right } => {
+                (format!("{} {op} {}", self.operand(left, MUL)?, self.right_operand(right, MUL + 1)?), MUL)
+            }
+            Expr::BinaryOp { left, op: BinaryOp::Pow, right } => {
+                (format!("POWER({}, {})", self.expr(left)?.0, self.expr(right)?.0), ATOM)
+            }
+            Expr::BinaryOp { left, op: op @ (BinaryOp::In | BinaryOp::NotIn), right } => {
+                self.membership(left, *op == BinaryOp::NotIn, right)?
+            }
+            Expr::BinaryOp { left, op, right } if is_comparison(*op) => self.comparison(left, *op, right)?,
+            Expr::Compare { first, rest } => self.chain(first, rest)?,
+            Expr::Call { name, args } => {
+                let f = match name.as_str() {
+                    "abs" | "sqrt" | "exp" | "ln" | "log10" | "sin" | "cos" | "tan" | "floor" | "ceil"
+                    | "round" => name.to_uppercase(),
+                    "min" => "LEAST".to_string(),
+                    "max" => "GREATEST".to_string(),
+                    "pow" => "POWER".to_string(),
+                    _ => anyhow::bail!("SQL 中没有对应的函数: {name}"),
+                };
+                let args = args.iter().map(|a| Ok(self.expr(a)?.0)).collect::<anyhow::Result<Vec<_>>>()?;
+                (format!("{f}({})", args.join(", ")), ATOM)
+            }
+            Expr::Let { name, value, body } => {
+                self.scope.push((name, value));
+                let result = self.expr(body);
+                self.scope.pop();
+                result?
+            }
+            _ => anyhow::bail!("SQL 中没有对应的写法: {expr}"),
+        })
+    }
+
+    fn comparison(&mut self, left: &'a Expr, op: BinaryOp, right: &'a Expr) -> anyhow::Result<(String, u8)> {
+        // 和 NULL 比较要用 IS NULL
+        if matches!(op, BinaryOp::Eq | BinaryOp::Neq) {
+            let (value, null) = match (self.is_null(left), self.is_null(right)) {
+                (false, true) => (left, true),
+                (true, false) => (right, true),
+                _ => (left, false),
+            };
+            if null {
+                let not = if op == BinaryOp::Neq { " NOT" } else { "" };
+                return Ok((format!("{} IS{not} NULL", self.operand(value, COMPARE + 1)?), COMPARE));
+            }
+        }
+        let sql_op = match op {
+            BinaryOp::Eq => "=".to_string(),
+            BinaryOp::Neq => "<>".to_string(),
+            _ => op.to_string(),
+        };
+        Ok((format!("{} {sql_op} {}", self.operand(left, COMPARE + 1)?, self.operand(right, COMPARE + 1)?), COMPARE))
+    }
+
+    fn is_null(&self, expr: &Expr) -> bool {
+        matches!(expr, Expr::Var(name) if name == "null" && !self.scope.iter().any(|(n, _)| n == name))
+    }
+
+    // a < b < c => a < b AND b < c; lo <= x <= hi => x BETWEEN lo AND hi
+    fn chain(&mut self, first: &'a Expr, rest: &'a [(BinaryOp, Expr)]) -> anyhow::Result<(String, u8)> {
+        if let [(BinaryOp::Lte, value), (BinaryOp::Lte, hi)] = rest {
+            return self.between(value, first, hi, false);
+        }
+        let mut parts = Vec::new();
+        let mut left = first;
+        for (op, right) in rest {
+            parts.push(self.comparison(left, *op, right)?.0);
+            left = right;
+        }
+        Ok((parts.join(" AND "), AND))
+    }
+
+    fn between(&mut self, value: &'a Expr, lo: &'a Expr, hi: &'a Expr, not: bool) -> anyhow::Result<(String, u8)> {
+        let value = self.operand(value, COMPARE + 1)?;
+        let not = if not { " NOT" } else { "" };
+        let (lo, hi) = (self.operand(lo, COMPARE + 1)?, self.operand(hi, COMPARE + 1)?);
+        Ok((format!("{value}{not} BETWEEN {lo} AND {hi}"), COMPARE))
+    }
+
+    fn membership(&mut self, left: &'a Expr, not: bool, right: &'a Expr) -> anyhow::Result<(String, u8)> {
+        match right {
+            Expr::List(items) if items.is_empty() => {
+                // IN () 不是合法的 SQL
+                Ok((if not { "1 = 1" } else { "1 = 0" }.to_string(), COMPARE))
+            }
+            Expr::List(items) => {
+                let value = self.operand(left, COMPARE + 1)?;
+                let items = items.iter().map(|e| Ok(self.expr(e)?.0)).collect::<anyhow::Result<Vec<_>>>()?;
+                let not = if not { " NOT" } else { "" };
+                Ok((format!("{value}{not} IN ({})", items.join(", ")), COMPARE))
+            }
+            Expr::BinaryOp { left: lo, op: op @ (BinaryOp::Range | BinaryOp::RangeInclusive), right: hi } => {
+                let bounds = if *op == BinaryOp::RangeInclusive {
+                    self.between(left, lo, hi, false)?.0
+                } else {
+                    // 左闭右开: lo <= x AND x < hi
+                    let value = self.operand(left, COMPARE + 1)?;
+                    let lo = self.operand(lo, COMPARE + 1)?;
+                    let value_again = self.operand(left, COMPARE + 1)?;
+                    format!("{value} >= {lo} AND {value_again} < {}", self.operand(hi, COMPARE + 1)?)
+                };
+                // 和求值器一致, 范围里只有 lo, lo + 1, ...: x - lo 必须是整数
+                let offset = format!("{} - {}", self.operand(left, ADD)?, self.right_operand(lo, ADD + 1)?);
+                let offset_again = format!("{} - {}", self.operand(left, ADD)?, self.right_operand(lo, ADD + 1)?);
+                let sql = format!("{bounds} AND {offset} = FLOOR({offset_again})");
+                Ok(if not { (format!("NOT ({sql})"), NOT) } else { (sql, AND) })
+            }
+            _ => anyhow::bail!("SQL 中 IN 的右边必须是列表或范围: {right}"),
+        }
+    }
+}
+
+fn is_comparison(op: BinaryOp) -> bool {
+    matches!(op, BinaryOp::Eq | BinaryOp::Neq | BinaryOp::Gt | BinaryOp::Gte | BinaryOp::Lt | BinaryOp::Lte)
+}
+
+// 结果一定是 bool 的表达式
+fn is_condition(expr: &Expr) -> bool {
+    match expr {
+        Expr::Bool(_) | Expr::Compare { .. } => true,
+        Expr::UnaryOp { op: BinaryOp::Not, .. } => true,
+        Expr::UnaryOp { op: BinaryOp::Sub | BinaryOp::Add, expr } => is_condition(expr),
+        Expr::BinaryOp { op, .. } => {
+            is_comparison(*op) || matches!(op, BinaryOp::And | BinaryOp::Or | BinaryOp::In | BinaryOp::NotIn)
+        }
+        _ => false,
+    }
+}
+
+// 标识符加双引号, 里面的双引号写两次
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn sql(input: &str) -> Sql {
+        let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        to_sql(&expr).unwrap()
+    }
+
+    fn sql_err(input: &str) -> bool {
+        let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        to_sql(&expr).is_err()
+    }
+
+    #[test]
+    fn test_basic() {
+        let result = sql("age >= 18 && country == \"CN\"");
+        assert_eq!(result.sql, "\"age\" >= ? AND \"country\" = ?");
+        assert_eq!(result.params, [Value::Number(18.0), Value::Str("CN".to_string())]);
+
+        assert_eq!(sql("(a || b) && !c").sql, "(\"a\" > 0 OR \"b\" > 0) AND \"c\" = 0");
+        assert_eq!(sql("a || b && c").sql, "\"a\" > 0 OR \"b\" > 0 AND \"c\" > 0");
+        assert_eq!(sql("!(a > 1)").sql, "NOT \"a\" > ?");
+        assert_eq!(sql("!!b").sql, "NOT \"b\" = 0");
+        assert_eq!(sql("price * (1 - discount) < 100").sql, "\"price\" * (? - \"discount\") < ?");
+        assert_eq!(sql("a - (b - c) != 2 ^ x").sql, "\"a\" - (\"b\" - \"c\") <> POWER(?, \"x\")");
+        assert_eq!(sql("max(a, b) > abs(c)").sql, "GREATEST(\"a\", \"b\") > ABS(\"c\")");
+    }
+
+    #[test]
+    fn test_in_between_null() {
+        let result = sql("status in [\"new\", \"open\"]");
+        assert_eq!(result.sql, "\"status\" IN (?, ?)");
+        assert_eq!(result.params.len(), 2);
+        assert_eq!(sql("x not in [1]").sql, "\"x\" NOT IN (?)");
+        assert_eq!(sql("x in []").sql, "1 = 0");
+        assert_eq!(sql("x in 1..=5").sql, "\"x\" BETWEEN ? AND ? AND \"x\" - ? = FLOOR(\"x\" - ?)");
+        assert_eq!(sql("x not in 1..=5").sql, "NOT (\"x\" BETWEEN ? AND ? AND \"x\" - ? = FLOOR(\"x\" - ?))");
+        assert_eq!(sql("x in 1..5").sql, "\"x\" >= ? AND \"x\" < ? AND \"x\" - ? = FLOOR(\"x\" - ?)");
+        assert_eq!(sql("x in 1..5").params.len(), 4);
+        assert_eq!(sql("0 <= x <= 10").sql, "\"x\" BETWEEN ? AND ?");
+        assert_eq!(sql("0 < x < y").sql, "? < \"x\" AND \"x\" < \"y\"");
+        assert_eq!(sql("deleted_at == null").sql, "\"deleted_at\" IS NULL");
+        assert_eq!(sql("null != name").sql, "\"name\" IS NOT NULL");
+    }
+
+    #[test]
+    fn test_placeholders_and_quoting() {
+        let expr = Parser::new(tokenize("a > 1 && b < 2").unwrap()).parse_program().unwrap();
+        assert_eq!(to_sql_with(&expr, Placeholder::Numbered).unwrap().sql, "\"a\" > $1 AND \"b\" < $2");
+        assert_eq!(quote("we\"ird"), "\"we\"\"ird\"");
+
+        // 数字和不知道类型的变量按大于 0 为真, 取反是等于 0
+        assert_eq!(sql("count && flag").sql, "\"count\" > 0 AND \"flag\" > 0");
+        assert_eq!(sql("count - 1 && x > 1").sql, "\"count\" - ? > 0 AND \"x\" > ?");
+        assert_eq!(sql("!(count - 1)").sql, "\"count\" - ? = 0");
+        assert_eq!(sql("!count").sql, "\"count\" = 0");
+    }
+
+    #[test]
+    fn test_nested_negation() {
+        // 不能生成 SQL 的行注释 --
+        let result = sql("-(-x) > 0 && secret == 1");
+        assert_eq!(result.sql, "-(-\"x\") > ? AND \"secret\" = ?");
+        assert_eq!(sql("-(-x * 2) < 1").sql, "-(-\"x\" * ?) < ?");
+        assert_eq!(sql("x - -y").sql, "\"x\" - (-\"y\") > 0");
+        assert_eq!(sql("x - -1").sql, "\"x\" - (-?) > 0");
+        assert_eq!(sql("x * -y / -2 > 0").sql, "\"x\" * (-\"y\") / (-?) > ?");
+        assert_eq!(sql("x in -2..3").sql, "\"x\" >= -? AND \"x\" < ? AND \"x\" - (-?) = FLOOR(\"x\" - (-?))");
+    }
+
+    // 把生成的 SQL 按字面改写回表达式: 参数代入, AND/OR/NOT/= 换成对应的运算, BETWEEN 拆成两个比较
+    fn sql_to_expr(sql: &Sql) -> String {
+        let mut params = sql.params.iter();
+        let text: String = sql.sql.chars().map(|c| if c == '?' { params.next().unwrap().to_string() } else { c.to_string() }).collect();
+        let mut words: Vec<String> = Vec::new();
+        let mut input = text.replace('"', "").replace("FLOOR", "floor");
+        input = input.replace("(", "( ").replace(")", " )");
+        let mut iter = input.split_whitespace();
+        while let Some(word) = iter.next() {
+            let word = match word {
+                "BETWEEN" => {
+                    let value = words.pop().unwrap();
+                    let lo = iter.next().unwrap();
+                    assert_eq!(iter.next(), Some("AND"));
+                    let hi = iter.next().unwrap();
+                    format!("({lo} <= {value} && {value} <= {hi})")
+                }
+                "AND" => "&&".to_string(),
+                "OR" => "||".to_string(),
+                "NOT" => "!".to_string(),
+                "=" => "==".to_string(),
+                "<>" => "!=".to_string(),
+                word => word.to_string(),
+            };
+            words.push(word);
+        }
+        words.join(" ")
+    }
+
+    // SQL 的范围判断和求值器的 `in` 结果一致, 包括不在整数步长上的值
+    #[test]
+    fn test_range_matches_eval() {
+        use crate::eval::{eval_with, Context, EvalPolicy};
+        let parse = |input: &str| Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+        let policy = EvalPolicy::default();
+        for range in ["1..10", "1..=10", "0.5..3", "-2..=2"] {
+            for op in ["in", "not in"] {
+                let input = format!("x {op} {range}");
+                let rewritten = parse(&sql_to_expr(&to_sql(&parse(&input)).unwrap()));
+                for x in [-3.0, -2.0, -1.5, 0.0, 0.5, 1.0, 2.5, 3.0, 9.0, 9.5, 10.0, 11.0] {
+                    let mut ctx = Context::new();
+                    ctx.set("x", x);
+                    let expected = eval_with(&parse(&input), &ctx, &policy).unwrap();
+                    assert_eq!(eval_with(&rewritten, &ctx, &policy).unwrap(), expected, "{input}, x = {x}: {rewritten}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        assert!(sql_err("[1, 2] == x"));
+        assert!(sql_err("x in y"));
+        assert!(sql_err("1..2"));
+        assert!(sql_err("custom(x) > 1"));
+    }
+}
@@ -0,0 +1,60 @@
+// 测试用的工具: 随机生成语法树, 用于各种求值方式之间的差分测试
+use crate::ast::{BinaryOp, Expr};
+
+// 简单的 xorshift 随机数, 固定种子保证测试可重复
+pub struct Rng(pub u64);
+
+impl Rng {
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+pub fn random_expr(rng: &mut Rng, depth: u32) -> Expr {
+    const BINARY: [BinaryOp; 17] = [
+        BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul, BinaryOp::Div, BinaryOp::Pow,
+        BinaryOp::Eq, BinaryOp::Neq, BinaryOp::Gt, BinaryOp::Gte, BinaryOp::Lt, BinaryOp::Lte,
+        BinaryOp::And, BinaryOp::Or, BinaryOp::Range, BinaryOp::RangeInclusive,
+        BinaryOp::In, BinaryOp::NotIn,
+    ];
+    const COMPARE: [BinaryOp; 6] = [
+        BinaryOp::Eq, BinaryOp::Neq, BinaryOp::Gt, BinaryOp::Gte, BinaryOp::Lt, BinaryOp::Lte,
+    ];
+    if depth == 0 {
+        return match rng.below(9) {
+            0 => Expr::Str(["a", "b"][rng.below(2) as usize].to_string()),
+            1 => Expr::Var(["x", "s", "undefined"][rng.below(3) as usize].to_string()),
+            2 => Expr::Bool(rng.below(2) == 0),
+            _ => Expr::Number(rng.below(5) as f64 - 1.0),
+        };
+    }
+    match rng.below(6) {
+        0 => Expr::UnaryOp {
+            op: [BinaryOp::Add, BinaryOp::Sub, BinaryOp::Not][rng.below(3) as usize],
+            expr: Box::new(random_expr(rng, depth - 1)),
+        },
+        1 => Expr::List((0..rng.below(3)).map(|_| random_expr(rng, depth - 1)).collect()),
+        3 => {
+            let (name, argc) = [("abs", 1), ("max", 2)][rng.below(2) as usize];
+            Expr::Call { name: name.to_string(), args: (0..argc).map(|_| random_expr(rng, depth - 1)).collect() }
+        }
+        2 => Expr::Compare {
+            first: Box::new(random_expr(rng, depth - 1)),
+            rest: (0..2 + rng.below(2))
+                .map(|_| (COMPARE[rng.below(6) as usize], random_expr(rng, depth - 1)))
+                .collect(),
+        },
+        _ => Expr::BinaryOp {
+            left: Box::new(random_expr(rng, depth - 1)),
+            op: BINARY[rng.below(BINARY.len() as u64) as usize],
+            right: Box::new(random_expr(rng, depth - 1)),
+        },
+    }
+}
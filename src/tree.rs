@@ -0,0 +1,133 @@
+// 把语法树画出来, 用来观察优先级和结合性: Graphviz DOT 和终端里的字符树
+//
+//   1 + 2 * 3   =>   +
+//                    ├── 1
+//                    └── *
+//                        ├── 2
+//                        └── 3
+use std::fmt::Write;
+
+use crate::ast::{walk_expr, Expr, Visitor};
+
+// 收集直接子节点: visit_expr 不再往下走
+struct Children<'a>(Vec<&'a Expr>);
+
+impl<'a> Visitor<'a> for Children<'a> {
+    fn visit_expr(&mut self, expr: &'a Expr) {
+        self.0.push(expr);
+    }
+}
+
+// 节点显示的文字和子节点
+fn node(expr: &Expr) -> (String, Vec<&Expr>) {
+    let label = match expr {
+        Expr::Number(n) => n.to_string(),
+        Expr::Str(s) => format!("{s:?}"),
+        Expr::Var(name) => name.clone(),
+        Expr::Bool(b) => b.to_string(),
+        Expr::UnaryOp { op, .. } | Expr::BinaryOp { op, .. } => op.to_string(),
+        Expr::List(_) => "[]".to_string(),
+        Expr::Compare { rest, .. } => {
+            let ops: Vec<String> = rest.iter().map(|(op, _)| op.to_string()).collect();
+            format!("compare {}", ops.join(" "))
+        }
+        Expr::Call { name, .. } => format!("{name}()"),
+        Expr::Let { name, .. } => format!("let {name}"),
+        Expr::Error => "<错误>".to_string(),
+    };
+    let mut children = Children(Vec::new());
+    walk_expr(&mut children, expr);
+    (label, children.0)
+}
+
+pub fn to_tree(expr: &Expr) -> String {
+    let mut out = String::new();
+    let (label, children) = node(expr);
+    writeln!(out, "{label}").unwrap();
+    write_children(&mut out, &children, "");
+    out
+}
+
+fn write_children(out: &mut String, children: &[&Expr], prefix: &str) {
+    for (i, child) in children.iter().enumerate() {
+        let last = i == children.len() - 1;
+        let (label, grandchildren) = node(child);
+        writeln!(out, "{prefix}{}{label}", if last { "└── " } else { "├── " }).unwrap();
+        write_children(out, &grandchildren, &format!("{prefix}{}", if last { "    " } else { "│   " }));
+    }
+}
+
+pub fn to_dot(expr: &Expr) -> String {
+    let mut out = String::from("digraph expr {\n    node [shape=box, fontname=\"monospace\"];\n");
+    let mut next = 0;
+    write_dot(&mut out, expr, &mut next);
+    out.push_str("}\n");
+    out
+}
+
+// 返回这个节点的编号, 节点按先序编号
+fn write_dot(out: &mut String, expr: &Expr, next: &mut usize) -> usize {
+    let id = *next;
+    *next += 1;
+    let (label, children) = node(expr);
+    writeln!(out, "    n{id} [label=\"{}\"];", dot_escape(&label)).unwrap();
+    for child in children {
+        let child_id = write_dot(out, child, next);
+        writeln!(out, "    n{id} -> n{child_id};").unwrap();
+    }
+    id
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lexer::tokenize, parser::Parser};
+
+    fn parse(input: &str) -> Expr {
+        Parser::new(tokenize(input).unwrap()).parse_program().unwrap()
+    }
+
+    #[test]
+    fn test_tree() {
+        assert_eq!(to_tree(&parse("1 + 2 * 3")), "+\n├── 1\n└── *\n    ├── 2\n    └── 3\n");
+        assert_eq!(
+            to_tree(&parse("max(-x, [\"a\"]) < 0 <= y")),
+            concat!(
+                "compare < <=\n",
+                "├── max()\n",
+                "│   ├── -\n",
+                "│   │   └── x\n",
+                "│   └── []\n",
+                "│       └── \"a\"\n",
+                "├── 0\n",
+                "└── y\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_dot() {
+        assert_eq!(
+            to_dot(&parse("(1 - 2) - \"q\\\"\"")),
+            concat!(
+                "digraph expr {\n",
+                "    node [shape=box, fontname=\"monospace\"];\n",
+                "    n0 [label=\"-\"];\n",
+                "    n1 [label=\"-\"];\n",
+                "    n2 [label=\"1\"];\n",
+                "    n1 -> n2;\n",
+                "    n3 [label=\"2\"];\n",
+                "    n1 -> n3;\n",
+                "    n0 -> n1;\n",
+                "    n4 [label=\"\\\"q\\\\\\\"\\\"\"];\n",
+                "    n0 -> n4;\n",
+                "}\n",
+            )
+        );
+    }
+}
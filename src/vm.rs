@@ -0,0 +1,206 @@
+// 基于栈的字节码虚拟机, 结果和 eval 的树遍历求值一致
+use std::cell::RefCell;
+
+use crate::{compiler::{Chunk, Op}, eval::{self, Context, EvalPolicy, Value}};
+
+#[derive(Default)]
+struct Frame {
+    stack: Vec<Value>,
+    locals: Vec<Value>, // 内部绑定 (let) 的槽位
+}
+
+// 变量不超过这么多个时, 按槽位查好的引用放在栈上的数组里
+const INLINE_VARS: usize = 16;
+
+thread_local! {
+    // 每个线程复用同一个栈, 热路径上不用每次分配
+    static FRAME: RefCell<Frame> = RefCell::new(Frame::default());
+}
+
+pub fn run(chunk: &Chunk, ctx: &Context, policy: &EvalPolicy) -> anyhow::Result<Value> {
+    // 先把栈取出来, 这样宿主函数在求值过程中再次调用 run 也不会冲突
+    let mut frame = FRAME.with(|f| std::mem::take(&mut *f.borrow_mut()));
+    frame.locals.resize(chunk.locals, Value::Bool(false));
+    // 每次求值只按名字查一次变量, 之后 Load 直接按槽位取; 只存引用, 没用到的变量不复制
+    let mut inline = [None; INLINE_VARS];
+    let mut spilled = Vec::new();
+    let vars = if chunk.names.len() <= INLINE_VARS {
+        &mut inline[..chunk.names.len()]
+    } else {
+        spilled.resize(chunk.names.len(), None);
+        &mut spilled[..]
+    };
+    for (var, name) in vars.iter_mut().zip(&chunk.names) {
+        *var = ctx.get(name);
+    }
+    let result = execute(chunk, ctx, policy, &mut frame.stack, &mut frame.locals, vars);
+    frame.stack.clear();
+    frame.locals.clear();
+    FRAME.with(|f| *f.borrow_mut() = frame);
+    result
+}
+
+fn execute(
+    chunk: &Chunk,
+    ctx: &Context,
+    policy: &EvalPolicy,
+    stack: &mut Vec<Value>,
+    locals: &mut [Value],
+    vars: &[Option<&Value>],
+) -> anyhow::Result<Value> {
+    let mut ip = 0;
+
+    while let Some(op) = chunk.code.get(ip) {
+        ip += 1;
+        match *op {
+            Op::Const(c) => stack.push(chunk.constants[c as usize].clone()),
+            Op::Load(slot) => match vars[slot as usize] {
+                Some(value) => stack.push(value.clone()),
+                // 没有定义的变量在真正用到时才报错, 被短路跳过时不报
+                None => return Err(ctx.lookup(&chunk.names[slot as usize]).expect_err("变量不在 ctx 里")),
+            },
+            Op::LoadLocal(slot) => stack.push(locals[slot as usize].clone()),
+            Op::StoreLocal(slot) => locals[slot as usize] = pop(stack)?,
+            Op::Call(func, argc) => {
+                let start = stack.len().checked_sub(argc as usize).ok_or_else(|| anyhow::anyhow!("栈下溢"))?;
+                let result = eval::call(&chunk.functions[func as usize].1, &stack[start..], policy)?;
+                stack.truncate(start);
+                stack.push(result);
+            }
+            Op::Unary(op) => {
+                let v = pop(stack)?;
+                stack.push(eval::unary(&op, v)?);
+            }
+            Op::Binary(op) => {
+                let r = pop(stack)?;
+                let l = pop(stack)?;
+                stack.push(eval::binary(&op, l, r, policy)?);
+            }
+            Op::MakeList(n) => {
+                let items = stack.split_off(stack.len().checked_sub(n as usize).ok_or_else(|| anyhow::anyhow!("栈下溢"))?);
+                stack.push(Value::List(items));
+            }
+            Op::Truthy => {
+                let v = pop(stack)?;
+                stack.push(Value::Bool(v.truthy()?));
+            }
+            Op::CompareKeep(op) => {
+                let r = pop(stack)?;
+                let l = pop(stack)?;
+                let result = eval::compare(&op, &l, &r, policy)?;
+                stack.push(r);
+                stack.push(Value::Bool(result));
+            }
+            Op::Jump(target) => ip = target as usize,
+            Op::JumpIfFalse(target) => {
+                if pop(stack)? == Value::Bool(false) {
+                    ip = target as usize;
+                }
+            }
+            Op::JumpIfFalseOrPop(target) => {
+                if stack.last() == Some(&Value::Bool(false)) {
+                    ip = target as usize;
+                } else {
+                    pop(stack)?;
+                }
+            }
+            Op::JumpIfTrueOrPop(target) => {
+                if stack.last() == Some(&Value::Bool(true)) {
+                    ip = target as usize;
+                } else {
+                    pop(stack)?;
+                }
+            }
+            Op::Pop => {
+                pop(stack)?;
+            }
+        }
+    }
+
+    match (stack.pop(), stack.is_empty()) {
+        (Some(v), true) => Ok(v),
+        _ => anyhow::bail!("字节码执行结束后栈不平衡"),
+    }
+}
+
+fn pop(stack: &mut Vec<Value>) -> anyhow::Result<Value> {
+    stack.pop().ok_or_else(|| anyhow::anyhow!("栈下溢"))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ast::Expr,
+        compiler::compile,
+        eval::{eval_with, DivByZero, FloatEq, NonFinite},
+        lexer::tokenize,
+        parser::Parser,
+        test_util::{random_expr, Rng},
+    };
+
+    // 树遍历和虚拟机的结果必须完全一致 (包括是否报错)
+    fn assert_same(expr: &Expr, policy: &EvalPolicy) {
+        let mut ctx = Context::new();
+        ctx.set("x", 2.0).set("s", "a");
+        let tree = eval_with(expr, &ctx, policy);
+        let vm = run(&compile(expr, ctx.functions()).unwrap(), &ctx, policy);
+        match (&tree, &vm) {
+            (Ok(a), Ok(b)) => assert_eq!(format!("{a:?}"), format!("{b:?}"), "{expr}"),
+            (Err(_), Err(_)) => {}
+            _ => panic!("结果不一致: {expr}\n  eval: {tree:?}\n  vm:   {vm:?}"),
+        }
+    }
+
+    #[test]
+    fn test_differential_samples() {
+        let inputs = [
+            "1 + 2 * 3",
+            "-1 - -2",
+            "!0",
+            "1 / 0",
+            "0 && 1 / 0",
+            "1 || 1 / 0",
+            "-1 && 1",
+            "1 < 2 <= 2 == 2",
+            "3 < 2 < 1 in 2",
+            "1 < 2 < 1 in 2",
+            "2 in 1..10",
+            "10 not in 1..=10",
+            "[1, 2 + 1, [3]]",
+            "3 in [1, 2 + 1]",
+            r#""a" < "b" && "b" != "c""#,
+            r#""a" < 1"#,
+            "[1] + 1",
+            "x * x + 1",
+            "s in [x, \"a\"]",
+            "y + 1",
+        ];
+        for input in inputs {
+            let expr = Parser::new(tokenize(input).unwrap()).parse_program().unwrap();
+            assert_same(&expr, &EvalPolicy::default());
+        }
+    }
+
+    #[test]
+    fn test_differential_random() {
+        let policies = [
+            EvalPolicy::default(),
+            EvalPolicy {
+                float_eq: FloatEq::Abs(0.5),
+                non_finite: NonFinite::Error,
+                div_by_zero: DivByZero::Infinity,
+                ..Default::default()
+            },
+        ];
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..5000 {
+            let depth = 1 + rng.below(4) as u32;
+            let expr = random_expr(&mut rng, depth);
+            for policy in &policies {
+                assert_same(&expr, policy);
+            }
+        }
+    }
+}
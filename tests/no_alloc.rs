@@ -0,0 +1,44 @@
+// 热路径不分配内存. 全局分配器会替换整个测试程序的分配器, 所以单独放在一个集成测试里
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    cell::Cell,
+};
+
+use expr_interpreter::{engine::Engine, eval::{Context, Value}};
+
+// 统计当前线程的分配次数
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|n| n.set(n.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn test_eval_does_not_allocate() {
+    // name 被短路跳过, 不会被复制
+    let compiled = Engine::new().compile("x * 2 + 2 - y / 4 >= 3 && x < y < 100 || name == \"a\"").unwrap();
+    let mut ctx = Context::new();
+    ctx.set("x", 3.0).set("y", 8.0).set("name", "一个不会被读到的字符串");
+    // 第一次执行时会分配线程复用的栈
+    assert_eq!(compiled.eval(&ctx).unwrap(), Value::Bool(true));
+
+    let before = ALLOCATIONS.with(Cell::get);
+    for _ in 0..100 {
+        compiled.eval(&ctx).unwrap();
+    }
+    assert_eq!(ALLOCATIONS.with(Cell::get), before);
+}